@@ -0,0 +1,31 @@
+#![feature(test)]
+
+extern crate luax;
+extern crate test;
+
+use luax::codegen::{FunctionBuilder, ModuleBuilder};
+use luax::parser;
+use luax::runtime;
+use luax::vm::executor::ExecutorImpl;
+use test::Bencher;
+
+const ARITHMETIC_PROGRAM: &str = "
+local sum = 0
+local x = 0.5
+for i = 1, 100000 do
+    sum = sum + i * 3 - 1
+    x = x * 1.5 - x
+end
+assert(sum == 15000050000)
+";
+
+#[bench]
+fn lua_arithmetic(b: &mut Bencher) {
+    let ast = parser::parse(ARITHMETIC_PROGRAM).unwrap();
+    b.iter(|| {
+        let module = ModuleBuilder::new();
+        let fn_id = FunctionBuilder::new(&module).build(&ast, Vec::new()).unwrap();
+        let mut executor = ExecutorImpl::new();
+        runtime::invoke(&mut executor, module, fn_id);
+    });
+}
@@ -0,0 +1,93 @@
+#![feature(test)]
+
+extern crate luax;
+extern crate test;
+
+use luax::codegen::{FunctionBuilder, ModuleBuilder};
+use luax::lua_types::Table;
+use luax::parser;
+use luax::runtime;
+use luax::vm::executor::ExecutorImpl;
+use luax::vm::value::Value;
+use test::Bencher;
+
+const N: usize = 10000;
+
+#[bench]
+fn table_sequential_set_get(b: &mut Bencher) {
+    let executor = ExecutorImpl::new();
+    b.iter(|| {
+        let t = Table::new();
+        for i in 1..N + 1 {
+            t.set(&executor, Value::Float(i as f64), Value::Float(i as f64));
+        }
+        let mut sum = 0.0;
+        for i in 1..N + 1 {
+            if let Value::Float(v) = t.get(&executor, Value::Float(i as f64)) {
+                sum += v;
+            }
+        }
+        sum
+    });
+}
+
+#[bench]
+fn table_reverse_set_get(b: &mut Bencher) {
+    let executor = ExecutorImpl::new();
+    b.iter(|| {
+        let t = Table::new();
+        for i in (1..N + 1).rev() {
+            t.set(&executor, Value::Float(i as f64), Value::Float(i as f64));
+        }
+        let mut sum = 0.0;
+        for i in 1..N + 1 {
+            if let Value::Float(v) = t.get(&executor, Value::Float(i as f64)) {
+                sum += v;
+            }
+        }
+        sum
+    });
+}
+
+#[bench]
+fn table_sparse_set_get(b: &mut Bencher) {
+    let executor = ExecutorImpl::new();
+    b.iter(|| {
+        let t = Table::new();
+        for i in 1..N + 1 {
+            t.set(&executor, Value::Float((i * 7) as f64), Value::Float(i as f64));
+        }
+        let mut sum = 0.0;
+        for i in 1..N + 1 {
+            if let Value::Float(v) = t.get(&executor, Value::Float((i * 7) as f64)) {
+                sum += v;
+            }
+        }
+        sum
+    });
+}
+
+const ARRAY_PROGRAM: &str = "
+local t = {}
+for i = 1, 10000 do
+    t[i] = i
+end
+local sum = 0
+for j = 1, 10 do
+    for i = 1, #t do
+        sum = sum + t[i]
+    end
+end
+assert(sum == 500050000)
+";
+
+#[bench]
+fn lua_array_sum(b: &mut Bencher) {
+    let ast = parser::parse(ARRAY_PROGRAM).unwrap();
+    b.iter(|| {
+        let module = ModuleBuilder::new();
+        let fn_id = FunctionBuilder::new(&module).build(&ast, Vec::new()).unwrap();
+        let mut executor = ExecutorImpl::new();
+        runtime::invoke(&mut executor, module, fn_id);
+    });
+}
@@ -0,0 +1,182 @@
+-- Values are passed both ways through resume and yield.
+local co = coroutine.create(function(a, b)
+    local c = coroutine.yield(a + b)
+    local d, e = coroutine.yield(c * 2)
+    return d + e, "done"
+end)
+assert(coroutine.status(co) == "suspended")
+
+local ok, v = coroutine.resume(co, 1, 2)
+assert(ok and v == 3)
+ok, v = coroutine.resume(co, 10)
+assert(ok and v == 20)
+local ok2, sum, msg = coroutine.resume(co, 4, 5)
+assert(ok2 and sum == 9 and msg == "done")
+assert(coroutine.status(co) == "dead")
+
+ok, v = coroutine.resume(co)
+assert(not ok and v == "cannot resume dead coroutine")
+
+-- Yields from nested calls.
+local function walk(node)
+    if node.leaf then
+        coroutine.yield(node.leaf)
+    end
+    for i = 1, #node do
+        walk(node[i])
+    end
+end
+
+local tree = {{leaf = 1}, {{leaf = 2}, {{leaf = 3}, {leaf = 4}}}, {leaf = 5}}
+local leaves = {}
+for leaf in coroutine.wrap(function() walk(tree) end) do
+    leaves[#leaves + 1] = leaf
+end
+assert(#leaves == 5)
+for i = 1, 5 do
+    assert(leaves[i] == i)
+end
+
+-- Statuses seen from inside.
+local outer
+local inner = coroutine.create(function()
+    assert(coroutine.status(outer) == "normal")
+    coroutine.yield()
+end)
+outer = coroutine.create(function()
+    assert(coroutine.status(outer) == "running")
+    assert(coroutine.isyieldable())
+    assert(coroutine.resume(inner))
+    assert(coroutine.status(inner) == "suspended")
+    ok, v = coroutine.resume(outer)
+    assert(not ok and v == "cannot resume non-suspended coroutine")
+end)
+assert(not coroutine.isyieldable())
+assert(coroutine.resume(outer))
+assert(coroutine.status(outer) == "dead")
+assert(coroutine.status(inner) == "suspended")
+assert(coroutine.resume(inner))
+assert(coroutine.status(inner) == "dead")
+
+-- Interleaved generators.
+local function counter(from)
+    return coroutine.wrap(function()
+        local i = from
+        while true do
+            coroutine.yield(i)
+            i = i + 1
+        end
+    end)
+end
+local a, b = counter(1), counter(100)
+assert(a() == 1 and b() == 100 and a() == 2 and a() == 3 and b() == 101)
+
+-- Errors end the coroutine.
+co = coroutine.create(function()
+    coroutine.yield()
+    error("failed")
+end)
+assert(coroutine.resume(co))
+ok, v = coroutine.resume(co)
+assert(not ok and v == "failed" and coroutine.status(co) == "dead")
+
+ok, v = pcall(coroutine.wrap(function()
+    error({code = 1})
+end))
+assert(not ok and v.code == 1)
+
+ok, v = pcall(coroutine.yield)
+assert(not ok and v == "attempt to yield from outside a coroutine")
+
+-- Closing runs pending to-be-closed variables.
+local closed = false
+co = coroutine.create(function()
+    local x <close> = setmetatable({}, {
+        __close = function()
+            closed = true
+        end
+    })
+    coroutine.yield()
+end)
+assert(coroutine.resume(co))
+assert(not closed)
+assert(coroutine.close(co))
+assert(closed and coroutine.status(co) == "dead")
+
+-- Suspended coroutines survive collections.
+co = coroutine.wrap(function()
+    local t = {value = "kept"}
+    coroutine.yield()
+    return t.value
+end)
+co()
+collectgarbage()
+assert(co() == "kept")
+
+-- Unreachable suspended coroutines are collected, without closing their
+-- variables.
+local closed = 0
+local weak = setmetatable({}, {__mode = "k"})
+for i = 1, 5000 do
+    local co = coroutine.create(function(a)
+        local x <close> = setmetatable({}, {
+            __close = function()
+                closed = closed + 1
+            end
+        })
+        coroutine.yield(a)
+    end)
+    assert(select(2, coroutine.resume(co, i)) == i)
+    if i % 1000 == 0 and i < 5000 then
+        weak[co] = true
+    end
+end
+collectgarbage()
+assert(next(weak) == nil)
+assert(closed == 0)
+
+-- Many coroutines can be alive at once, and collections happen while
+-- some of them are running and others are suspended.
+local tasks = {}
+for i = 1, 1000 do
+    tasks[i] = coroutine.create(function(n)
+        local acc = {}
+        for step = 1, 10 do
+            acc[step] = n * step
+            if n % 100 == 0 and step % 3 == 0 then
+                collectgarbage()
+            end
+            n = coroutine.yield(#acc)
+        end
+        local sum = 0
+        for _, v in ipairs(acc) do
+            sum = sum + v
+        end
+        return sum
+    end)
+end
+local total = 0
+for round = 1, 11 do
+    for i, co in ipairs(tasks) do
+        local ok, v = coroutine.resume(co, i)
+        assert(ok)
+        if round <= 10 then
+            assert(v == round and coroutine.status(co) == "suspended")
+        else
+            assert(coroutine.status(co) == "dead")
+            total = total + v
+        end
+    end
+end
+assert(total == 55 * 500500)
+
+-- Coroutines left suspended at exit are not closed.
+co = coroutine.wrap(function()
+    local x <close> = setmetatable({}, {
+        __close = function()
+            error("closed at exit")
+        end
+    })
+    coroutine.yield()
+end)
+co()
@@ -0,0 +1,73 @@
+-- Error values of any type are caught by pcall.
+local ok, err = pcall(error, "message")
+assert(not ok and err == "message")
+
+local obj = {}
+ok, err = pcall(function()
+    error(obj)
+end)
+assert(not ok and rawequal(err, obj))
+
+ok, err = pcall(error)
+assert(not ok and err == nil)
+
+-- Results of a successful call follow the status.
+local a, b, c = pcall(function(x, y)
+    return x + y, x * y
+end, 3, 4)
+assert(a == true and b == 7 and c == 12)
+
+-- Errors raised by the VM become their messages.
+ok, err = pcall(function()
+    return 1 + nil
+end)
+assert(not ok and err == "attempt to perform arithmetic on a nil value")
+
+ok, err = pcall(function()
+    local t = nil
+    return t.x
+end)
+assert(not ok and err ~= nil)
+
+ok, err = pcall(assert, false, "failed")
+assert(not ok and err == "failed")
+
+-- A successful assert returns all of its arguments.
+local x, y = assert(1, 2)
+assert(x == 1 and y == 2)
+assert(select("#", assert(true, nil, 3)) == 3)
+
+-- Nested calls only catch their own errors.
+ok, err = pcall(function()
+    local inner_ok, inner_err = pcall(error, "inner")
+    assert(not inner_ok and inner_err == "inner")
+    error("outer")
+end)
+assert(not ok and err == "outer")
+
+-- Message handlers receive the original error object.
+local received
+ok, err = xpcall(function()
+    error(obj)
+end, function(e)
+    received = e
+    return "handled"
+end)
+assert(not ok and err == "handled" and rawequal(received, obj))
+
+ok, err = xpcall(function(x)
+    return x
+end, print, 5)
+assert(ok and err == 5)
+
+-- To-be-closed variables are closed with the error.
+local closed_with
+ok, err = pcall(function()
+    local x <close> = setmetatable({}, {
+        __close = function(self, e)
+            closed_with = e
+        end
+    })
+    error("closing")
+end)
+assert(not ok and err == "closing" and closed_with == "closing")
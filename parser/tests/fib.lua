@@ -1,4 +1,4 @@
-function fib(n)
+local function fib(n)
     if n == 0 then
         return 0
     elseif n == 1 then
@@ -7,4 +7,5 @@ function fib(n)
     return fib(n - 1) + fib(n - 2)
 end
 
-print(fib(35))
+assert(fib(10) == 55)
+assert(fib(20) == 6765)
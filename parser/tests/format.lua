@@ -0,0 +1,67 @@
+local f = string.format
+
+-- Integers.
+assert(f("%5d|%-5d|%05d", 42, 42, 42) == "   42|42   |00042")
+assert(f("%+d % d %d", 5, 5, -5) == "+5  5 -5")
+assert(f("%.3d|%.0d|%i", 7, 0, 3.0) == "007||3")
+assert(f("%d", "10") == "10")
+assert(f("%u", 42) == "42")
+assert(f("%x %X %#x %o %#o", 255, 255, 255, 8, 8) == "ff FF 0xff 10 010")
+assert(f("%x", -1) == "ffffffffffffffff")
+assert(f("%#06x|%-6x|", 10, 10) == "0x000a|a     |")
+assert(f("%c%c|%3c|%-3c|", 76, 117, 65, 66) == "Lu|  A|B  |")
+
+-- Floats.
+assert(f("%f", 1) == "1.000000")
+assert(f("%5.2f|%-8.3f|%08.3f", 3.14159, 2.5, -2.5) == " 3.14|2.500   |-002.500")
+assert(f("%+.1f % .1f %.0f %#.0f", 1.25, 1.25, 2.5, 2) == "+1.2  1.2 2 2.")
+assert(f("%e", 12345.678) == "1.234568e+04")
+assert(f("%.2E", 0.000123) == "1.23E-04")
+assert(f("%.0e", 5) == "5e+00")
+assert(f("%g %g %g %g", 100000, 1000000, 0.0001, 0.00001) == "100000 1e+06 0.0001 1e-05")
+assert(f("%g|%.3g|%#.3g|%G", 3.14159265, 2, 2, 1e-10) == "3.14159|2|2.00|1E-10")
+assert(f("%10.4g|%-10g|", 123.456, 0.5) == "     123.5|0.5       |")
+assert(f("%a %a %A", 1, 0.5, 3) == "0x1p+0 0x1p-1 0X1.8P+1")
+assert(f("%.1a %.0a %a", 1, 1.5, 0) == "0x1.0p+0 0x2p+0 0x0p+0")
+assert(f("%f %5.1f %e", 1/0, -1/0, 1/0) == "inf  -inf inf")
+assert(f("%F", 1/0) == "INF")
+
+-- Strings.
+assert(f("%s %s %s", 1, 2.5, nil) == "1 2.5 nil")
+assert(f("%10s|%-10s|%.2s|%5.1s", "hi", "hi", "hello", "xyz") == "        hi|hi        |he|    x")
+assert(f("%s", setmetatable({}, {
+    __tostring = function()
+        return "object"
+    end
+})) == "object")
+local long = string.rep("x", 200)
+assert(f("%s", long) == long)
+assert(f("%%d %d%%", 1) == "%d 1%")
+
+-- Literals.
+assert(f("%q", "a \"quoted\"\n\\") == "\"a \\\"quoted\\\"\\\n\\\\\"")
+assert(f("%q", string.char(0) .. "x" .. string.char(1) .. "2") == "\"\\0x\\0012\"")
+assert(f("%q", "tab\there") == "\"tab\\9here\"")
+assert(f("%q", "\200\255") == "\"\200\255\"" and #f("%q", "\200") == 3)
+assert(f("%3s|%-3s|%.1s", "\xff", "\xff", "\xff\xfe") == "  \xff|\xff  |\xff")
+assert(#f("%s%s", "\200", 1) == 2)
+assert(f("%q %q %q %q", 42, 0.5, 1/0, -1/0) == "42 0x1p-1 1e9999 -1e9999")
+assert(f("%q %q %q", 2.0, -3.0, math.mininteger) == "0x1p+1 -0x1.8p+1 0x8000000000000000")
+assert(f("%q %q", nil, true) == "nil true")
+
+-- Errors.
+local function format_error(...)
+    local ok, err = pcall(string.format, ...)
+    assert(not ok)
+    return err
+end
+assert(format_error("%y", 1) == "invalid conversion '%y' to 'format'")
+assert(format_error("%#d", 1) == "invalid conversion specification: '%#d'")
+assert(format_error("%100d", 1) == "invalid conversion specification: '%100d'")
+assert(format_error("%.3c", 65) == "invalid conversion specification: '%.3c'")
+assert(format_error("%5q", "x") == "specifier '%q' cannot have modifiers")
+assert(format_error("%d") == "bad argument #2 to 'format' (no value)")
+assert(format_error("%d", "x") == "bad argument #2 to 'format' (number expected, got string)")
+assert(format_error("%d", 1.5) == "bad argument #2 to 'format' (number has no integer representation)")
+assert(format_error("%q", {}) == "bad argument #2 to 'format' (value has no literal form)")
+assert(format_error("%5s", "a" .. string.char(0)) == "bad argument #2 to 'format' (string contains zeros)")
@@ -0,0 +1,86 @@
+local t = {10, 20, 30}
+local sum = 0
+local n = 0
+for i, v in ipairs(t) do
+    assert(t[i] == v)
+    sum = sum + v
+    n = n + 1
+end
+assert(sum == 60 and n == 3)
+
+-- ipairs stops at the first nil.
+t[5] = 50
+n = 0
+for _, v in ipairs(t) do
+    n = n + 1
+end
+assert(n == 3)
+
+local m = {a = 1, b = 2, c = 3}
+m[1] = 4
+sum = 0
+n = 0
+for k, v in pairs(m) do
+    assert(m[k] == v)
+    sum = sum + v
+    n = n + 1
+end
+assert(sum == 10 and n == 4)
+
+-- Fields may be cleared during traversal.
+for k in pairs(m) do
+    m[k] = nil
+end
+assert(next(m) == nil)
+
+local k, v = next({x = 1})
+assert(k == "x" and v == 1)
+
+-- A stateless iterator with explicit state and control variable.
+local step = function(limit, i)
+    if i < limit then
+        return i + 1, i * 2
+    end
+end
+sum = 0
+for i, d in step, 3, 0 do
+    sum = sum + i + d
+end
+assert(sum == 12)
+
+-- A stateful iterator built from a closure.
+function range(n)
+    local i = 0
+    return function()
+        i = i + 1
+        if i <= n then
+            return i
+        end
+    end
+end
+sum = 0
+for i in range(4) do
+    sum = sum + i
+end
+assert(sum == 10)
+
+-- Missing values are nil.
+for a, b, c in range(1) do
+    assert(a == 1 and b == nil and c == nil)
+end
+
+n = 0
+for i in range(100) do
+    if i > 5 then
+        break
+    end
+    n = i
+end
+assert(n == 5)
+
+-- Each iteration gets fresh variables.
+local fns = {}
+for i, v in ipairs({"x", "y"}) do
+    fns[i] = function() return v end
+end
+assert(fns[1]() == "x" and fns[2]() == "y")
@@ -0,0 +1,75 @@
+-- continue
+local sum = 0
+for i = 1, 10 do
+    local odd = i % 2 == 1
+    if odd then
+        goto continue
+    end
+    sum = sum + i
+    ::continue::
+end
+assert(sum == 30)
+
+local i = 0
+sum = 0
+while i < 10 do
+    i = i + 1
+    local x = i * 2
+    if x > 10 then goto continue end
+    sum = sum + x
+    ::continue::
+end
+assert(sum == 30)
+
+-- A backward goto as a loop.
+local n = 0
+::top::
+n = n + 1
+if n < 5 then
+    goto top
+end
+assert(n == 5)
+
+-- Leaving nested loops.
+local found
+for a = 1, 5 do
+    for b = 1, 5 do
+        if a * b == 12 then
+            found = a * 10 + b
+            goto done
+        end
+    end
+end
+::done::
+assert(found == 34)
+
+-- Labels in sibling blocks do not clash.
+do
+    goto skip
+    assert(false)
+    ::skip::
+end
+do
+    goto skip
+    assert(false)
+    ::skip::
+end
+
+-- Jumping over nested blocks with locals is fine.
+do
+    goto out
+    do
+        local y = 1
+    end
+    ::out::
+end
+
+-- Each backward jump creates fresh locals.
+local fns = {}
+local k = 1
+::again::
+local v = k
+fns[k] = function() return v end
+k = k + 1
+if k <= 3 then goto again end
+assert(fns[1]() == 1 and fns[2]() == 2 and fns[3]() == 3)
@@ -0,0 +1,46 @@
+-- Strings.
+assert(#"" == 0)
+assert(#"hello" == 5)
+local s = "ab"
+assert(#(s .. "cd") == 4)
+
+-- Sequences.
+assert(#{} == 0)
+assert(#{1, 2, 3} == 3)
+local t = {}
+for i = 1, 100 do
+    t[i] = i
+end
+assert(#t == 100)
+t[#t + 1] = 101
+assert(#t == 101)
+t[#t] = nil
+assert(#t == 100)
+
+-- Non-sequence keys do not count.
+local m = {1, 2, x = 1, y = 2, [true] = 3, [2.5] = 4}
+assert(#m == 2)
+assert(#{x = 1} == 0)
+
+-- Sequences in the hash part.
+local h = {}
+for i = 10, 1, -1 do
+    h[i] = i
+end
+assert(#h == 10)
+local r = {}
+r[1] = 1
+r[2] = 2
+r[3] = 3
+assert(#r == 3)
+
+-- Any border may be returned for tables with holes.
+local holes = {1, 2, nil, 4}
+local n = #holes
+assert(n == 2 or n == 4)
+assert(holes[n] ~= nil and holes[n + 1] == nil)
+
+-- Precedence.
+assert(#"abc" + 1 == 4)
+assert(-#"abc" == -3)
+assert(#{1, 2} * 2 == 4)
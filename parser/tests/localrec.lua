@@ -0,0 +1,56 @@
+local function fact(n)
+    if n <= 1 then
+        return 1
+    end
+    return n * fact(n - 1)
+end
+assert(fact(5) == 120)
+
+-- The local does not leak into globals.
+assert(fib == nil)
+local function fib(n)
+    if n < 2 then
+        return n
+    end
+    return fib(n - 1) + fib(n - 2)
+end
+assert(fib(10) == 55)
+assert(fib ~= nil)
+
+-- Recursion refers to the local even if a global has the same name.
+function count(n)
+    return -1
+end
+local function count(n)
+    if n == 0 then
+        return 0
+    end
+    return 1 + count(n - 1)
+end
+assert(count(3) == 3)
+
+-- Nested local functions capture each other as upvalues.
+local function outer(n)
+    local function inner(m)
+        if m == 0 then
+            return outer(n - 1)
+        end
+        return inner(m - 1) + 1
+    end
+    if n == 0 then
+        return 0
+    end
+    return inner(n)
+end
+assert(outer(3) == 6)
+
+-- Reassigning the local changes what the recursive call refers to.
+local function f(n)
+    if n == 0 then
+        return "old"
+    end
+    return f(n - 1)
+end
+local g = f
+f = function(n) return "new" end
+assert(g(1) == "new")
@@ -0,0 +1,102 @@
+-- Rounding.
+assert(math.floor(3.7) == 3 and math.floor(-3.2) == -4)
+assert(math.ceil(3.2) == 4 and math.ceil(-3.7) == -3)
+assert(math.floor(5) == 5 and math.type(math.floor(2.5)) == "integer")
+assert(math.type(math.ceil(-0.5)) == "integer" and math.type(math.floor(1e100)) == "float")
+assert(math.abs(-4) == 4 and math.abs(2.5) == 2.5)
+
+-- Elementary functions.
+assert(math.sqrt(16) == 4)
+assert(math.sin(0) == 0 and math.cos(0) == 1 and math.tan(0) == 0)
+assert(math.abs(math.asin(1) - math.pi / 2) < 1e-12)
+assert(math.abs(math.acos(0) - math.pi / 2) < 1e-12)
+assert(math.abs(math.atan(1) - math.pi / 4) < 1e-12)
+assert(math.abs(math.atan(1, -1) - 3 * math.pi / 4) < 1e-12)
+assert(math.exp(0) == 1)
+assert(math.log(1) == 0 and math.log(8, 2) == 3 and math.log(100, 10) == 2)
+assert(math.abs(math.log(27, 3) - 3) < 1e-12)
+
+-- fmod and modf.
+assert(math.fmod(7, 3) == 1 and math.fmod(-7, 3) == -1 and math.fmod(7, -3) == 1)
+assert(math.fmod(5.5, 2) == 1.5)
+assert(math.fmod(math.mininteger, -1) == 0)
+assert(math.type(math.fmod(7, 3)) == "integer" and math.type(math.fmod(7.0, 3)) == "float")
+local ip, fp = math.modf(3.25)
+assert(ip == 3 and fp == 0.25)
+ip, fp = math.modf(-3.25)
+assert(ip == -3 and fp == -0.25)
+ip, fp = math.modf(math.huge)
+assert(ip == math.huge and fp == 0)
+
+-- min and max.
+assert(math.min(3, 1, 2) == 1 and math.max(3, 1, 2) == 3)
+assert(math.min(5) == 5 and math.max(-1.5, -2) == -1.5)
+
+-- Constants.
+assert(math.huge > 1e308 and -math.huge < -1e308)
+assert(math.pi > 3.14159 and math.pi < 3.1416)
+assert(math.maxinteger + 1.0 == 2 ^ 63)
+assert(math.mininteger == -2 ^ 63)
+assert(math.type(math.maxinteger) == "integer")
+assert(math.ult(1, 2) and not math.ult(2, 1))
+assert(math.ult(1, -1) and not math.ult(-1, 1))
+assert(math.ult(math.maxinteger, math.mininteger))
+
+-- Subtypes.
+assert(math.type(1) == "integer")
+assert(math.type(1.5) == "float" and math.type(1.0) == "float")
+assert(math.type(math.huge) == "float")
+assert(math.type(0 / 0) == "float")
+assert(math.type("1") == nil and math.type({}) == nil)
+assert(math.tointeger(3) == 3 and math.tointeger(3.5) == nil)
+assert(math.tointeger("8") == 8 and math.tointeger("x") == nil)
+assert(math.type(math.tointeger(3.0)) == "integer")
+assert(math.tointeger(math.maxinteger) == math.maxinteger)
+
+-- Random numbers.
+for _ = 1, 100 do
+    local r = math.random()
+    assert(r >= 0 and r < 1)
+    r = math.random(6)
+    assert(r >= 1 and r <= 6 and math.type(r) == "integer")
+    r = math.random(-3, 3)
+    assert(r >= -3 and r <= 3)
+end
+assert(math.random(7, 7) == 7)
+assert(math.type(math.random(0)) == "integer" and math.type(math.random(1, 2)) == "integer")
+
+-- A seed always gives the same sequence.
+local function sequence(seed)
+    math.randomseed(seed)
+    local t = {}
+    for i = 1, 20 do
+        t[i] = math.random(1000)
+    end
+    t[21] = math.random()
+    return t
+end
+local a, b, c = sequence(42), sequence(42), sequence(7)
+local same_as_other = true
+for i = 1, 21 do
+    assert(a[i] == b[i])
+    if a[i] ~= c[i] then
+        same_as_other = false
+    end
+end
+assert(not same_as_other)
+local n1, n2 = math.randomseed(123, 4)
+assert(n1 == 123 and n2 == 4)
+
+-- Errors.
+local function fails(f, msg)
+    local ok, err = pcall(f)
+    assert(not ok)
+    assert(string.find(err, msg, 1, true), err)
+end
+fails(function() math.floor("x") end, "bad argument #1 to 'floor' (number expected, got string)")
+fails(function() math.min() end, "bad argument #1 to 'min' (number expected, got no value)")
+fails(function() math.fmod(1, 0) end, "bad argument #2 to 'fmod' (zero)")
+fails(function() math.random(3, 1) end, "bad argument #1 to 'random' (interval is empty)")
+fails(function() math.random(1, 2, 3) end, "wrong number of arguments")
+fails(function() math.random(1.5) end, "bad argument #1 to 'random' (number has no integer representation)")
+fails(function() math.tointeger() end, "bad argument #1 to 'tointeger' (value expected)")
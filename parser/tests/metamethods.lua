@@ -0,0 +1,113 @@
+-- Callable tables.
+local adder = setmetatable({n = 10}, {
+    __call = function(self, a, b)
+        return self.n + a + b
+    end
+})
+assert(adder(1, 2) == 13)
+
+local obj = {add = adder}
+assert(obj.add(3, 4) == 17)
+
+-- __tostring.
+local named = setmetatable({}, {
+    __tostring = function()
+        return "named"
+    end
+})
+assert(tostring(named) == "named")
+assert(tostring(nil) == "nil")
+
+-- To-be-closed variables.
+local log = {}
+local function closable(name)
+    return setmetatable({}, {
+        __close = function(self, err)
+            log[#log + 1] = name
+        end
+    })
+end
+
+do
+    local a <close> = closable("a")
+    local b <close>, c = closable("b"), 1
+    local d <close> = nil
+    local e <const> = 5
+    assert(c + e == 6)
+end
+assert(#log == 2 and log[1] == "b" and log[2] == "a")
+
+local function ret()
+    local x <close> = closable("ret")
+    return #log
+end
+log = {}
+assert(ret() == 0 and log[1] == "ret")
+
+log = {}
+for i = 1, 3 do
+    local x <close> = closable(i)
+    if i == 2 then
+        break
+    end
+end
+assert(#log == 2 and log[1] == 1 and log[2] == 2)
+
+log = {}
+do
+    local x <close> = closable("goto")
+    goto out
+end
+::out::
+assert(log[1] == "goto")
+
+-- Finalizers.
+local collected = {}
+local function make_garbage(name)
+    setmetatable({}, {
+        __gc = function(self)
+            collected[#collected + 1] = name
+        end
+    })
+end
+make_garbage("x")
+local kept = setmetatable({}, {
+    __gc = function(self)
+        collected[#collected + 1] = "kept"
+    end
+})
+collectgarbage()
+assert(#collected == 1 and collected[1] == "x")
+
+-- The size of the heap in kilobytes grows with live objects.
+local before = collectgarbage("count")
+local live = {}
+for i = 1, 1000 do
+    live[i] = {}
+end
+assert(math.type(before) == "float" and collectgarbage("count") > before)
+live = nil
+collectgarbage()
+assert(collectgarbage("count") < before + 1)
+
+-- Weak tables.
+local weak_keys = setmetatable({}, {__mode = "k"})
+local weak_values = setmetatable({}, {__mode = "v"})
+local function fill()
+    weak_keys[{}] = 1
+    weak_values[1] = {}
+    weak_values[2] = function() end
+end
+fill()
+local key = {}
+weak_keys[key] = 2
+weak_values.s = "strings stay"
+collectgarbage()
+
+local n = 0
+for k, v in pairs(weak_keys) do
+    n = n + 1
+end
+assert(n == 1 and weak_keys[key] == 2)
+assert(weak_values[1] == nil and weak_values[2] == nil)
+assert(weak_values.s == "strings stay")
@@ -0,0 +1,104 @@
+-- Classes.
+local Point = {}
+Point.__index = Point
+
+function Point.new(x, y)
+    return setmetatable({x = x, y = y}, Point)
+end
+
+function Point:len2()
+    return self.x * self.x + self.y * self.y
+end
+
+function Point:sum()
+    return self.x + self.y
+end
+
+local p = Point.new(3, 4)
+assert(p:len2() == 25)
+assert(rawequal(getmetatable(p), Point))
+assert(rawget(p, "len2") == nil)
+
+-- Inheritance through chained __index tables.
+local Point3 = setmetatable({}, {__index = Point})
+Point3.__index = Point3
+function Point3.new(x, y, z)
+    local o = Point.new(x, y)
+    o.z = z
+    return setmetatable(o, Point3)
+end
+function Point3:len2()
+    return Point.len2(self) + self.z * self.z
+end
+local q = Point3.new(1, 2, 2)
+assert(q:len2() == 9)
+assert(rawequal(q.new, Point3.new))
+assert(q:sum() == 3)
+
+-- Default values with an __index function.
+local calls = 0
+local defaults = setmetatable({}, {__index = function(t, k)
+    calls = calls + 1
+    return k .. "!"
+end})
+defaults.a = "set"
+assert(defaults.a == "set")
+assert(defaults.b == "b!")
+assert(calls == 1)
+
+-- __newindex as a function.
+local log = {}
+local proxy = setmetatable({}, {__newindex = function(t, k, v)
+    rawset(t, k, v * 2)
+    log[#log + 1] = k
+end})
+proxy.x = 1
+assert(rawget(proxy, "x") == 2)
+proxy.x = 5
+assert(proxy.x == 5)
+assert(#log == 1 and log[1] == "x")
+
+-- __newindex as a table.
+local store = {}
+local redirect = setmetatable({}, {__newindex = store})
+redirect.y = 7
+assert(rawget(redirect, "y") == nil and store.y == 7)
+
+-- rawset and rawget bypass metamethods.
+rawset(redirect, "z", 1)
+assert(rawget(redirect, "z") == 1 and store.z == nil)
+assert(rawget(defaults, "missing") == nil)
+
+-- Read-only tables.
+local ro = setmetatable({}, {
+    __index = {a = 1},
+    __newindex = function(t, k, v)
+        error_called = true
+    end
+})
+ro.a = 2
+assert(ro.a == 1 and error_called)
+
+-- __len.
+local sized = setmetatable({}, {__len = function(t)
+    return 42
+end})
+assert(#sized == 42)
+
+-- Protected metatables.
+local mt = {__metatable = "locked"}
+local locked = setmetatable({}, mt)
+assert(getmetatable(locked) == "locked")
+
+-- Removing a metatable.
+setmetatable(p, nil)
+assert(getmetatable(p) == nil)
+assert(p.len2 == nil)
+
+assert(getmetatable(1) == nil)
+assert(getmetatable("s").__index == string)
+
+-- rawequal.
+local a, b = {}, {}
+assert(rawequal(a, a) and not rawequal(a, b))
+assert(rawequal("x", "x") and rawequal(1, 1) and not rawequal(1, "1"))
@@ -0,0 +1,68 @@
+local Account = {}
+
+function Account.new(balance)
+    local self = {}
+    self.balance = balance
+    self.deposit = Account.deposit
+    self.get = Account.get
+    return self
+end
+
+function Account:deposit(v)
+    self.balance = self.balance + v
+    return self
+end
+
+function Account:get()
+    return self.balance
+end
+
+local a = Account.new(10)
+a:deposit(5)
+assert(a:get() == 15)
+assert(a.get(a) == 15)
+
+-- Calls can be chained.
+assert(a:deposit(1):deposit(2):get() == 18)
+
+-- The receiver is evaluated only once.
+local n = 0
+local function obj()
+    n = n + 1
+    return a
+end
+obj():deposit(2)
+assert(n == 1 and a:get() == 20)
+
+-- Methods may return multiple values, and take varargs.
+local t = {name = "t"}
+function t:pair(...)
+    return self, select('#', ...)
+end
+local s, count = t:pair(1, 2, 3)
+assert(s.name == "t" and count == 3)
+assert(select('#', t:pair()) == 2)
+assert((t:pair()).name == "t")
+
+-- A method call can be the last argument of another call.
+function t:sum(a, b, c)
+    return a + b + c
+end
+function t:three()
+    return 1, 2, 3
+end
+assert(t:sum(t:three()) == 6)
+
+-- Nested tables.
+local ns = {inner = {v = 3}}
+function ns.inner:get()
+    return self.v
+end
+assert(ns.inner:get() == 3)
+
+-- String and table call arguments.
+function t:id(v)
+    return v
+end
+assert(t:id "x" == "x")
+assert(t:id {4}[1] == 4)
@@ -0,0 +1,45 @@
+local a, b = 1
+assert(a == 1)
+assert(b == nil)
+
+local x, y
+assert(x == nil)
+assert(y == nil)
+
+local c, d = 1, 2, 3
+assert(c == 1)
+assert(d == 2)
+
+a, b = 1, 2
+a, b = b, a
+assert(a == 2)
+assert(b == 1)
+
+-- Extra values are still evaluated.
+local calls = 0
+function count()
+    calls = calls + 1
+    return calls
+end
+local e = 5, count(), count()
+assert(e == 5)
+assert(calls == 2)
+
+-- All expressions are evaluated before any assignment.
+local i = 3
+local t = {}
+i, t[i] = i + 1, 20
+assert(i == 4)
+assert(t[3] == 20)
+assert(t[4] == nil)
+
+-- New locals are not visible in their own initializers.
+local n = 1
+local n, m = 2, n
+assert(n == 2)
+assert(m == 1)
+
+g1, g2, t.k = 1, 2
+assert(g1 == 1)
+assert(g2 == 2)
+assert(t.k == nil)
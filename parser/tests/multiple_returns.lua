@@ -0,0 +1,50 @@
+function none() end
+function one() return 1 end
+function three() return 1, 2, 3 end
+
+function count(a, b, c, d)
+    local n = 0
+    if a ~= nil then n = n + 1 end
+    if b ~= nil then n = n + 1 end
+    if c ~= nil then n = n + 1 end
+    if d ~= nil then n = n + 1 end
+    return n
+end
+
+-- Expansion in the last argument, truncation elsewhere.
+assert(count(three()) == 3)
+assert(count(three(), three()) == 4)
+assert(count(three(), 10) == 2)
+assert(count((three())) == 1)
+assert(count(none()) == 0)
+assert(count(1, none()) == 1)
+
+-- Expansion in return statements.
+function forward() return three() end
+function prepend() return 0, three() end
+function truncated() return (three()) end
+assert(count(forward()) == 3)
+assert(count(prepend()) == 4)
+assert(count(truncated()) == 1)
+
+local a, b, c, d = prepend()
+assert(a == 0 and b == 1 and c == 2 and d == 3)
+
+-- Expansion in table constructors.
+local t = { three() }
+assert(t[1] == 1 and t[2] == 2 and t[3] == 3)
+t = { three(), three() }
+assert(t[1] == 1 and t[2] == 1 and t[3] == 2 and t[4] == 3)
+t = { three(), k = 1 }
+assert(t[1] == 1 and t[2] == nil)
+t = { (three()) }
+assert(t[1] == 1 and t[2] == nil)
+
+-- Truncation in expressions.
+assert(three() + 1 == 2)
+local x = three()
+assert(x == 1)
+local y, z = none()
+assert(y == nil and z == nil)
+assert(none() == nil)
+assert(one() == 1)
@@ -0,0 +1,109 @@
+local sum = 0
+for i = 1, 10 do
+    sum = sum + i
+end
+assert(sum == 55)
+
+sum = 0
+for i = 10, 1, -1 do
+    sum = sum + i
+end
+assert(sum == 55)
+
+local n = 0
+for i = 0, 1, 0.25 do
+    n = n + 1
+end
+assert(n == 5)
+
+-- An empty range does not run the body at all.
+n = 0
+for i = 1, 0 do
+    n = n + 1
+end
+for i = 0, 1, -1 do
+    n = n + 1
+end
+assert(n == 0)
+
+-- Bounds are evaluated only once.
+local calls = 0
+local limit = function()
+    calls = calls + 1
+    return 3
+end
+n = 0
+for i = 1, limit() do
+    n = n + 1
+end
+assert(n == 3 and calls == 1)
+
+-- Assigning to the control variable does not affect the iteration.
+n = 0
+for i = 1, 3 do
+    i = i * 10
+    n = n + 1
+end
+assert(n == 3)
+
+-- Each iteration gets a fresh variable.
+local fns = {}
+for i = 1, 3 do
+    fns[i] = function() return i end
+end
+assert(fns[1]() == 1 and fns[2]() == 2 and fns[3]() == 3)
+
+n = 0
+for i = 1, 100 do
+    if i > 5 then
+        break
+    end
+    n = i
+end
+assert(n == 5)
+
+-- Nested loops.
+sum = 0
+for i = 1, 3 do
+    for j = i, 3 do
+        sum = sum + j
+    end
+end
+assert(sum == 14)
+
+-- Integer bounds give integer control variables, without overflowing at
+-- the ends of the integer range.
+local types = {}
+for i = 1, 3 do
+    types[#types + 1] = math.type(i)
+end
+for i = 1, 2, 0.5 do
+    types[#types + 1] = math.type(i)
+end
+assert(table.concat(types, ",") == "integer,integer,integer,float,float,float")
+n = 0
+for i = math.maxinteger - 2, math.maxinteger do
+    n = n + 1
+end
+for i = math.mininteger, math.mininteger + 2 do
+    n = n + 1
+end
+for i = math.mininteger + 2, math.mininteger, -1 do
+    n = n + 1
+end
+assert(n == 9)
+n = 0
+for i = 1, 3.5 do
+    n = n + i
+end
+for i = 3, 0.5, -1 do
+    n = n + i
+end
+for i = 1, math.huge do
+    n = n + 1
+    if i == 3 then break end
+end
+for i = 1, -math.huge do
+    n = n + 1
+end
+assert(n == 15)
@@ -0,0 +1,102 @@
+-- Plain numbers and strings.
+assert(7 // 2 == 3 and -7 // 2 == -4)
+assert(-7 % 3 == 2 and 7 % -3 == -2 and 5.5 % 2 == 1.5)
+assert(2 ^ 10 == 1024)
+assert("10" + 1 == 11 and "3" * "4" == 12)
+assert(1 .. 2 == "12" and "a" .. 1.5 == "a1.5")
+assert("a" < "b" and "abc" < "abd" and "Z" < "a" and "" <= "")
+assert(not ("b" < "a") and "b" > "a" and "b" >= "b")
+local s = "x"
+assert(s ~= nil and not (s == nil) and s ~= 1)
+
+-- Integers stay integers and wrap around; '/' and '^' give floats.
+assert(math.maxinteger - 1 == 9223372036854775806)
+assert(string.format("%d", math.maxinteger - 1) == "9223372036854775806")
+assert(math.maxinteger + 1 == math.mininteger and -math.mininteger == math.mininteger)
+assert(math.mininteger // -1 == math.mininteger and math.mininteger % -1 == 0)
+assert(tostring(3 * 4) == "12" and tostring(6 / 2) == "3.0" and tostring(2 ^ 2) == "4.0")
+assert(tostring(7 // 2.0) == "3.0" and tostring(1 + 0.5) == "1.5" and tostring(-0.0) == "-0.0")
+assert(tostring("10" + 1) == "11" and tostring("1.5" * 2) == "3.0")
+assert(tostring(1e15) == "1e+15" and tostring(2^53) == "9.007199254741e+15")
+assert(9007199254740993 > 9007199254740992 and 1 / 0 > math.maxinteger)
+-- Integers and floats are ordered exactly, even beyond 2^53.
+assert(2^53 + 1 < 2.0^53 + 2 and 9007199254740993 < 2.0^53 + 2)
+assert(not (9007199254740993 <= 2.0^53) and 2.0^53 < 9007199254740993)
+assert(math.maxinteger < 2.0^63 and math.mininteger <= -2.0^63 and not (math.mininteger < -2.0^63))
+assert(1 < 1.5 and not (2 < 1.5) and 1 <= 1.0 and -2 < -1.5 and not (-1 <= -1.5))
+assert(not (1 < 0 / 0) and not (0 / 0 <= 1) and -1 / 0 < math.mininteger)
+
+-- Strings are coerced following the syntax of numerals.
+assert("0x10" + 0 == 16 and math.type("0x10" + 0) == "integer")
+assert("0x1p4" + 0 == 16.0 and " -0x10 " + 0 == -16 and "\t1e2\n" * 1 == 100.0)
+assert("-9223372036854775808" + 0 == math.mininteger and "+.5" + 0 == 0.5)
+assert(math.type("9223372036854775808" + 0) == "float")
+for _, v in ipairs({"inf", "-inf", "nan", "infinity", "1e5f", "1e", "0x", "- 1", "1 2", ""}) do
+    assert(not pcall(function() return v + 0 end))
+end
+
+-- Identity equality of tables and functions.
+local t, u = {}, {}
+local f = function() end
+assert(t == t and t ~= u and f == f and t ~= f and t ~= nil)
+
+-- Arithmetic metamethods.
+local V = {}
+V.__index = V
+
+local function vec(x, y)
+    return setmetatable({x = x, y = y}, V)
+end
+
+V.__add = function(a, b) return vec(a.x + b.x, a.y + b.y) end
+V.__sub = function(a, b) return vec(a.x - b.x, a.y - b.y) end
+V.__mul = function(a, b)
+    if getmetatable(a) ~= V then
+        return vec(a * b.x, a * b.y)
+    end
+    return vec(a.x * b, a.y * b)
+end
+V.__div = function(a, b) return vec(a.x / b, a.y / b) end
+V.__mod = function(a, b) return vec(a.x % b, a.y % b) end
+V.__pow = function(a, b) return vec(a.x ^ b, a.y ^ b) end
+V.__idiv = function(a, b) return vec(a.x // b, a.y // b) end
+V.__unm = function(a) return vec(-a.x, -a.y) end
+V.__concat = function(a, b)
+    if getmetatable(a) == V then
+        a = "(" .. a.x .. ", " .. a.y .. ")"
+    end
+    if getmetatable(b) == V then
+        b = "(" .. b.x .. ", " .. b.y .. ")"
+    end
+    return a .. b
+end
+V.__eq = function(a, b) return a.x == b.x and a.y == b.y end
+V.__lt = function(a, b) return a.x * a.x + a.y * a.y < b.x * b.x + b.y * b.y end
+V.__le = function(a, b) return not (b < a) end
+
+local a, b = vec(1, 2), vec(3, 4)
+assert(a + b == vec(4, 6))
+assert(b - a == vec(2, 2))
+assert(a * 2 == vec(2, 4) and 2 * a == vec(2, 4))
+assert(b / 2 == vec(1.5, 2))
+assert(b % 3 == vec(0, 1))
+assert(a ^ 2 == vec(1, 4))
+assert(b // 2 == vec(1, 2))
+assert(-a == vec(-1, -2))
+assert(a .. "!" == "(1, 2)!" and "v" .. b == "v(3, 4)")
+assert(a .. b == "(1, 2)(3, 4)")
+
+-- __eq is only used for two distinct tables.
+assert(a == vec(1, 2) and a ~= b and a ~= nil and a ~= 1)
+local eq_calls = 0
+local E = {__eq = function(x, y)
+    eq_calls = eq_calls + 1
+    return true
+end}
+local e1, e2 = setmetatable({}, E), {}
+assert(e1 == e1 and eq_calls == 0)
+assert(e1 == e2 and e2 == e1 and eq_calls == 2)
+
+-- Comparisons, with `a > b` evaluated as `b < a`.
+assert(a < b and b > a and a <= b and b >= a and a <= a)
+assert(not (b < a) and not (a > b))
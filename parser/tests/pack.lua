@@ -0,0 +1,82 @@
+local pack, unpack, packsize = string.pack, string.unpack, string.packsize
+
+-- Endianness.
+assert(pack(">i2", 0x4142) == "AB")
+assert(pack("<i2", 0x4142) == "BA")
+assert(pack(">I3", 0x414243) == "ABC")
+assert(pack("<i4", 0x41) == "A\0\0\0")
+assert(unpack(">i2", "AB") == 0x4142)
+assert(unpack("<i2", "AB") == 0x4241)
+
+-- Signed values are sign extended.
+assert(unpack("<i2", "\xC3\xBF") == -16445)
+assert(unpack("<I2", "\xC3\xBF") == 0xbfc3)
+assert(pack("<i2", -16445) == "\xC3\xBF")
+
+-- Any bytes round-trip.
+assert(#pack("B", 255) == 1 and pack("B", 255) == "\xff")
+assert(unpack("i4", pack("i4", 100000)) == 100000)
+assert(unpack("<i8", pack("<i8", math.mininteger)) == math.mininteger)
+assert(unpack(">j", pack(">j", -123456789)) == -123456789)
+assert(unpack("<I4", pack("<I4", 0xfedcba98)) == 0xfedcba98)
+assert(math.type(unpack("i4", pack("i4", 7))) == "integer")
+assert(unpack("<d", pack("<d", -0.1)) == -0.1 and unpack(">d", pack(">d", 1e300)) == 1e300)
+assert(unpack("<f", pack("<f", 0.75)) == 0.75 and unpack("<d", pack("<d", 2.0^-1074)) == 2.0^-1074)
+assert(unpack("<d", pack("<d", math.huge)) == math.huge)
+assert(unpack("s1", pack("s1", "\0\200\255")) == "\0\200\255")
+assert(unpack(">i16", pack(">i16", 3)) == 3)
+assert(unpack("<i9", pack("<i9", 12345)) == 12345)
+
+-- Floats.
+assert(unpack("<d", pack("<d", 2.0)) == 2.0)
+assert(unpack(">f", pack(">f", 2)) == 2)
+assert(unpack("n", pack("n", 3)) == 3)
+assert(pack(">d", 2) == "\64\0\0\0\0\0\0\0")
+
+-- Strings.
+assert(pack("z", "hi") == "hi\0")
+assert(pack(">s1", "hi") == "\2hi")
+assert(pack("<s2", "hi") == "\2\0hi")
+assert(pack("c5", "hi") == "hi\0\0\0")
+local s, z, c, nextpos = unpack("s1zc3", "\3abcdef\0ghi!")
+assert(s == "abc" and z == "def" and c == "ghi" and nextpos == 12)
+
+-- Alignment and padding.
+assert(packsize("!4 b i4") == 8)
+assert(packsize("b i4") == 5)
+assert(packsize("!8 b Xd") == 8)
+assert(packsize("b x h") == 4)
+assert(pack("<!2 b h", 1, 2) == "\1\0\2\0")
+assert(pack("<!4 b i4", 65, 66) == "A\0\0\0B\0\0\0")
+assert(packsize("i3 i16 j") == 27)
+
+-- Positions.
+local a, b, pos = unpack("bb", "\1\2\3\4", 2)
+assert(a == 2 and b == 3 and pos == 4)
+assert(unpack("b", "\1\2\3", -1) == 3)
+
+-- Multiple values round trip.
+local x, y, str, p = unpack("<i4 h z", pack("<i4 h z", 0x414243, 7, "name"))
+assert(x == 0x414243 and y == 7 and str == "name" and p == 12)
+
+-- Errors.
+local function fails(f, msg)
+    local ok, err = pcall(f)
+    assert(not ok)
+    assert(string.find(err, msg, 1, true), err)
+end
+fails(function() pack("i17", 1) end, "integral size (17) out of limits [1,16]")
+fails(function() pack("y", 1) end, "invalid format option 'y'")
+fails(function() pack("c", "a") end, "missing size for format option 'c'")
+fails(function() pack("b", 200) end, "bad argument #2 to 'pack' (integer overflow)")
+fails(function() pack("B", -1) end, "bad argument #2 to 'pack' (unsigned overflow)")
+fails(function() pack("c2", "abc") end, "string longer than given size")
+fails(function() pack("z", "a\0b") end, "string contains zeros")
+fails(function() pack("s1", string.rep("x", 256)) end, "string length does not fit in given size")
+fails(function() pack("!3 i4", 1) end, "format asks for alignment not power of 2")
+fails(function() pack("X", 1) end, "invalid next option for option 'X'")
+fails(function() packsize("s") end, "bad argument #1 to 'packsize' (variable-length format)")
+fails(function() unpack("i4", "abc") end, "bad argument #2 to 'unpack' (data string too short)")
+fails(function() unpack("z", "abc") end, "unfinished string for format 'z'")
+fails(function() unpack("b", "abc", 5) end, "initial position out of string")
+fails(function() unpack("<i9", "\0\0\0\0\0\0\0\0\1") end, "9-byte integer does not fit into Lua Integer")
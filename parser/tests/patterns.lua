@@ -0,0 +1,105 @@
+-- find, with plain searches and positions.
+assert(string.find("hello world", "wor") == 7)
+local s, e = string.find("hello world", "o", 6)
+assert(s == 8 and e == 8)
+s, e = string.find("a.b", ".", 1, true)
+assert(s == 2 and e == 2)
+assert(string.find("abc", "") == 1)
+assert(string.find("abc", "", 10) == nil)
+assert(string.find("abc", "x") == nil)
+s, e = string.find("key = value", "(%w+)%s*=%s*(%w+)")
+assert(s == 1 and e == 11)
+local _, _, k, v = string.find("key = value", "(%w+)%s*=%s*(%w+)")
+assert(k == "key" and v == "value")
+
+-- Character classes and sets.
+assert(string.match("abc123", "%a+") == "abc")
+assert(string.match("abc123", "%d+") == "123")
+assert(string.match("  x", "%S") == "x")
+assert(string.match("x-y", "[%w_]+%-") == "x-")
+assert(string.match("hello", "[^aeiou]+") == "h")
+assert(string.match("2024", "[0-9]+") == "2024")
+assert(string.match("a]b", "[]]") == "]")
+assert(string.match("f(x)", "%(.%)") == "(x)")
+
+-- Anchors and quantifiers.
+assert(string.match("hello", "^h") == "h")
+assert(string.match("hello", "^e") == nil)
+assert(string.match("hello", "o$") == "o")
+assert(string.match("a$b", "a$b") == "a$b")
+assert(string.match("<a><b>", "<.*>") == "<a><b>")
+assert(string.match("<a><b>", "<.->") == "<a>")
+assert(string.match("color", "colou?r") == "color")
+assert(string.match("", "a*") == "")
+
+-- Captures, positions, back references, balances and frontiers.
+local y, m, d = string.match("2024-01-15", "(%d+)-(%d+)-(%d+)")
+assert(y == "2024" and m == "01" and d == "15")
+local p1, word, p2 = string.match("say hello", "()(%a+)()", 4)
+assert(p1 == 5 and word == "hello" and p2 == 10)
+assert(string.match("'quoted' text", "(['\"])(.-)%1") == "'")
+assert(select(2, string.match("'quoted' text", "(['\"])(.-)%1")) == "quoted")
+assert(string.match("x = (a * (b + c)) + d", "%b()") == "(a * (b + c))")
+assert(string.match("THE (quick) fox", "%f[%a]%a+%f[%A]") == "THE")
+assert(string.match("hello", "()ll()") == 3)
+
+-- gmatch.
+local words = {}
+for w in string.gmatch("one two  three", "%a+") do
+    words[#words + 1] = w
+end
+assert(#words == 3 and words[1] == "one" and words[3] == "three")
+
+local pairs_found = {}
+for key, value in ("a=1, b=2"):gmatch("(%w+)=(%w+)") do
+    pairs_found[key] = value
+end
+assert(pairs_found.a == "1" and pairs_found.b == "2")
+
+local n = 0
+for _ in string.gmatch("abc", "") do
+    n = n + 1
+end
+assert(n == 4)
+
+-- gsub with string, table and function replacements.
+local r, count = string.gsub("hello world", "o", "0")
+assert(r == "hell0 w0rld" and count == 2)
+assert(string.gsub("hello world", "(%w+)", "<%1>") == "<hello> <world>")
+assert(string.gsub("hello", "", "-") == "-h-e-l-l-o-")
+assert(string.gsub("abc", "%w", "%0%0") == "aabbcc")
+assert(string.gsub("hello world", "%w+", "%%") == "% %")
+r, count = string.gsub("aaa", "a", "b", 2)
+assert(r == "bba" and count == 2)
+assert(string.gsub("hello", "^h", "H") == "Hello")
+assert(string.gsub("x x", "x", 5) == "5 5")
+
+local vars = {name = "lua", version = 5.4}
+assert(string.gsub("$name $version $missing", "%$(%w+)", vars) == "lua 5.4 $missing")
+assert(string.gsub("1 2 3", "%d", function(digit)
+    return digit * 2
+end) == "2 4 6")
+assert(string.gsub("abc", "%w", function(c)
+    if c == "b" then
+        return nil
+    end
+    return c:upper()
+end) == "AbC")
+
+-- Errors.
+local ok, err = pcall(string.find, "a", "%")
+assert(not ok and err == "malformed pattern (ends with '%')")
+ok, err = pcall(string.find, "a", "[a")
+assert(not ok and err == "malformed pattern (missing ']')")
+ok, err = pcall(string.match, "a", "(a")
+assert(not ok and err == "unfinished capture")
+ok, err = pcall(string.match, "a", "a)")
+assert(not ok and err == "invalid pattern capture")
+ok, err = pcall(string.match, "aa", "(a)%2")
+assert(not ok and err == "invalid capture index %2 in pattern")
+ok, err = pcall(string.gsub, "a", "a", "%2")
+assert(not ok and err == "invalid capture index %2")
+ok, err = pcall(string.gsub, "a", "a", "%x")
+assert(not ok and err == "invalid use of '%' in replacement string")
+ok, err = pcall(string.gsub, "a", "a", {a = {}})
+assert(not ok and err == "invalid replacement value (a table)")
@@ -0,0 +1,69 @@
+-- Substrings with negative and out of range positions.
+local s = "hello world"
+assert(string.sub(s, 1, 5) == "hello")
+assert(string.sub(s, -5) == "world")
+assert(string.sub(s, 7, -1) == "world")
+assert(string.sub(s, 0) == s)
+assert(string.sub(s, 5, 2) == "")
+assert(string.sub(s, -100, 100) == s)
+assert(string.sub(s, 12) == "")
+
+assert(string.len(s) == 11 and string.len("") == 0)
+assert(string.upper("MiXeD 123") == "MIXED 123")
+assert(string.lower("MiXeD 123") == "mixed 123")
+assert(string.reverse("abc") == "cba" and string.reverse("") == "")
+
+assert(string.rep("ab", 3) == "ababab")
+assert(string.rep("ab", 3, ",") == "ab,ab,ab")
+assert(string.rep("x", 0) == "" and string.rep("x", -1) == "")
+
+-- Bytes and characters.
+local a, b, c = string.byte("ABC", 1, -1)
+assert(a == 65 and b == 66 and c == 67)
+assert(string.byte("A") == 65)
+assert(string.byte("ABC", 10) == nil)
+assert(string.char(72, 105) == "Hi" and string.char() == "")
+
+-- Strings hold raw bytes.
+assert(#"\xff" == 1 and "\xff\200" == string.char(255, 200) and #"\u{e9}" == 2)
+local raw = string.char(0, 200, 255, 65)
+assert(#raw == 4 and raw:len() == 4 and #string.char(200) == 1)
+a, b, c = raw:byte(2, -1)
+assert(a == 200 and b == 255 and c == 65)
+assert(raw:reverse() == string.char(65, 255, 200, 0))
+assert(raw:sub(2, 3) == string.char(200, 255) and raw:upper() == string.char(0, 200, 255, 65))
+assert(raw:find(string.char(255), 1, true) == 3 and raw:rep(2, "-"):len() == 9)
+assert(string.char(200) < string.char(255) and "z" < string.char(128))
+
+-- Numbers are converted to strings.
+assert(string.len(123) == 3)
+assert(string.rep(1, 2) == "11")
+
+-- Methods through the string metatable.
+assert(s:upper() == "HELLO WORLD")
+assert(("abc"):rep(2) == "abcabc")
+assert(s:sub(1, 1):upper() == "H")
+assert(#s:reverse() == 11)
+assert(s.len == string.len)
+assert(getmetatable("").__index == string)
+
+-- Formatting.
+assert(string.format("%d items", 3) == "3 items")
+assert(string.format("%s=%s", "x", 1.5) == "x=1.5")
+assert(string.format("%.2f", 3.14159) == "3.14")
+assert(string.format("%x %X", 255, 255) == "ff FF")
+assert(string.format("100%%") == "100%")
+assert(string.format("%.3s", "abcdef") == "abc")
+assert(string.format("%c%c", 76, 117) == "Lu")
+assert(string.format("%s", setmetatable({}, {
+    __tostring = function()
+        return "custom"
+    end
+})) == "custom")
+
+local ok, err = pcall(string.rep)
+assert(not ok and err == "bad argument #1 to 'rep' (string expected, got no value)")
+ok, err = pcall(string.char, 256)
+assert(not ok and err == "bad argument #1 to 'char' (value out of range)")
+ok, err = pcall(string.format, "%d", 1.5)
+assert(not ok and err == "bad argument #2 to 'format' (number has no integer representation)")
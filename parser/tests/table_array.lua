@@ -0,0 +1,84 @@
+-- Sequential fill.
+local t = {}
+for i = 1, 100 do
+    t[i] = i * 2
+end
+local sum = 0
+for i = 1, 100 do
+    sum = sum + t[i]
+end
+assert(sum == 10100)
+
+-- Reverse fill goes through the hash part first.
+local r = {}
+for i = 100, 1, -1 do
+    r[i] = i
+end
+for i = 1, 100 do
+    assert(r[i] == i)
+end
+
+-- Holes, and keys outside of the array part.
+local h = {1, 2, 3}
+h[2] = nil
+h[0] = 0
+h[-1] = -1
+h[1000] = 1000
+h[2.5] = 2.5
+assert(h[1] == 1 and h[2] == nil and h[3] == 3)
+assert(h[0] == 0 and h[-1] == -1 and h[1000] == 1000 and h[2.5] == 2.5)
+
+-- Every entry is visited exactly once by pairs, whichever part it is in.
+local n, keysum = 0, 0
+for k, v in pairs(h) do
+    assert(h[k] == v)
+    n = n + 1
+    keysum = keysum + k
+end
+assert(n == 6 and keysum == 1005.5)
+
+-- Sparse integer keys.
+local s = {}
+for i = 1, 50 do
+    s[i * i] = i
+end
+for i = 1, 50 do
+    assert(s[i * i] == i)
+end
+assert(s[2] == nil)
+
+-- Entries move back and forth between the parts as the table grows.
+local m = {}
+for i = 1, 20 do
+    m["k" .. i] = i
+    m[i * 3] = i
+end
+for i = 1, 60 do
+    m[i] = i
+end
+for i = 1, 60 do
+    assert(m[i] == i)
+end
+for i = 1, 20 do
+    assert(m["k" .. i] == i)
+end
+n = 0
+for k, v in pairs(m) do
+    n = n + 1
+end
+assert(n == 80)
+
+-- Table constructors.
+local c = {10, 20, 30, x = 1, [4] = 40}
+assert(c[1] == 10 and c[3] == 30 and c[4] == 40 and c.x == 1)
+n = 0
+for _, v in ipairs(c) do
+    n = n + 1
+end
+assert(n == 4)
+
+-- Clearing entries while traversing.
+for k in pairs(t) do
+    t[k] = nil
+end
+assert(next(t) == nil)
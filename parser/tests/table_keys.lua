@@ -0,0 +1,68 @@
+local t = {}
+
+-- Tables and functions are keyed by identity.
+local k1, k2 = {}, {}
+t[k1] = 1
+t[k2] = 2
+assert(t[k1] == 1 and t[k2] == 2)
+local f1 = function() end
+local f2 = function() end
+t[f1] = "f1"
+t[f2] = "f2"
+assert(t[f1] == "f1" and t[f2] == "f2")
+assert(t[{}] == nil)
+
+-- Strings are keyed by content.
+local s = "ab"
+t[s .. "c"] = 3
+assert(t["abc"] == 3 and t.abc == 3)
+
+-- Booleans.
+t[true] = "yes"
+t[false] = "no"
+assert(t[true] == "yes" and t[false] == "no")
+
+-- Floats with integral values are the same keys as integers.
+t[1] = "one"
+assert(t[1.0] == "one")
+t[2.0] = "two"
+assert(t[2] == "two" and t[4 / 2] == "two")
+t[1.5] = "one and a half"
+assert(t[3 / 2] == "one and a half" and t[1] == "one")
+t[-0.0] = "zero"
+assert(t[0] == "zero")
+
+-- Large integers only match floats with exactly their value.
+t[9007199254740993] = "odd"
+assert(t[2^53] == nil and t[2^53 + 2] == nil and t[9007199254740993] == "odd")
+t[2^53] = "even"
+assert(t[9007199254740992] == "even" and t[9007199254740993] == "odd")
+assert(9007199254740993 ~= 2^53 and 9007199254740992 == 2^53 and not rawequal(9007199254740993, 2^53))
+assert(math.maxinteger ~= 2.0^63 and math.mininteger == -2.0^63)
+
+-- Reading nil and NaN keys gives nil.
+assert(t[nil] == nil)
+assert(t[0 / 0] == nil)
+
+-- Keys of any type are visited by pairs.
+local u = {[k1] = 1, [true] = 2, [1.5] = 3, x = 4}
+local n, sum = 0, 0
+for k, v in pairs(u) do
+    assert(u[k] == v)
+    n = n + 1
+    sum = sum + v
+end
+assert(n == 4 and sum == 10)
+
+-- Table keys keep their objects alive.
+local holder = {}
+holder[{}] = true
+for i = 1, 2000 do
+    local tmp = {i}
+end
+n = 0
+for k in pairs(holder) do
+    n = n + 1
+    assert(holder[k])
+end
+assert(n == 1)
@@ -0,0 +1,109 @@
+local function list(t)
+    return table.concat(t, ",")
+end
+
+-- insert and remove.
+local t = {}
+table.insert(t, "a")
+table.insert(t, "c")
+table.insert(t, 2, "b")
+table.insert(t, 1, "z")
+assert(list(t) == "z,a,b,c")
+assert(table.remove(t, 1) == "z")
+assert(table.remove(t) == "c")
+assert(list(t) == "a,b")
+table.insert(t, #t + 1, "d")
+assert(list(t) == "a,b,d")
+assert(table.remove({}) == nil)
+assert(table.remove(t, #t + 1) == nil)
+
+-- concat.
+assert(table.concat({}) == "")
+assert(table.concat({1, 2, 3}) == "123")
+assert(table.concat({1, "x", 2.5}, ", ") == "1, x, 2.5")
+assert(table.concat({"a", "b", "c", "d"}, "-", 2, 3) == "b-c")
+assert(table.concat({"a"}, "-", 3, 2) == "")
+
+-- pack and unpack.
+local p = table.pack(1, nil, 3)
+assert(p.n == 3 and p[1] == 1 and p[2] == nil and p[3] == 3)
+assert(table.pack().n == 0)
+local a, b, c = table.unpack({1, 2, 3})
+assert(a == 1 and b == 2 and c == 3)
+a, b = table.unpack({1, 2, 3}, 2)
+assert(a == 2 and b == 3)
+a, b, c = table.unpack({1, 2, 3}, 2, 4)
+assert(a == 2 and b == 3 and c == nil)
+assert(select("#", table.unpack({}, 1, 0)) == 0)
+
+-- move.
+t = {1, 2, 3, 4, 5}
+table.move(t, 1, 3, 3)
+assert(list(t) == "1,2,1,2,3")
+t = {1, 2, 3, 4, 5}
+table.move(t, 2, 5, 1)
+assert(list(t) == "2,3,4,5,5")
+local dest = table.move({1, 2, 3}, 1, 3, 2, {"x"})
+assert(list(dest) == "x,1,2,3")
+
+-- sort.
+t = {5, 2, 8, 1, 9, 3, 7, 4, 6}
+table.sort(t)
+assert(list(t) == "1,2,3,4,5,6,7,8,9")
+table.sort(t, function(x, y) return x > y end)
+assert(list(t) == "9,8,7,6,5,4,3,2,1")
+t = {"pear", "apple", "fig"}
+table.sort(t)
+assert(list(t) == "apple,fig,pear")
+
+local big = {}
+for i = 1, 500 do
+    big[i] = (i * 7919) % 503
+end
+table.sort(big)
+for i = 2, 500 do
+    assert(big[i - 1] <= big[i])
+end
+
+local records = {{k = 3}, {k = 1}, {k = 2}}
+table.sort(records, function(x, y) return x.k < y.k end)
+assert(records[1].k == 1 and records[2].k == 2 and records[3].k == 3)
+
+-- Metamethods.
+local log = {}
+local proxy = setmetatable({}, {
+    __index = function(_, k) return k * 10 end,
+    __len = function() return 3 end
+})
+assert(table.concat(proxy, ",") == "10,20,30")
+a, b, c = table.unpack(proxy)
+assert(a == 10 and b == 20 and c == 30)
+local sink = setmetatable({}, {
+    __newindex = function(t, k, v)
+        log[#log + 1] = k
+        rawset(t, k, v)
+    end
+})
+table.insert(sink, "x")
+assert(log[1] == 1 and sink[1] == "x")
+
+-- Errors.
+local function fails(f, msg)
+    local ok, err = pcall(f)
+    assert(not ok)
+    assert(string.find(err, msg, 1, true), err)
+end
+fails(function() table.insert({}, 5, "x") end, "bad argument #2 to 'insert' (position out of bounds)")
+fails(function() table.insert({}, 1, 2, 3) end, "wrong number of arguments to 'insert'")
+fails(function() table.insert(nil, 1) end, "bad argument #1 to 'insert' (table expected, got nil)")
+fails(function() table.remove({1}, 5) end, "bad argument #2 to 'remove' (position out of bounds)")
+fails(function() table.concat({1, {}, 3}) end, "invalid value (at index 2) in table for 'concat'")
+fails(function() table.sort({3, 1, 2}, 1) end, "bad argument #2 to 'sort' (function expected, got number)")
+fails(function() table.sort({1, 2, {}}) end, "attempt to compare")
+fails(function()
+    local t = {}
+    for i = 1, 200 do
+        t[i] = i % 7
+    end
+    table.sort(t, function(x, y) return true end)
+end, "invalid order function for sorting")
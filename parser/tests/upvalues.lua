@@ -0,0 +1,70 @@
+function make_counter()
+    local n = 0
+    return function()
+        n = n + 1
+        return n
+    end
+end
+
+local c1 = make_counter()
+local c2 = make_counter()
+assert(c1() == 1)
+assert(c1() == 2)
+assert(c2() == 1)
+assert(c1() == 3)
+
+-- Closures created in the same activation share the variable.
+function make_pair()
+    local v = 0
+    local get = function() return v end
+    local set = function(x) v = x end
+    return { get = get, set = set }
+end
+
+local p1 = make_pair()
+local p2 = make_pair()
+p1.set(10)
+assert(p1.get() == 10)
+assert(p2.get() == 0)
+
+-- Upvalues pass through intermediate functions.
+function outer(a)
+    return function(b)
+        return function(c)
+            a = a + 1
+            return a + b + c
+        end
+    end
+end
+
+local f = outer(1)(10)
+assert(f(100) == 112)
+assert(f(100) == 113)
+
+-- A fresh local in each iteration.
+local fns = {}
+local i = 1
+while i <= 3 do
+    local j = i
+    fns[i] = function() return j end
+    i = i + 1
+end
+assert(fns[1]() == 1)
+assert(fns[2]() == 2)
+assert(fns[3]() == 3)
+
+-- Recursion does not clobber the captured variables of other activations.
+function rec(n)
+    local get = function() return n end
+    if n > 0 then
+        rec(n - 1)
+    end
+    assert(get() == n)
+end
+rec(5)
+
+-- Missing arguments are nil.
+function opt(a, b)
+    return b
+end
+assert(opt(1) == nil)
@@ -0,0 +1,56 @@
+function count(...)
+    return select('#', ...)
+end
+
+assert(count() == 0)
+assert(count(nil) == 1)
+assert(count(1, nil, 3) == 3)
+assert(count(nil, nil) == 2)
+
+function pack(...)
+    return { ... }
+end
+
+local t = pack(1, 2, 3)
+assert(t[1] == 1 and t[2] == 2 and t[3] == 3)
+
+function first(a, ...)
+    local b, c = ...
+    return a, b, c
+end
+
+local a, b, c = first(1, 2, 3, 4)
+assert(a == 1 and b == 2 and c == 3)
+a, b, c = first(1)
+assert(a == 1 and b == nil and c == nil)
+
+-- `...` in single-value contexts is truncated.
+function head(...)
+    return (...)
+end
+assert(count(head(5, 6, 7)) == 1)
+assert(head(5, 6, 7) == 5)
+
+function sum(...)
+    local n = select('#', ...)
+    local total = 0
+    local i = 1
+    while i <= n do
+        total = total + select(i, ...)
+        i = i + 1
+    end
+    return total
+end
+assert(sum(1, 2, 3, 4) == 10)
+
+assert(select(2, 'a', 'b', 'c') == 'b')
+assert(count(select(2, 'a', 'b', 'c')) == 2)
+assert(select(-1, 'a', 'b', 'c') == 'c')
+assert(count(select(5, 'a', 'b', 'c')) == 0)
+
+-- Varargs are captured like any other argument.
+function forward(...)
+    local f = function(...) return count(...) end
+    return f(...)
+end
+assert(forward(1, 2) == 2)
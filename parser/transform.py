@@ -3,6 +3,28 @@ import sys
 
 inAst = json.loads(sys.stdin.read())
 
+# The original source is optional. When given, `pos` offsets from
+# lua-parser are turned into line/column positions on every node.
+lineStarts = None
+if len(sys.argv) > 1:
+    with open(sys.argv[1], "rb") as f:
+        source = f.read()
+    lineStarts = [0]
+    for i in range(0, len(source)):
+        if source[i:i + 1] == b"\n":
+            lineStarts.append(i + 1)
+
+def offsetToPos(offset):
+    # lua-parser offsets start from 1.
+    offset -= 1
+    line = 0
+    while line + 1 < len(lineStarts) and lineStarts[line + 1] <= offset:
+        line += 1
+    return {
+        "line": line + 1,
+        "column": offset - lineStarts[line] + 1
+    }
+
 def reprsInt(s):
     try:
         int(s)
@@ -15,6 +37,7 @@ class Node:
         self.tag = None
         self.children = []
         self.value = None
+        self.pos = None
 
     @staticmethod
     def fromValue(v):
@@ -31,6 +54,8 @@ class Node:
                 result.tag = inNode["tag"]
             except KeyError:
                 return None
+            if "pos" in inNode:
+                result.pos = inNode["pos"]
             for k in inNode:
                 if reprsInt(k):
                     v = Node.fromInput(inNode[k])
@@ -47,6 +72,20 @@ class Node:
         return result
 
     def toDict(self):
+        v = self._toDict()
+        if lineStarts == None or self.pos == None or self.tag in Node._noPosTags:
+            return v
+        if type(v) == str:
+            v = {
+                v: None
+            }
+        if type(v) == dict and "pos" not in v:
+            v["pos"] = offsetToPos(self.pos)
+        return v
+
+    _noPosTags = ["Block", "NameList", "ExpList", "VarList"]
+
+    def _toDict(self):
         if self.value != None:
             return self.value
 
@@ -96,6 +135,17 @@ class Node:
                     argList
                 ]
             }
+        elif self.tag == "Invoke":
+            # (expr, String, expr*)
+            assert(len(self.children) >= 2)
+            argList = list(map(lambda x: x.toDict(), self.children[2:]))
+            return {
+                "Invoke": [
+                    self.children[0].toDict(),
+                    self.children[1].children[0].value,
+                    argList
+                ]
+            }
         elif self.tag == "Fornum":
             # (ident, expr, expr, block)
             if len(self.children) == 4:
@@ -138,10 +188,16 @@ class Node:
                     elseBranch
                 ]
             }
-        elif self.tag == "Break":
-            return "Break"
-        elif self.tag == "Paren": # Is this correct?
+        elif self.tag == "Break" or self.tag == "Nil" or self.tag == "Dots":
+            return self.tag
+        elif self.tag == "Paren":
+            # Parentheses only matter around expressions that may
+            # produce multiple values, where they truncate to one.
             assert(len(self.children) == 1)
+            if self.children[0].tag in ["Call", "Invoke", "Dots"]:
+                return {
+                    "Paren": self.children[0].toDict()
+                }
             return self.children[0].toDict()
 
         v = list(map(lambda x: x.toDict(), self.children))
@@ -170,6 +226,7 @@ class Node:
             "ge": "Ge",
             "not": "Not",
             "unm": "Unm",
+            "len": "Len",
             "concat": "Concat",
             "and": "And",
             "or": "Or"
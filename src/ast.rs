@@ -1,8 +1,97 @@
 use std::collections::HashSet;
+use std::fmt;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{DeserializeOwned, Error as DeError};
+use serde::ser::Error as SerError;
 use serde_json;
+use serde_json::Value;
+
+/// A line/column position in the source, both starting from 1.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pos {
+    pub line: usize,
+    pub column: usize
+}
+
+impl Pos {
+    pub fn new(line: usize, column: usize) -> Pos {
+        Pos {
+            line,
+            column
+        }
+    }
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// An AST node together with where it starts in the source.
+///
+/// In JSON, a node is written just like its `kind`, with an optional
+/// `"pos": { "line": ..., "column": ... }` entry next to the variant tag.
+/// Unit variants carrying a position are written as `{ "Break": null, "pos": ... }`.
+#[derive(Debug, Clone)]
+pub struct Node<T> {
+    pub kind: T,
+    pub pos: Option<Pos>
+}
+
+pub type Stmt = Node<StmtKind>;
+pub type Expr = Node<ExprKind>;
+pub type Lhs = Node<LhsKind>;
+
+impl<T> Node<T> {
+    pub fn new(kind: T, pos: Option<Pos>) -> Node<T> {
+        Node {
+            kind,
+            pos
+        }
+    }
+}
+
+impl<T> From<T> for Node<T> {
+    fn from(kind: T) -> Node<T> {
+        Node::new(kind, None)
+    }
+}
+
+impl<T: Serialize> Serialize for Node<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut v = serde_json::to_value(&self.kind).map_err(S::Error::custom)?;
+        if let Some(pos) = self.pos {
+            if let Value::String(tag) = v {
+                let mut m = serde_json::Map::new();
+                m.insert(tag, Value::Null);
+                v = Value::Object(m);
+            }
+            if let Value::Object(ref mut m) = v {
+                m.insert("pos".into(), serde_json::to_value(pos).map_err(S::Error::custom)?);
+            }
+        }
+        v.serialize(serializer)
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Node<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Node<T>, D::Error> {
+        let mut v = Value::deserialize(deserializer)?;
+        let pos = match v {
+            Value::Object(ref mut m) => match m.remove("pos") {
+                Some(pos) => Some(serde_json::from_value(pos).map_err(D::Error::custom)?),
+                None => None
+            },
+            _ => None
+        };
+        let kind = serde_json::from_value(v).map_err(D::Error::custom)?;
+        Ok(Node::new(kind, pos))
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum Stmt {
+pub enum StmtKind {
     Do(Vec<Stmt>),
     Set(Vec<Lhs>, Vec<Expr>),
     While(Expr, Block),
@@ -11,12 +100,24 @@ pub enum Stmt {
     Fornum(Lhs, Expr, Expr, Option<Expr>, Block),
     Forin(Vec<Lhs>, Vec<Expr>, Block),
     Local(Vec<Lhs>, Vec<Expr>),
+    // A local declaration where some names have an attribute.
+    LocalAttrib(Vec<Lhs>, Vec<Option<Attrib>>, Vec<Expr>),
     Localrec(Lhs, Expr),
     Goto(String),
     Label(String),
     Return(Vec<Expr>),
     Break,
-    Call(Expr, Vec<Expr>)
+    Call(Expr, Vec<Expr>),
+    // `obj:method(args)`
+    Invoke(Expr, String, Vec<Expr>)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Attrib {
+    Const,
+    // A to-be-closed variable, whose value's `__close` metamethod is
+    // called when it goes out of scope.
+    Close
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -25,11 +126,13 @@ pub enum Block {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum Expr {
+pub enum ExprKind {
     Nil,
     Dots,
     Boolean(bool),
+    Integer(i64),
     Number(f64),
+    // One char per byte of the literal.
     String(String),
     Function(Vec<Lhs>, Block),
     Table(Vec<Expr>),
@@ -49,18 +152,26 @@ pub enum Expr {
     Ge(Box<Expr>, Box<Expr>),
     Not(Box<Expr>),
     Unm(Box<Expr>),
+    Len(Box<Expr>),
     And(Box<Expr>, Box<Expr>),
     Or(Box<Expr>, Box<Expr>),
     Call(Box<Expr>, Vec<Expr>),
+    // `obj:method(args)`
+    Invoke(Box<Expr>, String, Vec<Expr>),
     Pair(Box<Expr>, Box<Expr>),
     Id(String),
-    Index(Box<Expr>, Box<Expr>)
+    Index(Box<Expr>, Box<Expr>),
+    // Only kept around expressions that may produce multiple values,
+    // where it truncates them to one.
+    Paren(Box<Expr>)
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum Lhs {
+pub enum LhsKind {
     Id(String),
-    Index(Expr, Expr)
+    Index(Expr, Expr),
+    // The `...` at the end of a vararg function's parameter list.
+    Dots
 }
 
 macro_rules! concat_vec {
@@ -91,8 +202,8 @@ impl Block {
 
 impl Lhs {
     pub fn id(&self) -> Option<&str> {
-        match *self {
-            Lhs::Id(ref s) => Some(s.as_str()),
+        match self.kind {
+            LhsKind::Id(ref s) => Some(s.as_str()),
             _ => None
         }
     }
@@ -123,7 +234,7 @@ impl GetEscapeInfo for Block {
             result.extend(
                 new_results
             );
-            if let Stmt::Local(ref lhs, _) = *stmt {
+            if let StmtKind::Local(ref lhs, _) | StmtKind::LocalAttrib(ref lhs, _, _) = stmt.kind {
                 for v in lhs {
                     if let Some(k) = v.id() {
                         locals.insert(k.to_string());
@@ -142,13 +253,14 @@ impl GetEscapeInfo for Block {
 
 impl GetEscapeInfo for Lhs {
     fn get_used_vars(&self) -> Vec<String> {
-        match *self {
-            Lhs::Id(ref v) => vec! [ v.clone() ],
-            Lhs::Index(ref left, ref right) => {
+        match self.kind {
+            LhsKind::Id(ref v) => vec! [ v.clone() ],
+            LhsKind::Index(ref left, ref right) => {
                 let mut ret = left.get_used_vars();
                 ret.extend(right.get_used_vars());
                 ret
-            }
+            },
+            LhsKind::Dots => Vec::new()
         }
     }
 
@@ -173,13 +285,13 @@ impl GetEscapeInfo for Vec<Lhs> {
 
 impl GetEscapeInfo for Stmt {
     fn get_used_vars(&self) -> Vec<String> {
-        match *self {
-            Stmt::Do(ref v) => v.get_used_vars(),
-            Stmt::Set(ref l, ref r) => pair_get_used_vars!(l, r),
-            Stmt::While(ref l, ref r) => pair_get_used_vars!(l, r),
-            Stmt::Repeat(ref l, ref r) => pair_get_used_vars!(l, r),
-            Stmt::If(ref l, ref r) => pair_get_used_vars!(l, r),
-            Stmt::Fornum(ref a, ref b, ref c, ref d, ref e) => {
+        match self.kind {
+            StmtKind::Do(ref v) => v.get_used_vars(),
+            StmtKind::Set(ref l, ref r) => pair_get_used_vars!(l, r),
+            StmtKind::While(ref l, ref r) => pair_get_used_vars!(l, r),
+            StmtKind::Repeat(ref l, ref r) => pair_get_used_vars!(l, r),
+            StmtKind::If(ref l, ref r) => pair_get_used_vars!(l, r),
+            StmtKind::Fornum(ref a, ref b, ref c, ref d, ref e) => {
                 concat_vec!(
                     pair_get_used_vars!(a, b),
                     concat_vec!(
@@ -188,28 +300,30 @@ impl GetEscapeInfo for Stmt {
                     )
                 )
             },
-            Stmt::Forin(ref a, ref b, ref c) => {
+            StmtKind::Forin(ref a, ref b, ref c) => {
                 concat_vec!(
                     pair_get_used_vars!(a, b),
                     c.get_used_vars()
                 )
             },
-            Stmt::Local(ref l, ref r) => r.get_used_vars(),
-            Stmt::Localrec(ref l, ref r) => pair_get_used_vars!(l, r),
-            Stmt::Goto(_) | Stmt::Label(_) | Stmt::Break => Vec::new(),
-            Stmt::Return(ref v) => v.get_used_vars(),
-            Stmt::Call(ref l, ref r) => pair_get_used_vars!(l, r)
+            StmtKind::Local(_, ref r) => r.get_used_vars(),
+            StmtKind::LocalAttrib(_, _, ref r) => r.get_used_vars(),
+            StmtKind::Localrec(ref l, ref r) => pair_get_used_vars!(l, r),
+            StmtKind::Goto(_) | StmtKind::Label(_) | StmtKind::Break => Vec::new(),
+            StmtKind::Return(ref v) => v.get_used_vars(),
+            StmtKind::Call(ref l, ref r) => pair_get_used_vars!(l, r),
+            StmtKind::Invoke(ref l, _, ref r) => pair_get_used_vars!(l, r)
         }
     }
 
     fn get_closure_escaped_vars(&self) -> Vec<String> {
-        match *self {
-            Stmt::Do(ref v) => v.get_closure_escaped_vars(),
-            Stmt::Set(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Stmt::While(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Stmt::Repeat(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Stmt::If(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Stmt::Fornum(ref a, ref b, ref c, ref d, ref e) => {
+        match self.kind {
+            StmtKind::Do(ref v) => v.get_closure_escaped_vars(),
+            StmtKind::Set(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            StmtKind::While(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            StmtKind::Repeat(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            StmtKind::If(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            StmtKind::Fornum(ref a, ref b, ref c, ref d, ref e) => {
                 concat_vec!(
                     pair_get_closure_escaped_vars!(a, b),
                     concat_vec!(
@@ -218,17 +332,19 @@ impl GetEscapeInfo for Stmt {
                     )
                 )
             },
-            Stmt::Forin(ref a, ref b, ref c) => {
+            StmtKind::Forin(ref a, ref b, ref c) => {
                 concat_vec!(
                     pair_get_closure_escaped_vars!(a, b),
                     c.get_closure_escaped_vars()
                 )
             },
-            Stmt::Local(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Stmt::Localrec(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Stmt::Goto(_) | Stmt::Label(_) | Stmt::Break => Vec::new(),
-            Stmt::Return(ref v) => v.get_closure_escaped_vars(),
-            Stmt::Call(ref l, ref r) => pair_get_closure_escaped_vars!(l, r)
+            StmtKind::Local(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            StmtKind::LocalAttrib(ref l, _, ref r) => pair_get_closure_escaped_vars!(l, r),
+            StmtKind::Localrec(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            StmtKind::Goto(_) | StmtKind::Label(_) | StmtKind::Break => Vec::new(),
+            StmtKind::Return(ref v) => v.get_closure_escaped_vars(),
+            StmtKind::Call(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            StmtKind::Invoke(ref l, _, ref r) => pair_get_closure_escaped_vars!(l, r)
         }
     }
 }
@@ -253,70 +369,77 @@ impl GetEscapeInfo for Vec<Stmt> {
 
 impl GetEscapeInfo for Expr {
     fn get_used_vars(&self) -> Vec<String> {
-        match *self {
-            Expr::Nil | Expr::Dots | Expr::Boolean(_) | Expr::Number(_) | Expr::String(_) => Vec::new(),
-            Expr::Function(ref l, ref r) => {
-                let mut args: HashSet<String> = l.iter()
-                    .map(|v| v.id().unwrap().to_string())
+        match self.kind {
+            ExprKind::Nil | ExprKind::Dots | ExprKind::Boolean(_) | ExprKind::Integer(_) | ExprKind::Number(_) | ExprKind::String(_) => Vec::new(),
+            ExprKind::Function(ref l, ref r) => {
+                let args: HashSet<String> = l.iter()
+                    .filter_map(|v| v.id())
+                    .map(|v| v.to_string())
                     .collect();
-                let mut result: Vec<String> = r.get_used_vars()
+                let result: Vec<String> = r.get_used_vars()
                     .into_iter()
                     .filter(|v| !args.contains(v)).collect();
                 result
             },
-            Expr::Table(ref t) => t.get_used_vars(),
-            Expr::Add(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Sub(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Mul(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Div(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Idiv(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Mod(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Pow(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Concat(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Eq(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Ne(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Lt(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Gt(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Le(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Ge(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Not(ref v) => v.get_used_vars(),
-            Expr::Unm(ref v) => v.get_used_vars(),
-            Expr::And(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Or(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Call(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Pair(ref l, ref r) => pair_get_used_vars!(l, r),
-            Expr::Id(ref v) => vec! [ v.clone() ],
-            Expr::Index(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Table(ref t) => t.get_used_vars(),
+            ExprKind::Add(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Sub(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Mul(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Div(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Idiv(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Mod(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Pow(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Concat(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Eq(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Ne(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Lt(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Gt(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Le(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Ge(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Not(ref v) => v.get_used_vars(),
+            ExprKind::Unm(ref v) => v.get_used_vars(),
+            ExprKind::Len(ref v) => v.get_used_vars(),
+            ExprKind::And(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Or(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Call(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Invoke(ref l, _, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Pair(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Id(ref v) => vec! [ v.clone() ],
+            ExprKind::Index(ref l, ref r) => pair_get_used_vars!(l, r),
+            ExprKind::Paren(ref v) => v.get_used_vars(),
         }
     }
 
     fn get_closure_escaped_vars(&self) -> Vec<String> {
-        match *self {
-            Expr::Nil | Expr::Dots | Expr::Boolean(_) | Expr::Number(_) | Expr::String(_) => Vec::new(),
-            Expr::Function(_, _) => self.get_used_vars(),
-            Expr::Table(ref t) => t.get_closure_escaped_vars(),
-            Expr::Add(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Sub(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Mul(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Div(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Idiv(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Mod(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Pow(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Concat(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Eq(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Ne(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Lt(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Gt(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Le(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Ge(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Not(ref v) => v.get_closure_escaped_vars(),
-            Expr::Unm(ref v) => v.get_closure_escaped_vars(),
-            Expr::And(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Or(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Call(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Pair(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
-            Expr::Id(ref v) => vec! [  ],
-            Expr::Index(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+        match self.kind {
+            ExprKind::Nil | ExprKind::Dots | ExprKind::Boolean(_) | ExprKind::Integer(_) | ExprKind::Number(_) | ExprKind::String(_) => Vec::new(),
+            ExprKind::Function(_, _) => self.get_used_vars(),
+            ExprKind::Table(ref t) => t.get_closure_escaped_vars(),
+            ExprKind::Add(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Sub(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Mul(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Div(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Idiv(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Mod(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Pow(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Concat(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Eq(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Ne(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Lt(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Gt(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Le(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Ge(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Not(ref v) => v.get_closure_escaped_vars(),
+            ExprKind::Unm(ref v) => v.get_closure_escaped_vars(),
+            ExprKind::Len(ref v) => v.get_closure_escaped_vars(),
+            ExprKind::And(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Or(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Call(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Invoke(ref l, _, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Pair(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Id(_) => vec! [  ],
+            ExprKind::Index(ref l, ref r) => pair_get_closure_escaped_vars!(l, r),
+            ExprKind::Paren(ref v) => v.get_closure_escaped_vars(),
         }
     }
 }
@@ -339,6 +462,7 @@ impl GetEscapeInfo for Vec<Expr> {
     }
 }
 
+#[allow(clippy::needless_borrowed_reference)]
 impl<L, R> GetEscapeInfo for Vec<(L, R)> where L: GetEscapeInfo, R: GetEscapeInfo {
     fn get_used_vars(&self) -> Vec<String> {
         let mut ret: Vec<String> = Vec::new();
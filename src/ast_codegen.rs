@@ -1,27 +1,64 @@
 use std::error::Error;
 use std::fmt;
 use hexagon::opcode::{OpCode, SelectType};
-use ast::{Block, Expr, Stmt, Lhs, GetEscapeInfo};
-use codegen::{ModuleBuilder, FunctionBuilder, BasicBlockBuilder, LoopControlInfo, VarLocation};
+use ast::{Attrib, Block, Expr, ExprKind, Stmt, StmtKind, Lhs, LhsKind, Pos};
+use codegen::{FunctionBuilder, LoopControlInfo, VarLocation};
 
 #[derive(Debug)]
 pub struct CodegenError {
-    desc: String
+    desc: String,
+    file: Option<String>,
+    pos: Option<Pos>
 }
 
 impl<'a> From<&'a str> for CodegenError {
     fn from(other: &'a str) -> CodegenError {
         CodegenError {
-            desc: other.to_string()
+            desc: other.to_string(),
+            file: None,
+            pos: None
+        }
+    }
+}
+
+impl From<String> for CodegenError {
+    fn from(other: String) -> CodegenError {
+        CodegenError {
+            desc: other,
+            file: None,
+            pos: None
         }
     }
 }
 
 impl Default for CodegenError {
     fn default() -> Self {
-        CodegenError {
-            desc: "Error while generating code".into()
+        CodegenError::from("Error while generating code")
+    }
+}
+
+impl CodegenError {
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn pos(&self) -> Option<Pos> {
+        self.pos
+    }
+
+    /// Attaches a position unless a more precise one is already known.
+    pub fn with_pos(mut self, pos: Option<Pos>) -> CodegenError {
+        if self.pos.is_none() {
+            self.pos = pos;
+        }
+        self
+    }
+
+    pub fn with_file(mut self, file: Option<&str>) -> CodegenError {
+        if self.file.is_none() {
+            self.file = file.map(|v| v.to_string());
         }
+        self
     }
 }
 
@@ -33,36 +70,179 @@ impl Error for CodegenError {
 
 impl fmt::Display for CodegenError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "CodegenError: {}", self.desc)
+        match (self.file.as_ref(), self.pos) {
+            (Some(file), Some(pos)) => write!(f, "CodegenError at {}:{}: {}", file, pos, self.desc),
+            (Some(file), None) => write!(f, "CodegenError in {}: {}", file, self.desc),
+            (None, Some(pos)) => write!(f, "CodegenError at {}: {}", pos, self.desc),
+            (None, None) => write!(f, "CodegenError: {}", self.desc)
+        }
     }
 }
 
 impl Lhs {
     fn build_set(&self, fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
-        match *self {
-            Lhs::Id(ref id) => {
-                let loc = fb.get_var_location(id);
+        match self.kind {
+            LhsKind::Id(ref id) => {
+                if fb.is_const_var(id) {
+                    return Err(CodegenError::from(format!(
+                        "attempt to assign to const variable '{}'",
+                        id
+                    )).with_pos(self.pos));
+                }
+                let loc = fb.get_var_location(id)?;
                 loc.build_set(fb)?;
             },
-            Lhs::Index(ref target, ref index) => {
+            LhsKind::Index(ref target, ref index) => {
                 index.restricted_generate_code(fb)?;
                 target.restricted_generate_code(fb)?;
                 fb.write_index_set()?;
-            }
+            },
+            LhsKind::Dots => return Err(CodegenError::from("Cannot assign to '...'").with_pos(self.pos))
         }
         Ok(())
     }
 
-    fn build_new_local(&self, fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
-        match *self {
-            Lhs::Id(ref id) => {
-                let loc = fb.create_local(id);
-                loc.build_set(fb)?;
+    fn create_local(&self, fb: &mut FunctionBuilder) -> Result<VarLocation, CodegenError> {
+        match self.kind {
+            LhsKind::Id(ref id) => Ok(fb.create_local(id)),
+            _ => Err(CodegenError::from("create_local: Unexpected lvalue").with_pos(self.pos))
+        }
+    }
+
+    /// Evaluates the table and key of an indexing target into temporaries,
+    /// so that they are not affected by the assignments that follow.
+    fn build_set_target(&self, fb: &mut FunctionBuilder) -> Result<Option<(VarLocation, VarLocation)>, CodegenError> {
+        match self.kind {
+            LhsKind::Id(_) => Ok(None),
+            LhsKind::Index(ref target, ref index) => {
+                let target_loc = fb.get_anonymous_local();
+                let index_loc = fb.get_anonymous_local();
+                target.restricted_generate_code(fb)?;
+                target_loc.build_set(fb)?;
+                index.restricted_generate_code(fb)?;
+                index_loc.build_set(fb)?;
+                Ok(Some((target_loc, index_loc)))
             },
-            _ => return Err("build_new_local: Unexpected lvalue".into())
+            LhsKind::Dots => Err(CodegenError::from("Cannot assign to '...'").with_pos(self.pos))
         }
-        Ok(())
     }
+
+    fn build_set_to_target(&self, fb: &mut FunctionBuilder, target: Option<(VarLocation, VarLocation)>) -> Result<(), CodegenError> {
+        match target {
+            Some((target_loc, index_loc)) => {
+                index_loc.build_get(fb)?;
+                target_loc.build_get(fb)?;
+                fb.write_index_set()
+            },
+            None => self.build_set(fb)
+        }
+    }
+}
+
+impl Expr {
+    /// Whether the expression may produce more than one value.
+    pub fn is_multi_value(&self) -> bool {
+        matches!(self.kind, ExprKind::Call(_, _) | ExprKind::Invoke(_, _, _) | ExprKind::Dots)
+    }
+
+    /// Generates code leaving all values of a multi-value expression
+    /// on the stack as a single multi-value object.
+    pub fn multi_generate_code(&self, fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
+        match self.kind {
+            ExprKind::Call(ref target, ref args) => build_call(target, args, fb)
+                .map_err(|e| e.with_pos(self.pos)),
+            ExprKind::Invoke(ref target, ref method, ref args) => build_invoke(target, method, args, fb)
+                .map_err(|e| e.with_pos(self.pos)),
+            ExprKind::Dots => fb.write_varargs_load()
+                .map_err(|e| e.with_pos(self.pos)),
+            _ => self.restricted_generate_code(fb)
+        }
+    }
+}
+
+/// Generates a call leaving its result on the stack as is, which may be
+/// a multi-value. A trailing multi-value argument is expanded.
+fn build_call(target: &Expr, args: &[Expr], fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
+    build_call_with_self(|fb| target.restricted_generate_code(fb), None, args, fb)
+}
+
+/// Generates a method call like `build_call`. The receiver is evaluated
+/// only once, and passed as the first argument.
+fn build_invoke(target: &Expr, method: &str, args: &[Expr], fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
+    fb.scoped(|fb| -> Result<(), CodegenError> {
+        let self_loc = fb.get_anonymous_local();
+        target.restricted_generate_code(fb)?;
+        self_loc.build_set(fb)?;
+
+        build_call_with_self(|fb| {
+            fb.get_current_bb().opcodes.push(OpCode::LoadString(method.to_string()));
+            self_loc.build_get(fb)?;
+            fb.write_index_get()
+        }, Some(&self_loc), args, fb)
+    })
+}
+
+fn build_call_with_self<F: FnMut(&mut FunctionBuilder) -> Result<(), CodegenError>>(
+    mut build_target: F,
+    self_loc: Option<&VarLocation>,
+    args: &[Expr],
+    fb: &mut FunctionBuilder
+) -> Result<(), CodegenError> {
+    let n_self = if self_loc.is_some() { 1 } else { 0 };
+    match args.last() {
+        Some(last) if last.is_multi_value() => {
+            let n_fixed = args.len() - 1;
+            build_target(fb)?;
+            if let Some(loc) = self_loc {
+                loc.build_get(fb)?;
+            }
+            for arg in &args[..n_fixed] {
+                arg.restricted_generate_code(fb)?;
+            }
+            last.multi_generate_code(fb)?;
+            fb.write_apply(n_self + n_fixed)?;
+        },
+        _ => {
+            let n_args = n_self + args.len();
+            if let Some(loc) = self_loc {
+                loc.build_get(fb)?;
+            }
+            for arg in args {
+                arg.restricted_generate_code(fb)?;
+            }
+            if n_args > 0 {
+                fb.get_current_bb().opcodes.push(OpCode::RotateReverse(n_args));
+            }
+            // The target is also passed as `this`, for `__call`.
+            build_target(fb)?;
+            fb.get_current_bb().opcodes.extend(vec! [
+                OpCode::Dup,
+                OpCode::Call(n_args)
+            ]);
+        }
+    }
+    Ok(())
+}
+
+/// Pushes exactly `n` values computed from `exprs`, following Lua's
+/// adjustment rules: extra values are evaluated and dropped, missing
+/// ones are nil, and a trailing call expands to all of its results.
+fn build_adjusted_values(exprs: &[Expr], n: usize, fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
+    for (i, expr) in exprs.iter().enumerate() {
+        if i + 1 == exprs.len() && i < n && expr.is_multi_value() {
+            expr.multi_generate_code(fb)?;
+            fb.write_multi_value_expand(n - i)?;
+            return Ok(());
+        }
+        expr.restricted_generate_code(fb)?;
+        if i >= n {
+            fb.get_current_bb().opcodes.push(OpCode::Pop);
+        }
+    }
+    for _ in exprs.len()..n {
+        fb.get_current_bb().opcodes.push(OpCode::LoadNull);
+    }
+    Ok(())
 }
 
 pub trait RestrictedGenerateCode {
@@ -73,37 +253,72 @@ pub trait UnrestrictedGenerateCode {
     fn unrestricted_generate_code(&self, fb: &mut FunctionBuilder) -> Result<(), CodegenError>;
 }
 
-impl UnrestrictedGenerateCode for Block {
-    fn unrestricted_generate_code(&self, fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
-        for stmt in self.statements() {
-            stmt.unrestricted_generate_code(fb)?;
+/// Generates a binary operator, evaluating the left operand first.
+fn build_binary_op(left: &Expr, right: &Expr, op: &str, fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
+    left.restricted_generate_code(fb)?;
+    right.restricted_generate_code(fb)?;
+    fb.write_binary_op(op)
+}
+
+/// Generates the statements of a block.
+fn build_statements(stmts: &[Stmt], fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
+    let n_tbc = fb.get_tbc_count();
+    for (i, stmt) in stmts.iter().enumerate() {
+        match stmt.kind {
+            StmtKind::Label(ref name) => {
+                // Only labels may follow a label at the end of a block.
+                let at_end = stmts[i + 1..].iter().all(|v| matches!(v.kind, StmtKind::Label(_)));
+                fb.write_label(name, at_end).map_err(|e| e.with_pos(stmt.pos))?;
+            },
+            _ => stmt.unrestricted_generate_code(fb)?
         }
+    }
+    fb.end_tbc_block(n_tbc)
+}
 
-        Ok(())
+impl UnrestrictedGenerateCode for Block {
+    fn unrestricted_generate_code(&self, fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
+        build_statements(self.statements(), fb)
     }
 }
 
 impl UnrestrictedGenerateCode for Stmt {
+    fn unrestricted_generate_code(&self, fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
+        match self.kind {
+            // Errors for gotos are reported when their labels are resolved.
+            StmtKind::Goto(ref name) => fb.write_goto(name, self.pos),
+            StmtKind::Break => fb.write_break(self.pos),
+            _ => self.kind.unrestricted_generate_code(fb).map_err(|e| e.with_pos(self.pos))
+        }
+    }
+}
+
+impl UnrestrictedGenerateCode for StmtKind {
+    #[allow(clippy::needless_borrowed_reference)]
     fn unrestricted_generate_code(&self, fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
         match *self {
-            Stmt::Do(ref stmts) => {
-                fb.scoped(|fb| -> Result<(), CodegenError> {
-                    for stmt in stmts {
-                        stmt.unrestricted_generate_code(fb)?;
-                    }
-                    Ok(())
-                })?;
+            StmtKind::Do(ref stmts) => {
+                fb.scoped(|fb| build_statements(stmts, fb))?;
             },
-            Stmt::Set(ref lhs, ref exprs) => {
-                if lhs.len() != exprs.len() {
-                    return Err("Set: lhs & exprs length mismatch".into());
-                }
-                for i in 0..lhs.len() {
-                    exprs[i].restricted_generate_code(fb)?;
-                    lhs[i].build_set(fb)?;
+            StmtKind::Set(ref lhs, ref exprs) => {
+                if lhs.len() == 1 && exprs.len() == 1 && !exprs[0].is_multi_value() {
+                    exprs[0].restricted_generate_code(fb)?;
+                    lhs[0].build_set(fb)?;
+                } else {
+                    fb.scoped(|fb| -> Result<(), CodegenError> {
+                        let mut targets = Vec::with_capacity(lhs.len());
+                        for v in lhs {
+                            targets.push(v.build_set_target(fb)?);
+                        }
+                        build_adjusted_values(exprs, lhs.len(), fb)?;
+                        for (v, target) in lhs.iter().zip(targets).rev() {
+                            v.build_set_to_target(fb, target)?;
+                        }
+                        Ok(())
+                    })?;
                 }
             },
-            Stmt::While(ref expr, ref blk) => {
+            StmtKind::While(ref expr, ref blk) => {
                 fb.scoped(|fb| -> Result<(), CodegenError> {
                     let expr_check_bb_id = fb.current_basic_block + 1;
                     fb.get_current_bb().opcodes.push(OpCode::Branch(expr_check_bb_id));
@@ -135,7 +350,201 @@ impl UnrestrictedGenerateCode for Stmt {
                     Ok(())
                 })?;
             },
-            Stmt::Repeat(ref blk, ref expr) => {
+            StmtKind::Fornum(ref var, ref start, ref limit, ref step, ref blk) => {
+                fb.scoped(|fb| -> Result<(), CodegenError> {
+                    let var_loc = fb.get_anonymous_local();
+                    // Holds the remaining iteration count in integer loops.
+                    let limit_loc = fb.get_anonymous_local();
+                    let step_loc = fb.get_anonymous_local();
+                    let is_int_loc = fb.get_anonymous_local();
+
+                    start.restricted_generate_code(fb)?;
+                    limit.restricted_generate_code(fb)?;
+                    match *step {
+                        Some(ref step) => step.restricted_generate_code(fb)?,
+                        None => fb.get_current_bb().opcodes.push(OpCode::LoadInt(1))
+                    }
+                    fb.write_fornum_prep()?;
+                    is_int_loc.build_set(fb)?;
+                    step_loc.build_set(fb)?;
+                    limit_loc.build_set(fb)?;
+                    var_loc.build_set(fb)?;
+
+                    // Integer loops always run the first iteration.
+                    let float_check_bb_id = fb.current_basic_block + 1;
+                    let check_positive_bb_id = fb.current_basic_block + 2;
+                    let check_negative_bb_id = fb.current_basic_block + 3;
+                    let continue_point_bb_id = fb.current_basic_block + 4;
+                    let int_check_bb_id = fb.current_basic_block + 5;
+                    let int_step_bb_id = fb.current_basic_block + 6;
+                    let float_step_bb_id = fb.current_basic_block + 7;
+                    let break_point_bb_id = fb.current_basic_block + 8;
+                    let body_begin_bb_id = fb.current_basic_block + 9;
+                    is_int_loc.build_get(fb)?;
+                    fb.get_current_bb().opcodes.push(OpCode::ConditionalBranch(body_begin_bb_id, float_check_bb_id));
+
+                    // Separate checks for positive and negative steps.
+                    fb.move_forward();
+                    step_loc.build_get(fb)?;
+                    fb.get_current_bb().opcodes.extend(vec! [
+                        OpCode::LoadFloat(0.0),
+                        OpCode::Rotate2,
+                        OpCode::TestGt,
+                        OpCode::ConditionalBranch(check_positive_bb_id, check_negative_bb_id)
+                    ]);
+
+                    fb.move_forward();
+                    var_loc.build_get(fb)?;
+                    limit_loc.build_get(fb)?;
+                    fb.get_current_bb().opcodes.push(OpCode::Rotate2);
+                    fb.get_current_bb().opcodes.push(OpCode::TestLe);
+
+                    fb.move_forward();
+                    var_loc.build_get(fb)?;
+                    limit_loc.build_get(fb)?;
+                    fb.get_current_bb().opcodes.push(OpCode::Rotate2);
+                    fb.get_current_bb().opcodes.push(OpCode::TestGe);
+
+                    fb.move_forward();
+                    is_int_loc.build_get(fb)?;
+                    fb.get_current_bb().opcodes.push(OpCode::ConditionalBranch(int_check_bb_id, float_step_bb_id));
+
+                    // Integer loops count down the iterations, so the
+                    // control variable cannot overflow.
+                    fb.move_forward();
+                    limit_loc.build_get(fb)?;
+                    fb.get_current_bb().opcodes.extend(vec! [
+                        OpCode::LoadInt(0),
+                        OpCode::TestEq
+                    ]);
+
+                    fb.move_forward();
+                    limit_loc.build_get(fb)?;
+                    fb.get_current_bb().opcodes.extend(vec! [
+                        OpCode::LoadInt(1),
+                        OpCode::Rotate2,
+                        OpCode::IntSub
+                    ]);
+                    limit_loc.build_set(fb)?;
+                    var_loc.build_get(fb)?;
+                    step_loc.build_get(fb)?;
+                    fb.get_current_bb().opcodes.push(OpCode::IntAdd);
+                    var_loc.build_set(fb)?;
+                    fb.get_current_bb().opcodes.push(OpCode::Branch(body_begin_bb_id));
+
+                    fb.move_forward();
+                    var_loc.build_get(fb)?;
+                    step_loc.build_get(fb)?;
+                    fb.get_current_bb().opcodes.push(OpCode::Rotate2);
+                    fb.get_current_bb().opcodes.push(OpCode::Add);
+                    var_loc.build_set(fb)?;
+                    fb.get_current_bb().opcodes.push(OpCode::Branch(float_check_bb_id));
+
+                    fb.move_forward();
+                    assert_eq!(fb.current_basic_block, break_point_bb_id);
+
+                    fb.move_forward();
+                    assert_eq!(fb.current_basic_block, body_begin_bb_id);
+
+                    fb.with_lci(LoopControlInfo {
+                        break_point: break_point_bb_id,
+                        continue_point: continue_point_bb_id
+                    }, |fb| fb.scoped(|fb| -> Result<(), CodegenError> {
+                        // A fresh variable for each iteration.
+                        let loc = var.create_local(fb)?;
+                        var_loc.build_get(fb)?;
+                        loc.build_init(fb)?;
+                        blk.unrestricted_generate_code(fb)
+                    }))?;
+
+                    fb.get_current_bb().opcodes.push(OpCode::Branch(continue_point_bb_id));
+
+                    let end_bb_id = fb.current_basic_block + 1;
+                    fb.move_forward();
+
+                    for &bb_id in &[check_positive_bb_id, check_negative_bb_id] {
+                        fb.basic_blocks[bb_id].opcodes.push(OpCode::ConditionalBranch(
+                            body_begin_bb_id,
+                            end_bb_id
+                        ));
+                    }
+                    fb.basic_blocks[int_check_bb_id].opcodes.push(OpCode::ConditionalBranch(
+                        end_bb_id,
+                        int_step_bb_id
+                    ));
+                    fb.basic_blocks[break_point_bb_id].opcodes.push(OpCode::Branch(end_bb_id));
+                    Ok(())
+                })?;
+            },
+            StmtKind::Forin(ref vars, ref exprs, ref blk) => {
+                fb.scoped(|fb| -> Result<(), CodegenError> {
+                    let f_loc = fb.get_anonymous_local();
+                    let state_loc = fb.get_anonymous_local();
+                    let control_loc = fb.get_anonymous_local();
+
+                    build_adjusted_values(exprs, 3, fb)?;
+                    control_loc.build_set(fb)?;
+                    state_loc.build_set(fb)?;
+                    f_loc.build_set(fb)?;
+
+                    let call_bb_id = fb.current_basic_block + 1;
+                    fb.get_current_bb().opcodes.push(OpCode::Branch(call_bb_id));
+                    fb.move_forward();
+
+                    state_loc.build_get(fb)?;
+                    control_loc.build_get(fb)?;
+                    fb.get_current_bb().opcodes.push(OpCode::RotateReverse(2));
+                    f_loc.build_get(fb)?;
+                    fb.get_current_bb().opcodes.extend(vec! [
+                        OpCode::Dup,
+                        OpCode::Call(2)
+                    ]);
+                    fb.write_multi_value_expand(vars.len())?;
+
+                    let break_point_bb_id = fb.current_basic_block + 1;
+                    let mut check_bb_id = 0;
+                    let mut body_begin_bb_id = 0;
+
+                    fb.with_lci(LoopControlInfo {
+                        break_point: break_point_bb_id,
+                        continue_point: call_bb_id
+                    }, |fb| fb.scoped(|fb| -> Result<(), CodegenError> {
+                        // Fresh variables for each iteration.
+                        let mut locs = Vec::with_capacity(vars.len());
+                        for v in vars {
+                            locs.push(v.create_local(fb)?);
+                        }
+                        for loc in locs.iter().rev() {
+                            loc.build_init(fb)?;
+                        }
+
+                        locs[0].build_get(fb)?;
+                        control_loc.build_set(fb)?;
+                        control_loc.build_get(fb)?;
+                        fb.write_nil_test()?;
+                        check_bb_id = fb.current_basic_block;
+
+                        fb.move_forward();
+                        body_begin_bb_id = fb.current_basic_block + 1;
+                        fb.move_forward();
+
+                        blk.unrestricted_generate_code(fb)
+                    }))?;
+
+                    fb.get_current_bb().opcodes.push(OpCode::Branch(call_bb_id));
+
+                    let end_bb_id = fb.current_basic_block + 1;
+                    fb.move_forward();
+
+                    fb.basic_blocks[check_bb_id].opcodes.push(OpCode::ConditionalBranch(
+                        end_bb_id,
+                        body_begin_bb_id
+                    ));
+                    fb.basic_blocks[break_point_bb_id].opcodes.push(OpCode::Branch(end_bb_id));
+                    Ok(())
+                })?;
+            },
+            StmtKind::Repeat(ref blk, ref expr) => {
                 fb.scoped(|fb| -> Result<(), CodegenError> {
                     let before_bb_id = fb.current_basic_block;
 
@@ -175,7 +584,7 @@ impl UnrestrictedGenerateCode for Stmt {
                     Ok(())
                 })?;
             },
-            Stmt::If(ref branches, ref else_branch) => {
+            StmtKind::If(ref branches, ref else_branch) => {
                 let before_bb_id = fb.current_basic_block;
 
                 fb.move_forward();
@@ -218,36 +627,90 @@ impl UnrestrictedGenerateCode for Stmt {
                 let end_bb_id = fb.current_basic_block;
                 fb.basic_blocks[terminator_bb_id].opcodes.push(OpCode::Branch(end_bb_id));
             },
-            Stmt::Local(ref lhs, ref exprs) => {
-                if lhs.len() != exprs.len() {
-                    return Err("Local: lhs & exprs length mismatch".into());
+            StmtKind::Local(ref lhs, ref exprs) => {
+                build_adjusted_values(exprs, lhs.len(), fb)?;
+
+                // The new locals are only visible after all values are evaluated.
+                let mut locs = Vec::with_capacity(lhs.len());
+                for v in lhs {
+                    locs.push(v.create_local(fb)?);
+                }
+                for loc in locs.iter().rev() {
+                    loc.build_init(fb)?;
+                }
+            },
+            StmtKind::LocalAttrib(ref lhs, ref attribs, ref exprs) => {
+                build_adjusted_values(exprs, lhs.len(), fb)?;
+
+                let mut locs = Vec::with_capacity(lhs.len());
+                for v in lhs {
+                    locs.push(v.create_local(fb)?);
+                }
+                for (loc, attrib) in locs.iter().zip(attribs.iter()).rev() {
+                    if *attrib == Some(Attrib::Close) {
+                        fb.get_current_bb().opcodes.push(OpCode::Dup);
+                    }
+                    loc.build_init(fb)?;
+                }
+                // Both attributes make the variable read-only.
+                for (v, attrib) in lhs.iter().zip(attribs.iter()) {
+                    if attrib.is_some() {
+                        fb.mark_local_as_const(v.id().unwrap());
+                    }
                 }
-                for i in 0..lhs.len() {
-                    exprs[i].restricted_generate_code(fb)?;
-                    lhs[i].build_new_local(fb)?;
+                // The parser allows at most one to-be-closed variable, whose
+                // value was duplicated above.
+                if let Some(i) = attribs.iter().position(|v| *v == Some(Attrib::Close)) {
+                    fb.write_tbc_push(lhs[i].id().unwrap())?;
                 }
             },
-            Stmt::Call(ref target, ref args) => {
-                Expr::Call(Box::new(target.clone()), args.clone()).restricted_generate_code(fb)?;
+            StmtKind::Localrec(ref lhs, ref f) => {
+                // The local is visible inside the function body so that
+                // it can call itself.
+                let loc = lhs.create_local(fb)?;
+                fb.get_current_bb().opcodes.push(OpCode::LoadNull);
+                loc.build_init(fb)?;
+                f.restricted_generate_code(fb)?;
+                loc.build_set(fb)?;
+            },
+            StmtKind::Call(ref target, ref args) => {
+                build_call(target, args, fb)?;
                 fb.get_current_bb().opcodes.push(OpCode::Pop);
             },
-            Stmt::Return(ref v) => {
-                if v.len() == 0 {
-                    fb.get_current_bb().opcodes.push(OpCode::LoadNull);
-                    fb.get_current_bb().opcodes.push(OpCode::Return);
-                    fb.move_forward();
-                } else if v.len() == 1 {
-                    v[0].restricted_generate_code(fb)?;
-                    fb.get_current_bb().opcodes.push(OpCode::Return);
-                    fb.move_forward();
+            StmtKind::Invoke(ref target, ref method, ref args) => {
+                build_invoke(target, method, args, fb)?;
+                fb.get_current_bb().opcodes.push(OpCode::Pop);
+            },
+            StmtKind::Return(ref v) => {
+                if v.len() == 1 {
+                    v[0].multi_generate_code(fb)?;
                 } else {
-                    return Err("Multiple return values is not supported for now".into());
+                    let expand_last = match v.last() {
+                        Some(last) => last.is_multi_value(),
+                        None => false
+                    };
+                    for (i, expr) in v.iter().enumerate() {
+                        if i + 1 == v.len() && expand_last {
+                            expr.multi_generate_code(fb)?;
+                        } else {
+                            expr.restricted_generate_code(fb)?;
+                        }
+                    }
+                    fb.write_multi_value_create(v.len(), expand_last)?;
                 }
+                fb.write_tbc_close(0)?;
+                fb.get_current_bb().opcodes.push(OpCode::Return);
+                fb.move_forward();
+            },
+            StmtKind::Break => {
+                fb.write_break(None)?;
             },
-            Stmt::Break => {
-                fb.write_break()?;
+            StmtKind::Goto(ref name) => {
+                fb.write_goto(name, None)?;
             },
-            _ => return Err("Not implemented".into())
+            StmtKind::Label(ref name) => {
+                fb.write_label(name, false)?;
+            }
         }
 
         Ok(())
@@ -255,33 +718,48 @@ impl UnrestrictedGenerateCode for Stmt {
 }
 
 impl RestrictedGenerateCode for Expr {
+    fn restricted_generate_code(&self, fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
+        self.kind.restricted_generate_code(fb).map_err(|e| e.with_pos(self.pos))
+    }
+}
+
+impl RestrictedGenerateCode for ExprKind {
     fn restricted_generate_code(&self, fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
         match *self {
-            Expr::Nil => fb.get_current_bb().opcodes.push(OpCode::LoadNull),
-            Expr::Boolean(v) => fb.get_current_bb().opcodes.push(OpCode::LoadBool(v)),
-            Expr::Number(v) => fb.get_current_bb().opcodes.push(OpCode::LoadFloat(v)),
-            Expr::String(ref s) => fb.get_current_bb().opcodes.push(OpCode::LoadString(s.clone())),
-            Expr::Function(ref vlhs, ref blk) => {
-                let mut new_builder = fb.get_module_builder().new_function();
+            ExprKind::Nil => fb.get_current_bb().opcodes.push(OpCode::LoadNull),
+            ExprKind::Boolean(v) => fb.get_current_bb().opcodes.push(OpCode::LoadBool(v)),
+            ExprKind::Integer(v) => fb.get_current_bb().opcodes.push(OpCode::LoadInt(v)),
+            ExprKind::Number(v) => fb.get_current_bb().opcodes.push(OpCode::LoadFloat(v)),
+            ExprKind::String(ref s) => fb.get_current_bb().opcodes.push(OpCode::LoadString(s.clone())),
+            ExprKind::Function(ref vlhs, ref blk) => {
+                let new_builder = fb.get_module_builder().new_function();
 
                 let mut arg_names: Vec<String> = Vec::new();
-                for lhs in vlhs {
-                    if let Some(id) = lhs.id() {
-                        arg_names.push(id.to_string());
-                    } else {
-                        return Err("Expecting id in function signature".into());
+                let mut is_vararg = false;
+                for (i, lhs) in vlhs.iter().enumerate() {
+                    match lhs.kind {
+                        LhsKind::Id(ref id) => arg_names.push(id.clone()),
+                        LhsKind::Dots if i + 1 == vlhs.len() => is_vararg = true,
+                        _ => return Err(CodegenError::from("Expecting id in function signature").with_pos(lhs.pos))
                     }
                 }
 
-                let fn_id = new_builder.build(blk, arg_names)?;
+                let n_params = arg_names.len();
+                let (fn_id, upvalues) = new_builder.build_closure(blk, arg_names, is_vararg)?;
 
                 fb.write_function_load(fn_id)?;
+                fb.write_closure_create(n_params, is_vararg, &upvalues)?;
             },
-            Expr::Table(ref elems) => {
+            ExprKind::Table(ref elems) => {
                 fb.write_array_create()?;
                 for (i, v) in elems.iter().enumerate() {
                     fb.get_current_bb().opcodes.push(OpCode::Dup);
-                    v.restricted_generate_code(fb)?;
+                    // A trailing multi-value is expanded by `__copy_from_array__`.
+                    if i + 1 == elems.len() {
+                        v.multi_generate_code(fb)?;
+                    } else {
+                        v.restricted_generate_code(fb)?;
+                    }
                     fb.get_current_bb().opcodes.push(OpCode::Rotate2);
                     fb.write_array_push()?;
                 }
@@ -297,100 +775,47 @@ impl RestrictedGenerateCode for Expr {
                     OpCode::Pop
                 ]);
             },
-            Expr::Add(ref left, ref right) => {
-                left.restricted_generate_code(fb)?;
-                right.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.get_current_bb().opcodes.push(OpCode::Add);
-            },
-            Expr::Sub(ref left, ref right) => {
-                left.restricted_generate_code(fb)?;
-                right.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.get_current_bb().opcodes.push(OpCode::Sub);
-            },
-            Expr::Mul(ref left, ref right) => {
-                left.restricted_generate_code(fb)?;
-                right.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.get_current_bb().opcodes.push(OpCode::Mul);
-            },
-            Expr::Div(ref left, ref right) => {
-                left.restricted_generate_code(fb)?;
-                right.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.get_current_bb().opcodes.push(OpCode::Div);
-            },
-            Expr::Idiv(ref left, ref right) => {
-                left.restricted_generate_code(fb)?;
-                right.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.get_current_bb().opcodes.push(OpCode::IntDiv);
-            },
-            Expr::Mod(ref left, ref right) => {
-                left.restricted_generate_code(fb)?;
-                right.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.get_current_bb().opcodes.push(OpCode::Mod);
-            },
-            Expr::Pow(ref left, ref right) => {
-                left.restricted_generate_code(fb)?;
-                right.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.get_current_bb().opcodes.push(OpCode::Pow);
-            },
-            Expr::Concat(ref left, ref right) => {
-                left.restricted_generate_code(fb)?;
-                right.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.write_concat()?;
-            },
-            Expr::Eq(ref left, ref right) => {
-                left.restricted_generate_code(fb)?;
-                right.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.get_current_bb().opcodes.push(OpCode::TestEq);
-            },
-            Expr::Ne(ref left, ref right) => {
-                left.restricted_generate_code(fb)?;
-                right.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.get_current_bb().opcodes.push(OpCode::TestNe);
-            },
-            Expr::Lt(ref left, ref right) => {
-                left.restricted_generate_code(fb)?;
-                right.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.get_current_bb().opcodes.push(OpCode::TestLt);
-            },
-            Expr::Gt(ref left, ref right) => {
-                left.restricted_generate_code(fb)?;
-                right.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.get_current_bb().opcodes.push(OpCode::TestGt);
+            ExprKind::Add(ref left, ref right) => build_binary_op(left, right, "add", fb)?,
+            ExprKind::Sub(ref left, ref right) => build_binary_op(left, right, "sub", fb)?,
+            ExprKind::Mul(ref left, ref right) => build_binary_op(left, right, "mul", fb)?,
+            ExprKind::Div(ref left, ref right) => build_binary_op(left, right, "div", fb)?,
+            ExprKind::Idiv(ref left, ref right) => build_binary_op(left, right, "idiv", fb)?,
+            ExprKind::Mod(ref left, ref right) => build_binary_op(left, right, "mod", fb)?,
+            ExprKind::Pow(ref left, ref right) => build_binary_op(left, right, "pow", fb)?,
+            ExprKind::Concat(ref left, ref right) => build_binary_op(left, right, "concat", fb)?,
+            ExprKind::Eq(ref left, ref right) => build_binary_op(left, right, "eq", fb)?,
+            ExprKind::Ne(ref left, ref right) => {
+                build_binary_op(left, right, "eq", fb)?;
+                fb.get_current_bb().opcodes.push(OpCode::Not);
             },
-            Expr::Le(ref left, ref right) => {
+            ExprKind::Lt(ref left, ref right) => build_binary_op(left, right, "lt", fb)?,
+            // `a > b` is evaluated as `b < a`, as metamethods expect.
+            ExprKind::Gt(ref left, ref right) => {
                 left.restricted_generate_code(fb)?;
                 right.restricted_generate_code(fb)?;
                 fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.get_current_bb().opcodes.push(OpCode::TestLe);
+                fb.write_binary_op("lt")?;
             },
-            Expr::Ge(ref left, ref right) => {
+            ExprKind::Le(ref left, ref right) => build_binary_op(left, right, "le", fb)?,
+            ExprKind::Ge(ref left, ref right) => {
                 left.restricted_generate_code(fb)?;
                 right.restricted_generate_code(fb)?;
                 fb.get_current_bb().opcodes.push(OpCode::Rotate2);
-                fb.get_current_bb().opcodes.push(OpCode::TestGe);
+                fb.write_binary_op("le")?;
             },
-            Expr::Not(ref v) => {
+            ExprKind::Not(ref v) => {
                 v.restricted_generate_code(fb)?;
                 fb.get_current_bb().opcodes.push(OpCode::Not);
             },
-            Expr::Unm(ref v) => {
+            ExprKind::Unm(ref v) => {
                 v.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::LoadFloat(0.0));
-                fb.get_current_bb().opcodes.push(OpCode::Sub);
+                fb.write_unary_op("unm")?;
             },
-            Expr::And(ref left, ref right) => {
+            ExprKind::Len(ref v) => {
+                v.restricted_generate_code(fb)?;
+                fb.write_len()?;
+            },
+            ExprKind::And(ref left, ref right) => {
                 let begin = fb.get_current_bb().opcodes.len();
                 left.restricted_generate_code(fb)?;
                 let left_opcodes = fb.get_current_bb().detach_opcodes(begin);
@@ -406,7 +831,7 @@ impl RestrictedGenerateCode for Expr {
                     right_opcodes
                 ));
             },
-            Expr::Or(ref left, ref right) => {
+            ExprKind::Or(ref left, ref right) => {
                 let begin = fb.get_current_bb().opcodes.len();
                 left.restricted_generate_code(fb)?;
                 let left_opcodes = fb.get_current_bb().detach_opcodes(begin);
@@ -422,34 +847,35 @@ impl RestrictedGenerateCode for Expr {
                     right_opcodes
                 ));
             },
-            Expr::Call(ref target, ref args) => {
-                for arg in args {
-                    arg.restricted_generate_code(fb)?;
-                }
-                if args.len() > 0 {
-                    fb.get_current_bb().opcodes.push(OpCode::RotateReverse(args.len()));
-                }
-                fb.get_current_bb().opcodes.push(OpCode::LoadNull);
-                target.restricted_generate_code(fb)?;
-                fb.get_current_bb().opcodes.push(OpCode::Call(args.len()));
+            ExprKind::Call(ref target, ref args) => {
+                build_call(target, args, fb)?;
+                fb.write_multi_value_truncate()?;
+            },
+            ExprKind::Invoke(ref target, ref method, ref args) => {
+                build_invoke(target, method, args, fb)?;
+                fb.write_multi_value_truncate()?;
+            },
+            ExprKind::Paren(ref v) => {
+                v.restricted_generate_code(fb)?;
             },
-            Expr::Pair(ref left, ref right) => {
+            ExprKind::Pair(ref left, ref right) => {
                 left.restricted_generate_code(fb)?;
                 right.restricted_generate_code(fb)?;
                 fb.get_current_bb().opcodes.push(OpCode::Rotate2);
                 fb.write_pair_create()?;
             },
-            Expr::Id(ref k) => {
-                let loc = fb.get_var_location(k.as_str());
+            ExprKind::Id(ref k) => {
+                let loc = fb.get_var_location(k.as_str())?;
                 loc.build_get(fb)?;
             },
-            Expr::Index(ref target, ref index) => {
+            ExprKind::Index(ref target, ref index) => {
                 index.restricted_generate_code(fb)?;
                 target.restricted_generate_code(fb)?;
                 fb.write_index_get()?;
             },
-            Expr::Dots => {
-                return Err("Dots: Not implemented".into());
+            ExprKind::Dots => {
+                fb.write_varargs_load()?;
+                fb.write_multi_value_truncate()?;
             }
         }
 
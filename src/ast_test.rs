@@ -16,3 +16,28 @@ fn test_ast_build() {
     ast = serde_json::from_str(test_programs::get("arithmetic")).unwrap();
     println!("{:?}", ast);
 }
+
+#[test]
+fn test_ast_positions_from_json() {
+    let ast = Block::from_json(r#"{ "Block": [
+        { "Local": [ [ { "Id": "a", "pos": { "line": 1, "column": 7 } } ], [ { "Number": 1 } ] ],
+          "pos": { "line": 1, "column": 1 } },
+        { "Break": null, "pos": { "line": 2, "column": 3 } },
+        "Break"
+    ] }"#).unwrap();
+    let stmts = ast.statements();
+
+    assert_eq!(stmts[0].pos, Some(Pos::new(1, 1)));
+    match stmts[0].kind {
+        StmtKind::Local(ref lhs, ref exprs) => {
+            assert_eq!(lhs[0].pos, Some(Pos::new(1, 7)));
+            assert_eq!(exprs[0].pos, None);
+        },
+        _ => panic!("expecting local")
+    }
+    assert_eq!(stmts[1].pos, Some(Pos::new(2, 3)));
+    assert_eq!(stmts[2].pos, None);
+
+    let round_trip = Block::from_json(serde_json::to_string(&ast).unwrap()).unwrap();
+    assert_eq!(round_trip.statements()[1].pos, Some(Pos::new(2, 3)));
+}
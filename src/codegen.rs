@@ -1,4 +1,3 @@
-use std::rc::Rc;
 use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet};
 use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
@@ -7,25 +6,84 @@ use hexagon::opcode::OpCode;
 use hexagon::function::Function;
 use ast;
 use ast::GetEscapeInfo;
-use ast_codegen::{RestrictedGenerateCode, UnrestrictedGenerateCode, CodegenError};
+use ast_codegen::{UnrestrictedGenerateCode, CodegenError};
+
+// Limits of a hexagon call frame.
+pub const MAX_ARGS: usize = 32;
+pub const MAX_LOCALS: usize = 32;
 
 pub struct ModuleBuilder {
     scopes: RefCell<Vec<Scope>>,
+    upvalue_lists: RefCell<Vec<UpvalueList>>,
     pub(crate) functions: RefCell<Vec<Function>>,
-    next_unique_id: Cell<usize>
+    next_unique_id: Cell<usize>,
+    source_name: Option<String>
 }
 
 pub struct Scope {
     vars: HashMap<String, VarLocation>,
+    // Variables declared `<const>` or `<close>`.
+    consts: HashSet<String>,
     is_function_root: bool
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum VarLocation {
     Local(usize),
+    // A local slot holding an upvalue cell, for locals captured by closures.
+    Cell(usize),
+    // An argument holding an upvalue cell passed in by the closure.
+    Upvalue(usize),
     This(String)
 }
 
+/// Upvalues captured by a function that is being built.
+///
+/// Each entry is where the enclosing function finds the upvalue cell
+/// when creating the closure. Inside the function, the cell is passed
+/// as argument `base + index`.
+struct UpvalueList {
+    base: usize,
+    upvalues: Vec<VarLocation>
+}
+
+/// Labels and pending gotos of a block, following Lua 5.2 rules: a goto
+/// jumps to a visible label in the same or an enclosing block, and may
+/// not jump forward into the scope of a local.
+struct LabelBlock {
+    labels: Vec<Label>,
+    pending_gotos: Vec<PendingGoto>,
+    // Named locals declared in this block so far.
+    locals: Vec<String>
+}
+
+struct Label {
+    name: String,
+    basic_block: usize,
+    // To-be-closed variables that are active at the label.
+    n_tbc: usize
+}
+
+struct PendingGoto {
+    name: String,
+    // The basic block to be terminated with a branch to the label.
+    basic_block: usize,
+    // Locals of the current block that are visible at the goto.
+    n_locals: usize,
+    n_tbc: usize,
+    pos: Option<ast::Pos>
+}
+
+impl LabelBlock {
+    fn new() -> LabelBlock {
+        LabelBlock {
+            labels: Vec::new(),
+            pending_gotos: Vec::new(),
+            locals: Vec::new()
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct LoopControlInfo {
     pub break_point: usize,
@@ -36,15 +94,32 @@ pub struct FunctionBuilder<'a> {
     module: &'a ModuleBuilder,
     pub(crate) basic_blocks: Vec<BasicBlockBuilder>,
     next_local_id: usize,
+    n_local_slots: usize,
+    vararg_index: Option<usize>,
     pub(crate) current_basic_block: usize,
     loop_control_info: Vec<LoopControlInfo>,
-    closure_escaped_vars: HashSet<String>
+    label_blocks: Vec<LabelBlock>,
+    closure_escaped_vars: HashSet<String>,
+
+    // To-be-closed variables of this function that are active at the
+    // current position, and at the start of each enclosing loop.
+    //
+    // Their values live on a runtime stack, so every path leaving
+    // their scope pops and closes the difference.
+    n_active_tbc: usize,
+    loop_tbc_counts: Vec<usize>
 }
 
 pub struct BasicBlockBuilder {
     pub opcodes: Vec<OpCode>
 }
 
+impl Default for BasicBlockBuilder {
+    fn default() -> Self {
+        BasicBlockBuilder::new()
+    }
+}
+
 impl BasicBlockBuilder {
     pub fn new() -> BasicBlockBuilder {
         BasicBlockBuilder {
@@ -57,15 +132,34 @@ impl BasicBlockBuilder {
     }
 }
 
+impl Default for ModuleBuilder {
+    fn default() -> Self {
+        ModuleBuilder::new()
+    }
+}
+
 impl ModuleBuilder {
     pub fn new() -> ModuleBuilder {
         ModuleBuilder {
             scopes: RefCell::new(Vec::new()),
+            upvalue_lists: RefCell::new(Vec::new()),
             functions: RefCell::new(Vec::new()),
-            next_unique_id: Cell::new(0)
+            next_unique_id: Cell::new(0),
+            source_name: None
         }
     }
 
+    /// Creates a module builder whose errors refer to the given source file.
+    pub fn with_source_name<T: ToString>(name: T) -> ModuleBuilder {
+        let mut module = ModuleBuilder::new();
+        module.source_name = Some(name.to_string());
+        module
+    }
+
+    pub fn get_source_name(&self) -> Option<&str> {
+        self.source_name.as_deref()
+    }
+
     pub fn new_function<'a>(&'a self) -> FunctionBuilder<'a> {
         FunctionBuilder::new(self)
     }
@@ -81,31 +175,88 @@ impl ModuleBuilder {
     fn add_var_to_scope(&self, k: String, v: VarLocation) {
         let mut scopes = self.scopes.borrow_mut();
         let target_id = scopes.len() - 1;
+        scopes[target_id].consts.remove(&k);
         scopes[target_id].vars.insert(k, v);
     }
 
-    pub fn lookup_var(&self, key: &str) -> Option<VarLocation> {
-        let scopes = self.scopes.borrow();
-        let mut passed_function_root: bool = false;
+    fn mark_var_as_const(&self, k: &str) {
+        let mut scopes = self.scopes.borrow_mut();
+        let target_id = scopes.len() - 1;
+        scopes[target_id].consts.insert(k.to_string());
+    }
+
+    fn is_const_var(&self, key: &str) -> bool {
+        for scope in self.scopes.borrow().iter().rev() {
+            if scope.vars.contains_key(key) {
+                return scope.consts.contains(key);
+            }
+        }
+        false
+    }
+
+    pub fn lookup_var(&self, key: &str) -> Result<Option<VarLocation>, CodegenError> {
+        let (depth, loc) = {
+            let scopes = self.scopes.borrow();
+            let mut depth: usize = 0;
+            let mut found: Option<(usize, VarLocation)> = None;
+
+            for scope in scopes.iter().rev() {
+                if let Some(loc) = scope.vars.get(key) {
+                    found = Some((depth, loc.clone()));
+                    break;
+                }
 
-        for scope in scopes.iter().rev() {
-            if let Some(ref loc) = scope.vars.get(key) {
-                if passed_function_root {
-                    // Accessing locals across a function boundary
-                    // is invalid.
-                    if let VarLocation::Local(_) = **loc {
-                        continue
-                    }
+                if scope.is_function_root {
+                    depth += 1;
                 }
-                return Some((*loc).clone());
             }
 
-            if scope.is_function_root {
-                passed_function_root = true;
+            match found {
+                Some(v) => v,
+                None => return Ok(None)
             }
+        };
+
+        if depth == 0 {
+            return Ok(Some(loc));
+        }
+
+        // Accessing locals across a function boundary
+        // requires them to live in a cell.
+        if let VarLocation::Cell(_) = loc {} else {
+            return Err(format!("Local `{}` is captured but not stored in a cell", key).into());
         }
 
-        None
+        // Pass the cell down through every function between the
+        // one declaring the variable and the current one.
+        let mut upvalue_lists = self.upvalue_lists.borrow_mut();
+        let n_levels = upvalue_lists.len();
+        let mut source = loc;
+
+        for list in upvalue_lists[n_levels - depth..].iter_mut() {
+            let id = match list.upvalues.iter().position(|v| *v == source) {
+                Some(id) => id,
+                None => {
+                    list.upvalues.push(source);
+                    list.upvalues.len() - 1
+                }
+            };
+            source = VarLocation::Upvalue(list.base + id);
+        }
+
+        Ok(Some(source))
+    }
+
+    fn set_upvalue_base(&self, base: usize) {
+        let mut upvalue_lists = self.upvalue_lists.borrow_mut();
+        let id = upvalue_lists.len() - 1;
+        upvalue_lists[id].base = base;
+    }
+
+    fn take_upvalues(&self) -> Vec<VarLocation> {
+        let mut upvalue_lists = self.upvalue_lists.borrow_mut();
+        let id = upvalue_lists.len() - 1;
+        ::std::mem::take(&mut upvalue_lists[id].upvalues)
     }
 
     pub fn get_unique_id(&self) -> String {
@@ -116,10 +267,17 @@ impl ModuleBuilder {
     }
 }
 
+impl Default for Scope {
+    fn default() -> Self {
+        Scope::new()
+    }
+}
+
 impl Scope {
     pub fn new() -> Scope {
         Scope {
             vars: HashMap::new(),
+            consts: HashSet::new(),
             is_function_root: false
         }
     }
@@ -135,17 +293,26 @@ impl<'a> FunctionBuilder<'a> {
         scope.mark_as_function_root();
 
         module.push_scope(scope);
+        module.upvalue_lists.borrow_mut().push(UpvalueList {
+            base: 0,
+            upvalues: Vec::new()
+        });
 
         FunctionBuilder {
-            module: module,
+            module,
             basic_blocks: vec! [
                 BasicBlockBuilder::new(),
                 BasicBlockBuilder::new()
             ],
             next_local_id: 0,
+            n_local_slots: 0,
+            vararg_index: None,
             current_basic_block: 1,
             loop_control_info: Vec::new(),
-            closure_escaped_vars: HashSet::new()
+            label_blocks: vec! [ LabelBlock::new() ],
+            closure_escaped_vars: HashSet::new(),
+            n_active_tbc: 0,
+            loop_tbc_counts: Vec::new()
         }
     }
 
@@ -163,24 +330,24 @@ impl<'a> FunctionBuilder<'a> {
     }
 
     fn build_args_load(&mut self, names: Vec<String>) -> Result<(), CodegenError> {
-        for i in 0..names.len() {
-            let loc = self.create_local(names[i].as_str());
+        for (i, name) in names.iter().enumerate() {
+            let loc = self.create_local(name.as_str());
             self.get_current_bb().opcodes.push(
                 OpCode::GetArgument(i)
             );
-            loc.build_set(self)?;
+            loc.build_init(self)?;
         }
         Ok(())
     }
 
     pub fn create_local(&mut self, key: &str) -> VarLocation {
         let loc = if self.closure_escaped_vars.contains(key) {
-            VarLocation::This(self.module.get_unique_id())
+            VarLocation::Cell(self.next_local_id)
         } else {
-            let v = VarLocation::Local(self.next_local_id);
-            self.next_local_id += 1;
-            v
+            VarLocation::Local(self.next_local_id)
         };
+        self.alloc_local_slot();
+        self.label_blocks.last_mut().unwrap().locals.push(key.to_string());
         self.module.add_var_to_scope(
             key.to_string(),
             loc.clone()
@@ -189,22 +356,39 @@ impl<'a> FunctionBuilder<'a> {
         loc
     }
 
-    pub fn get_var_location(&mut self, key: &str) -> VarLocation {
-        let loc = match self.module.lookup_var(key) {
+    /// Makes a local created by `create_local` read-only.
+    pub fn mark_local_as_const(&mut self, key: &str) {
+        self.module.mark_var_as_const(key);
+    }
+
+    pub fn is_const_var(&self, key: &str) -> bool {
+        self.module.is_const_var(key)
+    }
+
+    pub fn get_var_location(&mut self, key: &str) -> Result<VarLocation, CodegenError> {
+        let loc = match self.module.lookup_var(key)? {
             Some(v) => v,
             None => VarLocation::This(key.to_string())
         };
         println!("[get_var_location] {} -> {:?}", key, loc);
-        loc
+        Ok(loc)
     }
 
     pub fn get_anonymous_local(&mut self) -> VarLocation {
         let loc = VarLocation::Local(self.next_local_id);
-        self.next_local_id += 1;
+        self.alloc_local_slot();
 
         loc
     }
 
+    // Slots are released at the end of each scope and reused by later ones.
+    fn alloc_local_slot(&mut self) {
+        self.next_local_id += 1;
+        if self.next_local_id > self.n_local_slots {
+            self.n_local_slots = self.next_local_id;
+        }
+    }
+
     pub fn write_function_load(&mut self, id: usize) -> Result<(), CodegenError> {
         self.get_current_bb().opcodes.extend(vec! [
             OpCode::LoadInt(id as i64),
@@ -218,6 +402,163 @@ impl<'a> FunctionBuilder<'a> {
         Ok(())
     }
 
+    /// Wraps the function on top of the stack into a closure
+    /// capturing the given upvalue cells.
+    pub fn write_closure_create(&mut self, n_params: usize, is_vararg: bool, upvalues: &[VarLocation]) -> Result<(), CodegenError> {
+        if upvalues.len() + 3 > MAX_ARGS {
+            return Err("Too many upvalues".into());
+        }
+        self.get_current_bb().opcodes.push(OpCode::LoadInt(n_params as i64));
+        self.get_current_bb().opcodes.push(OpCode::LoadBool(is_vararg));
+        for loc in upvalues {
+            loc.build_get_cell(self)?;
+        }
+        self.get_current_bb().opcodes.extend(vec! [
+            OpCode::RotateReverse(upvalues.len() + 3),
+            OpCode::LoadNull,
+            OpCode::LoadString("@__luax_internal.new_closure".into()),
+            OpCode::LoadThis,
+            OpCode::GetField,
+            OpCode::Call(upvalues.len() + 3)
+        ]);
+        Ok(())
+    }
+
+    /// Pushes the extra arguments of a vararg function as a multi-value.
+    pub fn write_varargs_load(&mut self) -> Result<(), CodegenError> {
+        let id = match self.vararg_index {
+            Some(v) => v,
+            None => return Err("cannot use '...' outside a vararg function".into())
+        };
+        self.get_current_bb().opcodes.push(OpCode::GetArgument(id));
+        Ok(())
+    }
+
+    pub fn write_cell_create(&mut self) -> Result<(), CodegenError> {
+        self.get_current_bb().opcodes.extend(vec! [
+            OpCode::LoadNull,
+            OpCode::LoadString("@__luax_internal.new_cell".into()),
+            OpCode::LoadThis,
+            OpCode::GetField,
+            OpCode::Call(1)
+        ]);
+        Ok(())
+    }
+
+    /// Replaces the value on top of the stack with its first `n` values,
+    /// padding with nil if it holds fewer.
+    pub fn write_multi_value_expand(&mut self, n: usize) -> Result<(), CodegenError> {
+        for i in 0..n {
+            self.get_current_bb().opcodes.extend(vec! [
+                OpCode::Dup,
+                OpCode::LoadInt(i as i64),
+                OpCode::RotateReverse(2),
+                OpCode::LoadNull,
+                OpCode::LoadString("@__luax_internal.nth_value".into()),
+                OpCode::LoadThis,
+                OpCode::GetField,
+                OpCode::Call(2),
+                OpCode::Rotate2
+            ]);
+        }
+        self.get_current_bb().opcodes.push(OpCode::Pop);
+        Ok(())
+    }
+
+    /// Truncates the value on top of the stack to a single value.
+    pub fn write_multi_value_truncate(&mut self) -> Result<(), CodegenError> {
+        self.get_current_bb().opcodes.extend(vec! [
+            OpCode::LoadInt(0),
+            OpCode::RotateReverse(2),
+            OpCode::LoadNull,
+            OpCode::LoadString("@__luax_internal.nth_value".into()),
+            OpCode::LoadThis,
+            OpCode::GetField,
+            OpCode::Call(2)
+        ]);
+        Ok(())
+    }
+
+    /// Replaces the top `n` values on the stack with the values they
+    /// produce together, expanding the last one if `expand_last` is set.
+    pub fn write_multi_value_create(&mut self, n: usize, expand_last: bool) -> Result<(), CodegenError> {
+        if n + 1 > MAX_ARGS {
+            return Err("Too many values".into());
+        }
+        self.get_current_bb().opcodes.extend(vec! [
+            OpCode::LoadBool(expand_last),
+            OpCode::RotateReverse(n + 1),
+            OpCode::LoadNull,
+            OpCode::LoadString("@__luax_internal.new_multi_value".into()),
+            OpCode::LoadThis,
+            OpCode::GetField,
+            OpCode::Call(n + 1)
+        ]);
+        Ok(())
+    }
+
+    /// Calls a function with `n_fixed` arguments followed by the values
+    /// of a multi-value.
+    ///
+    /// The stack holds the target, the fixed arguments and the multi-value.
+    pub fn write_apply(&mut self, n_fixed: usize) -> Result<(), CodegenError> {
+        if n_fixed + 2 > MAX_ARGS {
+            return Err("Too many arguments".into());
+        }
+        self.get_current_bb().opcodes.extend(vec! [
+            OpCode::RotateReverse(n_fixed + 2),
+            OpCode::LoadNull,
+            OpCode::LoadString("@__luax_internal.apply".into()),
+            OpCode::LoadThis,
+            OpCode::GetField,
+            OpCode::Call(n_fixed + 2)
+        ]);
+        Ok(())
+    }
+
+    /// Replaces the value on top of the stack with whether it is nil.
+    ///
+    /// Unlike `TestEq`, this works for values of any type.
+    pub fn write_nil_test(&mut self) -> Result<(), CodegenError> {
+        self.get_current_bb().opcodes.extend(vec! [
+            OpCode::RotateReverse(1),
+            OpCode::LoadNull,
+            OpCode::LoadString("@__luax_internal.is_nil".into()),
+            OpCode::LoadThis,
+            OpCode::GetField,
+            OpCode::Call(1)
+        ]);
+        Ok(())
+    }
+
+    /// Replaces the value on top of the stack with its length.
+    pub fn write_len(&mut self) -> Result<(), CodegenError> {
+        self.get_current_bb().opcodes.extend(vec! [
+            OpCode::RotateReverse(1),
+            OpCode::LoadNull,
+            OpCode::LoadString("@__luax_internal.len".into()),
+            OpCode::LoadThis,
+            OpCode::GetField,
+            OpCode::Call(1)
+        ]);
+        Ok(())
+    }
+
+    /// Replaces the initial value, limit and step on top of the stack with
+    /// the control variable, the limit or iteration count, the step and
+    /// whether the loop counts with integers.
+    pub fn write_fornum_prep(&mut self) -> Result<(), CodegenError> {
+        self.get_current_bb().opcodes.extend(vec! [
+            OpCode::RotateReverse(3),
+            OpCode::LoadNull,
+            OpCode::LoadString("@__luax_internal.fornum_prep".into()),
+            OpCode::LoadThis,
+            OpCode::GetField,
+            OpCode::Call(3)
+        ]);
+        self.write_multi_value_expand(4)
+    }
+
     pub fn write_array_create(&mut self) -> Result<(), CodegenError> {
         self.get_current_bb().opcodes.extend(vec! [
             OpCode::LoadNull,
@@ -262,9 +603,33 @@ impl<'a> FunctionBuilder<'a> {
         Ok(())
     }
 
-    pub fn write_concat(&mut self) -> Result<(), CodegenError> {
+    /// Applies the binary operator `op` to the two operands on top of the
+    /// stack, the right one being topmost.
+    ///
+    /// Operators are implemented by internal natives, which handle plain
+    /// numbers (and strings for `concat`) first and fall back to
+    /// metamethods otherwise.
+    pub fn write_binary_op(&mut self, op: &str) -> Result<(), CodegenError> {
         self.get_current_bb().opcodes.extend(vec! [
-            OpCode::StringAdd
+            OpCode::RotateReverse(2),
+            OpCode::LoadNull,
+            OpCode::LoadString(format!("@__luax_internal.{}", op)),
+            OpCode::LoadThis,
+            OpCode::GetField,
+            OpCode::Call(2)
+        ]);
+        Ok(())
+    }
+
+    /// Applies the unary operator `op` to the value on top of the stack.
+    pub fn write_unary_op(&mut self, op: &str) -> Result<(), CodegenError> {
+        self.get_current_bb().opcodes.extend(vec! [
+            OpCode::RotateReverse(1),
+            OpCode::LoadNull,
+            OpCode::LoadString(format!("@__luax_internal.{}", op)),
+            OpCode::LoadThis,
+            OpCode::GetField,
+            OpCode::Call(1)
         ]);
         Ok(())
     }
@@ -280,20 +645,25 @@ impl<'a> FunctionBuilder<'a> {
         Ok(())
     }
 
+    // Expects the key and then the target on the stack, which are the
+    // arguments in reverse order. Values other than tables are indexed
+    // through the metatable of their type.
     pub fn write_index_get(&mut self) -> Result<(), CodegenError> {
         self.get_current_bb().opcodes.extend(vec! [
-            OpCode::LoadString("__get__".into()),
             OpCode::LoadNull,
-            OpCode::Rotate3,
-            OpCode::CallField(1)
+            OpCode::LoadString("@__luax_internal.index".into()),
+            OpCode::LoadThis,
+            OpCode::GetField,
+            OpCode::Call(2)
         ]);
         Ok(())
     }
 
     pub fn write_index_set(&mut self) -> Result<(), CodegenError> {
         self.get_current_bb().opcodes.extend(vec! [
+            OpCode::Dup,
             OpCode::LoadString("__set__".into()),
-            OpCode::LoadNull,
+            OpCode::Rotate2,
             OpCode::Rotate3,
             OpCode::CallField(2),
             OpCode::Pop
@@ -301,11 +671,66 @@ impl<'a> FunctionBuilder<'a> {
         Ok(())
     }
 
-    pub fn write_break(&mut self) -> Result<(), CodegenError> {
+    /// Registers the value on top of the stack, which is consumed, as a
+    /// to-be-closed variable named `name`.
+    pub fn write_tbc_push(&mut self, name: &str) -> Result<(), CodegenError> {
+        self.get_current_bb().opcodes.extend(vec! [
+            OpCode::LoadString(name.to_string()),
+            OpCode::RotateReverse(2),
+            OpCode::LoadNull,
+            OpCode::LoadString("@__luax_internal.tbc_push".into()),
+            OpCode::LoadThis,
+            OpCode::GetField,
+            OpCode::Call(2),
+            OpCode::Pop
+        ]);
+        self.n_active_tbc += 1;
+        Ok(())
+    }
+
+    fn tbc_close_opcodes(n: usize) -> Vec<OpCode> {
+        if n == 0 {
+            return Vec::new();
+        }
+        vec! [
+            OpCode::LoadInt(n as i64),
+            OpCode::RotateReverse(1),
+            OpCode::LoadNull,
+            OpCode::LoadString("@__luax_internal.tbc_close".into()),
+            OpCode::LoadThis,
+            OpCode::GetField,
+            OpCode::Call(1),
+            OpCode::Pop
+        ]
+    }
+
+    pub fn get_tbc_count(&self) -> usize {
+        self.n_active_tbc
+    }
+
+    /// Closes the to-be-closed variables declared after there were
+    /// `count` of them, e.g. at the end of a block or before returning.
+    pub fn write_tbc_close(&mut self, count: usize) -> Result<(), CodegenError> {
+        let ops = Self::tbc_close_opcodes(self.n_active_tbc - count);
+        self.get_current_bb().opcodes.extend(ops);
+        Ok(())
+    }
+
+    /// Leaves a block that started with `count` active to-be-closed
+    /// variables.
+    pub fn end_tbc_block(&mut self, count: usize) -> Result<(), CodegenError> {
+        self.write_tbc_close(count)?;
+        self.n_active_tbc = count;
+        Ok(())
+    }
+
+    pub fn write_break(&mut self, pos: Option<ast::Pos>) -> Result<(), CodegenError> {
         let target = match self.get_lci() {
             Some(v) => v.break_point,
-            None => return Err("No LCI".into())
+            None => return Err(CodegenError::from("break outside a loop").with_pos(pos))
         };
+        let loop_tbc_count = *self.loop_tbc_counts.last().unwrap();
+        self.write_tbc_close(loop_tbc_count)?;
         self.get_current_bb().opcodes.push(OpCode::Branch(target));
         self.move_forward();
         Ok(())
@@ -321,6 +746,103 @@ impl<'a> FunctionBuilder<'a> {
         Ok(())
     }
 
+    /// Defines a label at the current position.
+    ///
+    /// A label at the end of its block is considered to be outside the
+    /// scope of the block's locals.
+    pub fn write_label(&mut self, name: &str, at_end: bool) -> Result<(), CodegenError> {
+        let target = self.current_basic_block + 1;
+        self.get_current_bb().opcodes.push(OpCode::Branch(target));
+        self.move_forward();
+
+        // Labels of the enclosing blocks are visible here too.
+        if self.label_blocks.iter().any(|b| b.labels.iter().any(|v| v.name == name)) {
+            return Err(format!("label '{}' already defined", name).into());
+        }
+        let block = self.label_blocks.last_mut().unwrap();
+        let n_locals = if at_end {
+            0
+        } else {
+            block.locals.len()
+        };
+        let n_tbc = self.n_active_tbc;
+        block.labels.push(Label {
+            name: name.to_string(),
+            basic_block: target,
+            n_tbc
+        });
+
+        let mut unresolved = Vec::new();
+        for goto in ::std::mem::take(&mut block.pending_gotos) {
+            if goto.name != name {
+                unresolved.push(goto);
+                continue;
+            }
+            if n_locals > goto.n_locals {
+                return Err(CodegenError::from(format!(
+                    "<goto {}> jumps into the scope of local '{}'",
+                    name,
+                    block.locals[goto.n_locals]
+                )).with_pos(goto.pos));
+            }
+            let bb = &mut self.basic_blocks[goto.basic_block].opcodes;
+            bb.extend(Self::tbc_close_opcodes(goto.n_tbc - n_tbc));
+            bb.push(OpCode::Branch(target));
+        }
+        self.label_blocks.last_mut().unwrap().pending_gotos = unresolved;
+        Ok(())
+    }
+
+    pub fn write_goto(&mut self, name: &str, pos: Option<ast::Pos>) -> Result<(), CodegenError> {
+        let block = self.label_blocks.last_mut().unwrap();
+        match block.labels.iter().find(|v| v.name == name) {
+            Some(label) => {
+                let (target, n_tbc) = (label.basic_block, label.n_tbc);
+                self.write_tbc_close(n_tbc)?;
+                self.get_current_bb().opcodes.push(OpCode::Branch(target));
+            },
+            None => {
+                // Resolved when the label is defined later.
+                block.pending_gotos.push(PendingGoto {
+                    name: name.to_string(),
+                    basic_block: self.current_basic_block,
+                    n_locals: block.locals.len(),
+                    n_tbc: self.n_active_tbc,
+                    pos
+                });
+            }
+        }
+        self.move_forward();
+        Ok(())
+    }
+
+    fn pop_label_block(&mut self) {
+        let block = self.label_blocks.pop().unwrap();
+        let parent = self.label_blocks.last_mut().unwrap();
+
+        for mut goto in block.pending_gotos {
+            // Labels defined so far in the enclosing block precede the goto.
+            if let Some(label) = parent.labels.iter().find(|v| v.name == goto.name) {
+                let bb = &mut self.basic_blocks[goto.basic_block].opcodes;
+                bb.extend(Self::tbc_close_opcodes(goto.n_tbc - label.n_tbc));
+                bb.push(OpCode::Branch(label.basic_block));
+                continue;
+            }
+            goto.n_locals = parent.locals.len();
+            parent.pending_gotos.push(goto);
+        }
+    }
+
+    fn check_pending_gotos(&self) -> Result<(), CodegenError> {
+        match self.label_blocks[0].pending_gotos.first() {
+            Some(goto) => Err(CodegenError::from(format!(
+                "no visible label '{}' for goto",
+                goto.name
+            )).with_pos(goto.pos)),
+            None => Ok(())
+        }
+    }
+
     pub fn get_lci(&self) -> Option<&LoopControlInfo> {
         if !self.loop_control_info.is_empty() {
             Some(&self.loop_control_info[self.loop_control_info.len() - 1])
@@ -331,7 +853,9 @@ impl<'a> FunctionBuilder<'a> {
 
     pub fn with_lci<R, T: FnMut(&mut Self) -> R>(&mut self, lci: LoopControlInfo, mut f: T) -> R {
         self.loop_control_info.push(lci);
+        self.loop_tbc_counts.push(self.n_active_tbc);
         let ret = catch_unwind(AssertUnwindSafe(|| f(self)));
+        self.loop_tbc_counts.pop().unwrap();
         self.loop_control_info.pop().unwrap();
 
         match ret {
@@ -341,9 +865,13 @@ impl<'a> FunctionBuilder<'a> {
     }
 
     pub fn scoped<R, T: FnMut(&mut Self) -> R>(&mut self, mut f: T) -> R {
+        let next_local_id = self.next_local_id;
         self.module.push_scope(Scope::new());
+        self.label_blocks.push(LabelBlock::new());
         let ret = catch_unwind(AssertUnwindSafe(|| f(self)));
+        self.pop_label_block();
         self.module.pop_scope();
+        self.next_local_id = next_local_id;
 
         match ret {
             Ok(v) => v,
@@ -351,19 +879,50 @@ impl<'a> FunctionBuilder<'a> {
         }
     }
 
-    pub fn build(mut self, blk: &ast::Block, arg_names: Vec<String>) -> Result<usize, CodegenError> {
+    pub fn build(self, blk: &ast::Block, arg_names: Vec<String>) -> Result<usize, CodegenError> {
+        let (fn_id, upvalues) = self.build_closure(blk, arg_names, false)?;
+        if !upvalues.is_empty() {
+            return Err("Unexpected upvalues in a top-level function".into());
+        }
+        Ok(fn_id)
+    }
+
+    /// Builds the function, returning its id and where the enclosing
+    /// function finds the upvalue cells it captures.
+    ///
+    /// Extra arguments of a vararg function are passed right after
+    /// the parameters, followed by the upvalue cells.
+    pub fn build_closure(mut self, blk: &ast::Block, arg_names: Vec<String>, is_vararg: bool) -> Result<(usize, Vec<VarLocation>), CodegenError> {
         self.closure_escaped_vars = blk.get_closure_escaped_vars().into_iter().collect();
         println!("Locals escaped to closures: {:?}", self.closure_escaped_vars);
 
         println!("{:?}", blk);
 
+        let n_params = arg_names.len();
+        let n_fixed_args = if is_vararg {
+            self.vararg_index = Some(n_params);
+            n_params + 1
+        } else {
+            n_params
+        };
+        self.module.set_upvalue_base(n_fixed_args);
         self.build_args_load(arg_names)?;
 
-        blk.unrestricted_generate_code(&mut self)?;
-        self.get_current_bb().opcodes.push(OpCode::LoadNull);
+        let source_name = self.module.get_source_name();
+        blk.unrestricted_generate_code(&mut self).map_err(|e| e.with_file(source_name))?;
+        self.check_pending_gotos().map_err(|e| e.with_file(source_name))?;
+        self.write_multi_value_create(0, false)?;
         self.get_current_bb().opcodes.push(OpCode::Return);
 
-        let n_locals = self.next_local_id;
+        let upvalues = self.module.take_upvalues();
+        if n_fixed_args + upvalues.len() > MAX_ARGS {
+            return Err("Too many parameters and upvalues".into());
+        }
+
+        let n_locals = self.n_local_slots;
+        if n_locals > MAX_LOCALS {
+            return Err("Too many local variables".into());
+        }
         self.basic_blocks[0].opcodes = vec! [
             OpCode::InitLocal(n_locals),
             OpCode::Branch(1)
@@ -376,13 +935,14 @@ impl<'a> FunctionBuilder<'a> {
 
         let mut functions = self.module.functions.borrow_mut();
         functions.push(f);
-        Ok(functions.len() - 1)
+        Ok((functions.len() - 1, upvalues))
     }
 }
 
 impl<'a> Drop for FunctionBuilder<'a> {
     fn drop(&mut self) {
         self.module.pop_scope();
+        self.module.upvalue_lists.borrow_mut().pop();
     }
 }
 
@@ -395,6 +955,13 @@ impl VarLocation {
                 );
                 Ok(())
             },
+            VarLocation::Cell(_) | VarLocation::Upvalue(_) => {
+                fb.get_current_bb().opcodes.push(OpCode::LoadString("__get__".into()));
+                fb.get_current_bb().opcodes.push(OpCode::LoadNull);
+                self.build_get_cell(fb)?;
+                fb.get_current_bb().opcodes.push(OpCode::CallField(0));
+                Ok(())
+            },
             VarLocation::This(ref key) => {
                 fb.get_current_bb().opcodes.extend(vec! [
                     OpCode::LoadString(key.clone()),
@@ -414,6 +981,14 @@ impl VarLocation {
                 );
                 Ok(())
             },
+            VarLocation::Cell(_) | VarLocation::Upvalue(_) => {
+                fb.get_current_bb().opcodes.push(OpCode::LoadString("__set__".into()));
+                fb.get_current_bb().opcodes.push(OpCode::LoadNull);
+                self.build_get_cell(fb)?;
+                fb.get_current_bb().opcodes.push(OpCode::CallField(1));
+                fb.get_current_bb().opcodes.push(OpCode::Pop);
+                Ok(())
+            },
             VarLocation::This(ref key) => {
                 fb.get_current_bb().opcodes.extend(vec! [
                     OpCode::LoadString(key.clone()),
@@ -424,4 +999,36 @@ impl VarLocation {
             }
         }
     }
+
+    /// Stores the value on top of the stack into a newly declared variable.
+    ///
+    /// Unlike `build_set`, this creates a fresh cell for captured locals
+    /// so that each declaration gets its own upvalue.
+    pub fn build_init(&self, fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
+        match *self {
+            VarLocation::Cell(id) => {
+                fb.write_cell_create()?;
+                fb.get_current_bb().opcodes.push(
+                    OpCode::SetLocal(id)
+                );
+                Ok(())
+            },
+            _ => self.build_set(fb)
+        }
+    }
+
+    /// Pushes the upvalue cell itself, without reading its value.
+    pub fn build_get_cell(&self, fb: &mut FunctionBuilder) -> Result<(), CodegenError> {
+        match *self {
+            VarLocation::Cell(id) => {
+                fb.get_current_bb().opcodes.push(OpCode::GetLocal(id));
+                Ok(())
+            },
+            VarLocation::Upvalue(id) => {
+                fb.get_current_bb().opcodes.push(OpCode::GetArgument(id));
+                Ok(())
+            },
+            _ => Err("Not an upvalue cell".into())
+        }
+    }
 }
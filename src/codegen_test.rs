@@ -1,11 +1,11 @@
 use std::panic::{catch_unwind, AssertUnwindSafe, resume_unwind};
 use ast;
 use codegen;
-use ast_codegen;
+use parser;
 use runtime;
 use serde_json;
 use test_programs;
-use hexagon::executor::{Executor, ExecutorImpl};
+use hexagon::executor::ExecutorImpl;
 use hexagon::errors::VMError;
 
 fn gen_and_run(ast: ast::Block) {
@@ -25,6 +25,10 @@ fn gen_and_run(ast: ast::Block) {
     }
 }
 
+fn gen_and_run_source(name: &str) {
+    gen_and_run(parser::parse(test_programs::get_source(name)).unwrap());
+}
+
 #[test]
 fn run_simple_local() {
     gen_and_run(serde_json::from_str(test_programs::get("simple_local")).unwrap());
@@ -35,14 +39,341 @@ fn run_function_def_call() {
     gen_and_run(serde_json::from_str(test_programs::get("function_def_call")).unwrap());
 }
 
-/*
 #[test]
 fn run_loops() {
     gen_and_run(serde_json::from_str(test_programs::get("loops")).unwrap());
 }
-*/
 
 #[test]
 fn run_arithmetic() {
     gen_and_run(serde_json::from_str(test_programs::get("arithmetic")).unwrap());
 }
+
+#[test]
+fn run_break() {
+    gen_and_run_source("break");
+}
+
+#[test]
+fn run_if_else() {
+    gen_and_run_source("if_else");
+}
+
+#[test]
+fn run_locals() {
+    gen_and_run_source("locals");
+}
+
+#[test]
+fn run_short_circuit() {
+    gen_and_run_source("short_circuit");
+}
+
+#[test]
+fn run_tables() {
+    gen_and_run_source("tables");
+}
+
+#[test]
+fn codegen_error_position() {
+    let ast = parser::parse("local a = 1\nlocal f = function()\n  goto done\nend").unwrap();
+    let module = codegen::ModuleBuilder::with_source_name("test.lua");
+    let fn_builder = codegen::FunctionBuilder::new(&module);
+    let err = fn_builder.build(&ast, Vec::new()).unwrap_err();
+
+    assert_eq!(err.file(), Some("test.lua"));
+    assert_eq!(err.pos(), Some(ast::Pos::new(3, 3)));
+    assert!(err.to_string().starts_with("CodegenError at test.lua:3:3: "));
+}
+
+#[test]
+fn run_closures() {
+    gen_and_run_source("closures");
+}
+
+#[test]
+fn run_upvalues() {
+    gen_and_run_source("upvalues");
+}
+
+#[test]
+fn run_multiple_assignment() {
+    gen_and_run_source("multiple_assignment");
+}
+
+#[test]
+fn run_multiple_value_returns() {
+    gen_and_run_source("multiple_value_returns");
+}
+
+#[test]
+fn run_multiple_returns() {
+    gen_and_run_source("multiple_returns");
+}
+
+#[test]
+fn run_varargs() {
+    gen_and_run_source("varargs");
+}
+
+#[test]
+fn varargs_outside_vararg_function() {
+    let ast = parser::parse("local f = function(a)\n  return ...\nend").unwrap();
+    let module = codegen::ModuleBuilder::new();
+    let fn_builder = codegen::FunctionBuilder::new(&module);
+    let err = fn_builder.build(&ast, Vec::new()).unwrap_err();
+
+    assert_eq!(err.pos(), Some(ast::Pos::new(2, 10)));
+}
+
+#[test]
+fn run_numeric_for() {
+    gen_and_run_source("numeric_for");
+}
+
+#[test]
+#[should_panic(expected = "'for' step is zero")]
+fn numeric_for_zero_step() {
+    gen_and_run(parser::parse("for i = 1, 10, 0 do end").unwrap());
+}
+
+#[test]
+fn run_generic_for() {
+    gen_and_run_source("generic_for");
+}
+
+#[test]
+fn run_localrec() {
+    gen_and_run_source("localrec");
+}
+
+#[test]
+fn run_fib() {
+    gen_and_run_source("fib");
+}
+
+#[test]
+fn run_goto() {
+    gen_and_run_source("goto");
+}
+
+#[test]
+fn goto_into_local_scope() {
+    let ast = parser::parse("do\n  goto l\n  local a = 1\n  ::l::\n  print(a)\nend").unwrap();
+    let module = codegen::ModuleBuilder::new();
+    let fn_builder = codegen::FunctionBuilder::new(&module);
+    let err = fn_builder.build(&ast, Vec::new()).unwrap_err();
+
+    assert_eq!(err.pos(), Some(ast::Pos::new(2, 3)));
+    assert!(err.to_string().contains("jumps into the scope of local 'a'"));
+}
+
+#[test]
+fn goto_duplicate_label() {
+    let ast = parser::parse("::l::\ndo\n  ::l::\nend\n::l::").unwrap();
+    let module = codegen::ModuleBuilder::new();
+    let fn_builder = codegen::FunctionBuilder::new(&module);
+    let err = fn_builder.build(&ast, Vec::new()).unwrap_err();
+
+    assert_eq!(err.pos(), Some(ast::Pos::new(3, 3)));
+    assert!(err.to_string().contains("label 'l' already defined"));
+}
+
+#[test]
+fn goto_label_in_nested_block() {
+    let ast = parser::parse("::a:: do ::a:: end").unwrap();
+    let module = codegen::ModuleBuilder::new();
+    let fn_builder = codegen::FunctionBuilder::new(&module);
+    let err = fn_builder.build(&ast, Vec::new()).unwrap_err();
+
+    assert!(err.to_string().contains("label 'a' already defined"));
+}
+
+#[test]
+fn break_outside_loop() {
+    let ast = parser::parse("local a = 1\nif a then\n  break\nend").unwrap();
+    let module = codegen::ModuleBuilder::new();
+    let fn_builder = codegen::FunctionBuilder::new(&module);
+    let err = fn_builder.build(&ast, Vec::new()).unwrap_err();
+
+    assert_eq!(err.pos(), Some(ast::Pos::new(3, 3)));
+    assert!(err.to_string().ends_with(": break outside a loop"));
+}
+
+#[test]
+fn run_methods() {
+    gen_and_run_source("methods");
+}
+
+#[test]
+fn run_table_keys() {
+    gen_and_run_source("table_keys");
+}
+
+#[test]
+#[should_panic(expected = "table index is nil")]
+fn table_nil_key() {
+    gen_and_run(parser::parse("local t = {}\nt[nil] = 1").unwrap());
+}
+
+#[test]
+#[should_panic(expected = "table index is NaN")]
+fn table_nan_key() {
+    gen_and_run(parser::parse("local t = {}\nt[0 / 0] = 1").unwrap());
+}
+
+#[test]
+fn run_table_array() {
+    gen_and_run_source("table_array");
+}
+
+#[test]
+fn run_length() {
+    gen_and_run_source("length");
+}
+
+#[test]
+#[should_panic(expected = "attempt to get length of a number value")]
+fn length_of_number() {
+    gen_and_run(parser::parse("local n = #1").unwrap());
+}
+
+#[test]
+fn run_metatables() {
+    gen_and_run_source("metatables");
+}
+
+#[test]
+#[should_panic(expected = "cannot change a protected metatable")]
+fn protected_metatable() {
+    gen_and_run(parser::parse("local t = setmetatable({}, {__metatable = false})\nsetmetatable(t, {})").unwrap());
+}
+
+#[test]
+fn run_operators() {
+    gen_and_run_source("operators");
+}
+
+#[test]
+#[should_panic(expected = "attempt to perform 'n//0'")]
+fn integer_division_by_zero() {
+    gen_and_run(parser::parse("local n = 0\nlocal x = 1 // n").unwrap());
+}
+
+#[test]
+#[should_panic(expected = "attempt to perform arithmetic on a nil value")]
+fn arithmetic_on_nil() {
+    gen_and_run(parser::parse("local a = {}\nreturn a.x + 1").unwrap());
+}
+
+#[test]
+#[should_panic(expected = "attempt to compare number with table")]
+fn compare_number_with_table() {
+    gen_and_run(parser::parse("return 1 < {}").unwrap());
+}
+
+#[test]
+fn run_metamethods() {
+    gen_and_run_source("metamethods");
+}
+
+#[test]
+fn assign_to_const_variable() {
+    let ast = parser::parse("local x <const> = 1\nx = 2").unwrap();
+    let module = codegen::ModuleBuilder::new();
+    let fn_builder = codegen::FunctionBuilder::new(&module);
+    let err = fn_builder.build(&ast, Vec::new()).unwrap_err();
+
+    assert_eq!(err.pos(), Some(ast::Pos::new(2, 1)));
+}
+
+#[test]
+#[should_panic(expected = "variable 'x' got a non-closable value")]
+fn close_non_closable_value() {
+    gen_and_run(parser::parse("local x <close> = {}").unwrap());
+}
+
+#[test]
+#[should_panic(expected = "attempt to call a table value")]
+fn call_table_without_call_metamethod() {
+    gen_and_run(parser::parse("local t = {}\nt()").unwrap());
+}
+
+#[test]
+#[should_panic(expected = "closed")]
+fn close_on_error() {
+    gen_and_run(parser::parse("\
+        local x <close> = setmetatable({}, {__close = function(self, err) assert(false, \"closed\") end})\n\
+        local y = 1 + nil\
+    ").unwrap());
+}
+
+#[test]
+#[should_panic(expected = "finalized")]
+fn finalize_at_exit() {
+    gen_and_run(parser::parse("local t = setmetatable({}, {__gc = function() assert(false, \"finalized\") end})").unwrap());
+}
+
+#[test]
+fn run_errors() {
+    gen_and_run_source("errors");
+}
+
+#[test]
+#[should_panic(expected = "(error object is a table value)")]
+fn uncaught_error_object() {
+    gen_and_run(parser::parse("error({})").unwrap());
+}
+
+#[test]
+fn run_coroutines() {
+    gen_and_run_source("coroutines");
+}
+
+#[test]
+#[should_panic(expected = "cannot resume dead coroutine")]
+fn call_dead_wrapped_coroutine() {
+    gen_and_run(parser::parse("local f = coroutine.wrap(function() end)\nf()\nf()").unwrap());
+}
+
+#[test]
+fn run_strings() {
+    gen_and_run_source("strings");
+}
+
+#[test]
+#[should_panic(expected = "attempt to index a number value")]
+fn index_number() {
+    gen_and_run(parser::parse("local n = 1\nlocal x = n.field").unwrap());
+}
+
+#[test]
+fn run_patterns() {
+    gen_and_run_source("patterns");
+}
+
+#[test]
+#[should_panic(expected = "pattern too complex")]
+fn backtracking_limit() {
+    gen_and_run(parser::parse("string.find(string.rep(\"a\", 3000), \"a-a-a-a-a-a-b\")").unwrap());
+}
+
+#[test]
+fn run_format() {
+    gen_and_run_source("format");
+}
+
+#[test]
+fn run_pack() {
+    gen_and_run_source("pack");
+}
+
+#[test]
+fn run_table_lib() {
+    gen_and_run_source("table_lib");
+}
+
+#[test]
+fn run_math() {
+    gen_and_run_source("math");
+}
@@ -0,0 +1,505 @@
+//! Coroutines.
+//!
+//! hexagon evaluates calls recursively on the native stack, so each
+//! coroutine runs on a thread of its own, which only exists while the
+//! coroutine is suspended or active. The executor is moved between the
+//! threads through channels, so that only the thread running it has it,
+//! and the others wait with a spare executor in its place.
+//!
+//! Each coroutine has a call stack of its own, which is swapped into the
+//! executor while the coroutine runs. The stack of a suspended coroutine
+//! is reported by its `get_children`, and the stack of its resumer by that
+//! of a running coroutine. A suspended coroutine that is no longer
+//! reachable is thus collected like any other object, and its thread is
+//! unwound on its own call stack, without the executor.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::panic::{catch_unwind, panic_any, resume_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use hexagon::object::Object;
+use hexagon::value::Value;
+use hexagon::executor::ExecutorImpl;
+use hexagon::call_stack::CallStack;
+use hexagon::builtin::array::Array;
+use hexagon::errors::VMError;
+use lua_types::MultiValue;
+use runtime::{close_tbc, error_value, raise_error};
+use gc::{self, GcMark};
+
+const STATE_KEY: &str = "@__luax_internal.coroutines";
+
+// Same as the call stack of the executor.
+const STACK_SIZE: usize = 2048;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Status {
+    Suspended,
+    Running,
+    // Active but not running, i.e. it resumed another coroutine.
+    Normal,
+    Dead
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Status::Suspended => "suspended",
+            Status::Running => "running",
+            Status::Normal => "normal",
+            Status::Dead => "dead"
+        }
+    }
+}
+
+/// The executor, on its way to the thread that runs it next.
+struct Baton(ExecutorImpl);
+
+// The executor is not `Send` because of the `Rc` counting the native
+// references to each of its objects, i.e. the handles returned by its
+// object pool. Waiting threads hold such handles on their stacks as well,
+// and they are only ever cloned or dropped:
+//
+// - by the thread holding the baton, while all others wait for it;
+// - by a thread unwinding when its coroutine is discarded, while the
+//   thread holding the baton waits for it to end.
+//
+// The channels and the join order these accesses, so that no two threads
+// update a count at the same time. Handles must thus not be kept anywhere
+// but on the stack of the thread that took them, e.g. in a thread-local or
+// in a value sent to another thread.
+unsafe impl Send for Baton {}
+
+/// What a coroutine is resumed for.
+enum Action {
+    Continue(Vec<Value>),
+    // Unwinds the coroutine, closing its to-be-closed variables.
+    Close
+}
+
+/// Messages to a coroutine thread.
+enum Resume {
+    Continue(Baton, Vec<Value>, Sender<(Transfer, Baton)>),
+    Close(Baton, Sender<(Transfer, Baton)>),
+    // Unwinds the coroutine on its call stack, without the executor and
+    // without closing its variables.
+    Discard(CallStack)
+}
+
+/// Messages to the resumer of a coroutine.
+enum Transfer {
+    Yield(Vec<Value>),
+    Return(Vec<Value>),
+    Error(Value),
+    Closed
+}
+
+// Unwinds a suspended coroutine that is being closed or discarded.
+struct CloseSignal {
+    close_variables: bool
+}
+
+thread_local! {
+    // Set on coroutine threads only.
+    static RESUMES: RefCell<Option<Receiver<Resume>>> = const { RefCell::new(None) };
+    static REPLY: RefCell<Option<Sender<(Transfer, Baton)>>> = const { RefCell::new(None) };
+
+    // Left in place of the executor while another thread runs it.
+    static SPARE: RefCell<Option<ExecutorImpl>> = const { RefCell::new(None) };
+}
+
+/// Takes the executor away from this thread.
+fn hand_over(executor: &mut ExecutorImpl) -> Baton {
+    let spare = SPARE.with(|v| v.borrow_mut().take()).unwrap_or_else(ExecutorImpl::new);
+    Baton(mem::replace(executor, spare))
+}
+
+/// Puts back the executor handed over by another thread.
+fn take_back(executor: &mut ExecutorImpl, baton: Baton) {
+    let spare = mem::replace(executor, baton.0);
+    SPARE.with(|v| *v.borrow_mut() = Some(spare));
+}
+
+/// The thread of a started coroutine.
+struct Thread {
+    resumes: Sender<Resume>,
+    handle: JoinHandle<()>
+}
+
+pub struct Coroutine {
+    function: Value,
+    // To-be-closed variables of the coroutine.
+    tbc_stack: Value,
+    status: Cell<Status>,
+    // The call stack of the coroutine while it is suspended, and that of
+    // its resumer while it runs.
+    stack: RefCell<Option<CallStack>>,
+    // None until the coroutine is first resumed, and once it is dead.
+    thread: RefCell<Option<Thread>>,
+    mark: GcMark
+}
+
+impl Object for Coroutine {
+    fn get_children(&self) -> Vec<usize> {
+        self.mark.mark();
+        let mut children: Vec<usize> = [self.function, self.tbc_stack].iter().filter_map(|v| match *v {
+            Value::Object(id) => Some(id),
+            _ => None
+        }).collect();
+        if let Some(ref stack) = *self.stack.borrow() {
+            children.extend(stack.collect_objects());
+        }
+        children
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
+    }
+
+    fn typename(&self) -> &str {
+        "thread"
+    }
+
+    fn to_bool(&self) -> bool {
+        true
+    }
+}
+
+impl Drop for Coroutine {
+    fn drop(&mut self) {
+        self.discard();
+    }
+}
+
+impl Coroutine {
+    pub fn status(&self) -> Status {
+        self.status.get()
+    }
+
+    pub fn gc_mark(&self) -> &GcMark {
+        &self.mark
+    }
+
+    /// Ends the thread of a suspended coroutine, without closing its
+    /// to-be-closed variables. This does not need the executor, so that
+    /// it can be done when the coroutine is collected.
+    fn discard(&self) {
+        if let Some(thread) = self.thread.borrow_mut().take() {
+            let stack = self.stack.borrow_mut().take().unwrap();
+            thread.resumes.send(Resume::Discard(stack)).unwrap();
+            let _ = thread.handle.join();
+        }
+        self.status.set(Status::Dead);
+    }
+}
+
+/// A function resuming a coroutine, as returned by `coroutine.wrap`.
+pub struct CoroutineFunction {
+    pub coroutine: Value
+}
+
+impl Object for CoroutineFunction {
+    fn get_children(&self) -> Vec<usize> {
+        match self.coroutine {
+            Value::Object(id) => vec! [ id ],
+            _ => Vec::new()
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
+    }
+
+    fn typename(&self) -> &str {
+        "function"
+    }
+
+    fn to_bool(&self) -> bool {
+        true
+    }
+
+    fn call(&self, executor: &mut ExecutorImpl) -> Value {
+        let args: Vec<Value> = {
+            let frame = executor.get_current_frame();
+            (0..frame.get_n_arguments()).map(|i| frame.must_get_argument(i)).collect()
+        };
+        match resume(executor, self.coroutine, args) {
+            Ok(values) => MultiValue::from_values(executor, values),
+            Err(err) => raise_error(executor, err)
+        }
+    }
+}
+
+/// Per-executor bookkeeping, stored as a static object.
+#[derive(Default)]
+struct Coroutines {
+    // Resumed coroutines, the running one last.
+    active: RefCell<Vec<usize>>,
+    // Coroutines with a thread, which are not kept alive by this list.
+    started: RefCell<Vec<(usize, GcMark)>>
+}
+
+impl Object for Coroutines {
+    fn get_children(&self) -> Vec<usize> {
+        self.active.borrow().clone()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
+    }
+}
+
+fn get_state(executor: &ExecutorImpl) -> &Coroutines {
+    match executor.get_object_pool().get_static_object(STATE_KEY) {
+        Some(&Value::Object(id)) => executor.get_object_pool().must_get_direct_typed::<Coroutines>(id),
+        _ => panic!("Coroutines not initialized")
+    }
+}
+
+fn get_coroutine(executor: &ExecutorImpl, id: usize) -> &Coroutine {
+    executor.get_object_pool().must_get_direct_typed::<Coroutine>(id)
+}
+
+pub fn init(executor: &mut ExecutorImpl) {
+    if executor.get_object_pool().get_static_object(STATE_KEY).is_none() {
+        executor.create_static_object(STATE_KEY, Box::new(Coroutines::default()));
+    }
+}
+
+/// Returns the id of a coroutine value, raising an error for other values.
+pub fn must_get_coroutine(executor: &ExecutorImpl, v: Value, fn_name: &str) -> usize {
+    match v {
+        Value::Object(id) if executor.get_object_pool().get_direct_typed::<Coroutine>(id).is_some() => id,
+        _ => panic_any(VMError::from(format!("bad argument #1 to '{}' (coroutine expected)", fn_name)))
+    }
+}
+
+pub fn create(executor: &mut ExecutorImpl, f: Value) -> Value {
+    let tbc_stack = Value::Object(executor.get_object_pool_mut().allocate(Box::new(Array::new())));
+    Value::Object(executor.get_object_pool_mut().allocate(Box::new(Coroutine {
+        function: f,
+        tbc_stack,
+        status: Cell::new(Status::Suspended),
+        stack: RefCell::new(None),
+        thread: RefCell::new(None),
+        mark: GcMark::new()
+    })))
+}
+
+/// The running coroutine, if any.
+pub fn running(executor: &ExecutorImpl) -> Option<usize> {
+    get_state(executor).active.borrow().last().cloned()
+}
+
+/// The stack of to-be-closed variables of the running coroutine, if any.
+pub fn running_tbc_stack(executor: &ExecutorImpl) -> Option<Value> {
+    running(executor).map(|id| get_coroutine(executor, id).tbc_stack)
+}
+
+/// Resumes a coroutine, returning the values it yields or returns, or the
+/// error it raises.
+pub fn resume(executor: &mut ExecutorImpl, co: Value, args: Vec<Value>) -> Result<Vec<Value>, Value> {
+    let co_id = must_get_coroutine(executor, co, "resume");
+    match get_coroutine(executor, co_id).status() {
+        Status::Suspended => {},
+        Status::Dead => return Err(new_string(executor, "cannot resume dead coroutine")),
+        _ => return Err(new_string(executor, "cannot resume non-suspended coroutine"))
+    }
+    transfer(executor, co_id, Action::Continue(args))
+}
+
+/// Closes a suspended coroutine, closing its pending to-be-closed
+/// variables. Returns the error raised by one of them, if any.
+pub fn close(executor: &mut ExecutorImpl, co: Value) -> Result<(), Value> {
+    let co_id = must_get_coroutine(executor, co, "close");
+    match get_coroutine(executor, co_id).status() {
+        Status::Suspended | Status::Dead => {},
+        status => panic_any(VMError::from(format!("cannot close a {} coroutine", status.name())))
+    }
+    if get_coroutine(executor, co_id).thread.borrow().is_none() {
+        get_coroutine(executor, co_id).status.set(Status::Dead);
+        return Ok(());
+    }
+    transfer(executor, co_id, Action::Close).map(|_| ())
+}
+
+/// Ends the threads of all suspended coroutines when a program ends. As in
+/// Lua, their pending to-be-closed variables are not closed.
+pub fn discard_all(executor: &mut ExecutorImpl) {
+    let started: Vec<(usize, GcMark)> = get_state(executor).started.borrow_mut().drain(..).collect();
+    for (id, mark) in started {
+        if mark.is_alive() {
+            get_coroutine(executor, id).discard();
+        }
+    }
+}
+
+fn new_string(executor: &mut ExecutorImpl, s: &str) -> Value {
+    Value::Object(executor.get_object_pool_mut().allocate(Box::new(s.to_string())))
+}
+
+/// Exchanges the call stack of the executor with the one kept by a
+/// coroutine.
+fn swap_stacks(executor: &mut ExecutorImpl, co_id: usize) {
+    let mut stack = get_coroutine(executor, co_id).stack.borrow_mut().take()
+        .unwrap_or_else(|| CallStack::new(STACK_SIZE));
+    executor.swap_call_stack(&mut stack);
+    *get_coroutine(executor, co_id).stack.borrow_mut() = Some(stack);
+}
+
+/// Runs a suspended coroutine until it yields, returns, raises an error or
+/// is closed.
+fn transfer(executor: &mut ExecutorImpl, co_id: usize, action: Action) -> Result<Vec<Value>, Value> {
+    if let Some(id) = running(executor) {
+        get_coroutine(executor, id).status.set(Status::Normal);
+    }
+    get_state(executor).active.borrow_mut().push(co_id);
+    get_coroutine(executor, co_id).status.set(Status::Running);
+
+    if get_coroutine(executor, co_id).thread.borrow().is_none() {
+        start(executor, co_id);
+    }
+    let resumes = get_coroutine(executor, co_id).thread.borrow().as_ref().unwrap().resumes.clone();
+    swap_stacks(executor, co_id);
+
+    // The coroutine thread runs until it hands the executor back.
+    let (reply_tx, reply_rx) = channel();
+    let baton = hand_over(executor);
+    resumes.send(match action {
+        Action::Continue(args) => Resume::Continue(baton, args, reply_tx),
+        Action::Close => Resume::Close(baton, reply_tx)
+    }).unwrap();
+    let (reply, baton) = reply_rx.recv().unwrap();
+    take_back(executor, baton);
+    swap_stacks(executor, co_id);
+
+    get_state(executor).active.borrow_mut().pop();
+    if let Some(id) = running(executor) {
+        get_coroutine(executor, id).status.set(Status::Running);
+    }
+    let co = get_coroutine(executor, co_id);
+    let result = match reply {
+        Transfer::Yield(values) => {
+            co.status.set(Status::Suspended);
+            return Ok(values);
+        },
+        Transfer::Return(values) => Ok(values),
+        Transfer::Error(err) => Err(err),
+        Transfer::Closed => Ok(Vec::new())
+    };
+    co.status.set(Status::Dead);
+    *co.stack.borrow_mut() = None;
+    if let Some(thread) = co.thread.borrow_mut().take() {
+        let _ = thread.handle.join();
+    }
+    result
+}
+
+fn start(executor: &mut ExecutorImpl, co_id: usize) {
+    let (resume_tx, resume_rx) = channel();
+    let f = get_coroutine(executor, co_id).function;
+    let tbc_stack = get_coroutine(executor, co_id).tbc_stack;
+    let cycle_counter = gc::cycle_counter();
+
+    let handle = thread::spawn(move || {
+        gc::set_cycle_counter(cycle_counter);
+        let (baton, args, reply) = match resume_rx.recv().unwrap() {
+            Resume::Continue(baton, args, reply) => (baton, args, reply),
+            _ => unreachable!()
+        };
+        RESUMES.with(|v| *v.borrow_mut() = Some(resume_rx));
+        REPLY.with(|v| *v.borrow_mut() = Some(reply));
+
+        let mut e = baton.0;
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            e.invoke(f, f, None, &args);
+            let ret = e.get_current_frame().pop_exec();
+            let mut values = Vec::new();
+            MultiValue::expand_into(&e, ret, &mut values);
+            values
+        }));
+        let msg = match result {
+            Ok(values) => Transfer::Return(values),
+            Err(payload) => match payload.downcast::<CloseSignal>() {
+                Ok(signal) => if signal.close_variables {
+                    close_variables(&mut e, tbc_stack, Value::Null, Transfer::Closed)
+                } else {
+                    // Discarded, with a spare executor in place of the
+                    // real one.
+                    return;
+                },
+                Err(payload) => {
+                    let err = error_value(&mut e, payload);
+                    close_variables(&mut e, tbc_stack, err, Transfer::Error(err))
+                }
+            }
+        };
+        REPLY.with(|v| v.borrow_mut().take().unwrap().send((msg, Baton(e))).unwrap());
+    });
+
+    *get_coroutine(executor, co_id).thread.borrow_mut() = Some(Thread {
+        resumes: resume_tx,
+        handle
+    });
+    let mark = get_coroutine(executor, co_id).mark.clone();
+    let mut started = get_state(executor).started.borrow_mut();
+    started.retain(|(_, mark)| mark.is_alive());
+    started.push((co_id, mark));
+}
+
+/// Closes the pending to-be-closed variables of a coroutine that ended with
+/// `err`. An error raised while closing replaces `result`.
+fn close_variables(e: &mut ExecutorImpl, tbc_stack: Value, err: Value, result: Transfer) -> Transfer {
+    match catch_unwind(AssertUnwindSafe(|| close_tbc(e, tbc_stack, 0, err))) {
+        Ok(()) => result,
+        Err(payload) => Transfer::Error(error_value(e, payload))
+    }
+}
+
+/// Suspends the running coroutine, returning the values passed to the
+/// `resume` that continues it.
+pub fn yield_values(executor: &mut ExecutorImpl, values: Vec<Value>) -> Vec<Value> {
+    if running(executor).is_none() {
+        panic_any(VMError::from("attempt to yield from outside a coroutine"));
+    }
+
+    let baton = hand_over(executor);
+    REPLY.with(|v| v.borrow_mut().take().unwrap().send((Transfer::Yield(values), baton)).unwrap());
+
+    match RESUMES.with(|v| v.borrow().as_ref().unwrap().recv()).unwrap() {
+        Resume::Continue(baton, args, reply) => {
+            take_back(executor, baton);
+            REPLY.with(|v| *v.borrow_mut() = Some(reply));
+            args
+        },
+        Resume::Close(baton, reply) => {
+            take_back(executor, baton);
+            REPLY.with(|v| *v.borrow_mut() = Some(reply));
+            resume_unwind(Box::new(CloseSignal {
+                close_variables: true
+            }))
+        },
+        Resume::Discard(mut stack) => {
+            // The executor keeps the spare, which unwinds on the stack of
+            // the coroutine.
+            executor.swap_call_stack(&mut stack);
+            resume_unwind(Box::new(CloseSignal {
+                close_variables: false
+            }))
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Finalizers and weak tables on top of hexagon's collector.
+//!
+//! hexagon marks objects with a depth-first traversal starting from the
+//! call stack and then from the children of the static root, last added
+//! first. `GcHook` is registered as the first static object after the
+//! builtin one, so its `get_children` runs once everything reachable from
+//! the program has been marked. Tables with a `__gc` metamethod that were
+//! not reached by then are moved to a pending list, and are kept alive
+//! along with everything they reference until their finalizers have run.
+//!
+//! Whether an object was reached is recorded by `GcMark`, which tables
+//! and closures update in their own `get_children`. Weak tables do not
+//! report their weak references as children, and drop the entries whose
+//! referents were not reached the next time they are accessed.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use hexagon::object::Object;
+use hexagon::object_info::TypedObjectHandle;
+use hexagon::value::Value;
+use hexagon::executor::ExecutorImpl;
+use lua_types::{get_metamethod, call_metamethod, is_function};
+
+const HOOK_KEY: &str = "@__luax_internal.gc";
+
+thread_local! {
+    // The collection cycle being marked, or the next one between
+    // collections. It is advanced by `GcHook` at the end of each mark phase.
+    //
+    // Coroutine threads share the counter of the thread that created them.
+    static CYCLE: RefCell<Arc<AtomicUsize>> = RefCell::new(Arc::new(AtomicUsize::new(1)));
+}
+
+fn current_cycle() -> usize {
+    CYCLE.with(|c| c.borrow().load(Ordering::Relaxed))
+}
+
+pub fn cycle_counter() -> Arc<AtomicUsize> {
+    CYCLE.with(|c| c.borrow().clone())
+}
+
+pub fn set_cycle_counter(counter: Arc<AtomicUsize>) {
+    CYCLE.with(|c| *c.borrow_mut() = counter);
+}
+
+/// The last collection cycle in which an object was reached.
+///
+/// Weak tables keep clones of the marks of the objects they refer to, so
+/// that they can tell dead references apart without accessing the pool.
+#[derive(Clone)]
+pub struct GcMark(Arc<AtomicUsize>);
+
+impl Default for GcMark {
+    fn default() -> Self {
+        GcMark::new()
+    }
+}
+
+impl GcMark {
+    /// New objects count as reached in the last cycle, so that they are
+    /// alive but not yet marked in the next one.
+    pub fn new() -> GcMark {
+        GcMark(Arc::new(AtomicUsize::new(current_cycle() - 1)))
+    }
+
+    pub fn mark(&self) {
+        self.0.store(current_cycle(), Ordering::Relaxed);
+    }
+
+    fn is_marked_in(&self, cycle: usize) -> bool {
+        self.0.load(Ordering::Relaxed) == cycle
+    }
+
+    /// Whether the object survived the last collection. Objects that were
+    /// not reached have been swept, and their ids may be reused.
+    pub fn is_alive(&self) -> bool {
+        self.0.load(Ordering::Relaxed) + 1 >= current_cycle()
+    }
+}
+
+/// Returns a number that changes whenever objects may have been swept.
+pub fn cycle_id() -> usize {
+    current_cycle()
+}
+
+/// Tracks the tables to be finalized.
+#[derive(Default)]
+pub struct GcHook {
+    finalizable: RefCell<Vec<(usize, GcMark)>>,
+    pending: RefCell<Vec<usize>>
+}
+
+impl Object for GcHook {
+    fn get_children(&self) -> Vec<usize> {
+        let cycle = current_cycle();
+        {
+            let mut finalizable = self.finalizable.borrow_mut();
+            let mut pending = self.pending.borrow_mut();
+            for &(id, ref mark) in finalizable.iter() {
+                if !mark.is_marked_in(cycle) {
+                    pending.push(id);
+                }
+            }
+            finalizable.retain(|(_, mark)| mark.is_marked_in(cycle));
+        }
+
+        // Objects only reached from here on are resurrected for their
+        // finalizers, and count as reached in the next cycle.
+        CYCLE.with(|c| c.borrow().store(cycle + 1, Ordering::Relaxed));
+
+        self.pending.borrow().clone()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
+    }
+}
+
+fn get_hook<'a>(executor: &ExecutorImpl) -> TypedObjectHandle<'a, GcHook> {
+    match executor.get_object_pool().get_static_object(HOOK_KEY) {
+        Some(&Value::Object(id)) => executor.get_object_pool().must_get_typed::<GcHook>(id),
+        _ => panic!("GC hook not initialized")
+    }
+}
+
+pub fn init(executor: &mut ExecutorImpl) {
+    if executor.get_object_pool().get_static_object(HOOK_KEY).is_none() {
+        executor.create_static_object(HOOK_KEY, Box::new(GcHook::default()));
+    }
+}
+
+/// Marks the table `id` for finalization by its `__gc` metamethod.
+pub fn register_finalizer(executor: &ExecutorImpl, id: usize, mark: &GcMark) {
+    let hook = get_hook(executor);
+    let mut finalizable = hook.finalizable.borrow_mut();
+    if finalizable.iter().all(|&(v, _)| v != id) {
+        finalizable.push((id, mark.clone()));
+    }
+}
+
+/// Calls the finalizers of the tables found unreachable so far.
+pub fn run_pending_finalizers(executor: &mut ExecutorImpl) {
+    loop {
+        // Other pending tables stay in the hook, and thus alive, while a
+        // finalizer runs.
+        let id = match get_hook(executor).pending.borrow_mut().pop() {
+            Some(id) => id,
+            None => break
+        };
+        let t = Value::Object(id);
+        let handler = get_metamethod(executor, t, "__gc");
+        if is_function(executor, &handler) {
+            call_metamethod(executor, handler, &[t]);
+        }
+    }
+}
+
+/// Runs a full collection cycle followed by the finalizers it uncovered.
+pub fn collect(executor: &mut ExecutorImpl) {
+    executor.gc();
+    run_pending_finalizers(executor);
+}
+
+/// Calls all remaining finalizers, as done when a program ends.
+pub fn run_all_finalizers(executor: &mut ExecutorImpl) {
+    {
+        let hook = get_hook(executor);
+        let mut finalizable = hook.finalizable.borrow_mut();
+        // Finalizers are called in the reverse order that they were marked.
+        let ids: Vec<usize> = finalizable.drain(..).map(|(id, _)| id).collect();
+        hook.pending.borrow_mut().splice(0..0, ids);
+    }
+    run_pending_finalizers(executor);
+}
@@ -0,0 +1,696 @@
+use std::error::Error;
+use std::fmt;
+use string_lib::bytes_to_string;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Name(String),
+    // One char per byte, as the runtime stores strings.
+    String(String),
+    Integer(i64),
+    Number(f64),
+
+    And,
+    Break,
+    Do,
+    Else,
+    Elseif,
+    End,
+    False,
+    For,
+    Function,
+    Goto,
+    If,
+    In,
+    Local,
+    Nil,
+    Not,
+    Or,
+    Repeat,
+    Return,
+    Then,
+    True,
+    Until,
+    While,
+
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    DoubleSlash,
+    Percent,
+    Caret,
+    Hash,
+    Ampersand,
+    Tilde,
+    Pipe,
+    ShiftLeft,
+    ShiftRight,
+    Eq,
+    Ne,
+    Le,
+    Ge,
+    Lt,
+    Gt,
+    Assign,
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    DoubleColon,
+    Semicolon,
+    Colon,
+    Comma,
+    Dot,
+    Concat,
+    Dots,
+
+    Eof
+}
+
+#[derive(Clone, Debug)]
+pub struct TokenInfo {
+    pub token: Token,
+    pub line: usize,
+    pub column: usize
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    desc: String
+}
+
+impl ParseError {
+    pub fn new<T: ToString>(line: usize, column: usize, desc: T) -> ParseError {
+        ParseError {
+            line,
+            column,
+            desc: desc.to_string()
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        self.desc.as_str()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ParseError at {}:{}: {}", self.line, self.column, self.desc)
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Token::Name(ref v) => return write!(f, "'{}'", v),
+            Token::String(ref v) => return write!(f, "'{}'", v),
+            Token::Integer(v) => return write!(f, "'{}'", v),
+            Token::Number(v) => return write!(f, "'{}'", v),
+            Token::And => "and",
+            Token::Break => "break",
+            Token::Do => "do",
+            Token::Else => "else",
+            Token::Elseif => "elseif",
+            Token::End => "end",
+            Token::False => "false",
+            Token::For => "for",
+            Token::Function => "function",
+            Token::Goto => "goto",
+            Token::If => "if",
+            Token::In => "in",
+            Token::Local => "local",
+            Token::Nil => "nil",
+            Token::Not => "not",
+            Token::Or => "or",
+            Token::Repeat => "repeat",
+            Token::Return => "return",
+            Token::Then => "then",
+            Token::True => "true",
+            Token::Until => "until",
+            Token::While => "while",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::DoubleSlash => "//",
+            Token::Percent => "%",
+            Token::Caret => "^",
+            Token::Hash => "#",
+            Token::Ampersand => "&",
+            Token::Tilde => "~",
+            Token::Pipe => "|",
+            Token::ShiftLeft => "<<",
+            Token::ShiftRight => ">>",
+            Token::Eq => "==",
+            Token::Ne => "~=",
+            Token::Le => "<=",
+            Token::Ge => ">=",
+            Token::Lt => "<",
+            Token::Gt => ">",
+            Token::Assign => "=",
+            Token::LeftParen => "(",
+            Token::RightParen => ")",
+            Token::LeftBrace => "{",
+            Token::RightBrace => "}",
+            Token::LeftBracket => "[",
+            Token::RightBracket => "]",
+            Token::DoubleColon => "::",
+            Token::Semicolon => ";",
+            Token::Colon => ":",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Concat => "..",
+            Token::Dots => "...",
+            Token::Eof => "<eof>"
+        };
+        write!(f, "'{}'", s)
+    }
+}
+
+fn keyword(name: &str) -> Option<Token> {
+    Some(match name {
+        "and" => Token::And,
+        "break" => Token::Break,
+        "do" => Token::Do,
+        "else" => Token::Else,
+        "elseif" => Token::Elseif,
+        "end" => Token::End,
+        "false" => Token::False,
+        "for" => Token::For,
+        "function" => Token::Function,
+        "goto" => Token::Goto,
+        "if" => Token::If,
+        "in" => Token::In,
+        "local" => Token::Local,
+        "nil" => Token::Nil,
+        "not" => Token::Not,
+        "or" => Token::Or,
+        "repeat" => Token::Repeat,
+        "return" => Token::Return,
+        "then" => Token::Then,
+        "true" => Token::True,
+        "until" => Token::Until,
+        "while" => Token::While,
+        _ => return None
+    })
+}
+
+pub struct Lexer<'a> {
+    source: &'a [u8],
+    pos: usize,
+    line: usize,
+    column: usize
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str) -> Lexer<'a> {
+        Lexer {
+            source: source.as_bytes(),
+            pos: 0,
+            line: 1,
+            column: 1
+        }
+    }
+
+    pub fn tokenize(mut self) -> Result<Vec<TokenInfo>, ParseError> {
+        let mut tokens: Vec<TokenInfo> = Vec::new();
+
+        // Skip the shebang line, if any.
+        if self.source.starts_with(b"#") {
+            while let Some(c) = self.peek(0) {
+                if c == b'\n' {
+                    break;
+                }
+                self.advance();
+            }
+        }
+
+        loop {
+            self.skip_whitespace_and_comments()?;
+            let (line, column) = (self.line, self.column);
+            let token = self.next_token()?;
+            let is_eof = token == Token::Eof;
+            tokens.push(TokenInfo {
+                token,
+                line,
+                column
+            });
+            if is_eof {
+                break;
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    fn error<T: ToString>(&self, desc: T) -> ParseError {
+        ParseError::new(self.line, self.column, desc)
+    }
+
+    fn peek(&self, n: usize) -> Option<u8> {
+        self.source.get(self.pos + n).cloned()
+    }
+
+    fn advance(&mut self) -> Option<u8> {
+        let c = self.peek(0)?;
+        self.pos += 1;
+        if c == b'\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_whitespace_and_comments(&mut self) -> Result<(), ParseError> {
+        loop {
+            match self.peek(0) {
+                Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') | Some(0x0b) | Some(0x0c) => {
+                    self.advance();
+                },
+                Some(b'-') if self.peek(1) == Some(b'-') => {
+                    self.advance();
+                    self.advance();
+                    if self.peek(0) == Some(b'[') {
+                        if let Some(level) = self.long_bracket_level() {
+                            self.read_long_bracket(level, "comment")?;
+                            continue;
+                        }
+                    }
+                    while let Some(c) = self.peek(0) {
+                        if c == b'\n' {
+                            break;
+                        }
+                        self.advance();
+                    }
+                },
+                _ => return Ok(())
+            }
+        }
+    }
+
+    /// Returns the level of the long bracket starting at the current
+    /// position (`[[` is level 0, `[==[` is level 2), if there is one.
+    fn long_bracket_level(&self) -> Option<usize> {
+        let mut level = 0;
+        while self.peek(1 + level) == Some(b'=') {
+            level += 1;
+        }
+        if self.peek(1 + level) == Some(b'[') {
+            Some(level)
+        } else {
+            None
+        }
+    }
+
+    fn read_long_bracket(&mut self, level: usize, what: &str) -> Result<String, ParseError> {
+        for _ in 0..level + 2 {
+            self.advance();
+        }
+
+        // A newline immediately following the opening bracket is skipped.
+        if self.peek(0) == Some(b'\r') {
+            self.advance();
+        }
+        if self.peek(0) == Some(b'\n') {
+            self.advance();
+        }
+
+        let mut content: Vec<u8> = Vec::new();
+        loop {
+            match self.peek(0) {
+                Some(b']') => {
+                    let mut n_eq = 0;
+                    while self.peek(1 + n_eq) == Some(b'=') {
+                        n_eq += 1;
+                    }
+                    if n_eq == level && self.peek(1 + n_eq) == Some(b']') {
+                        for _ in 0..level + 2 {
+                            self.advance();
+                        }
+                        return Ok(bytes_to_string(&content));
+                    }
+                    content.push(b']');
+                    self.advance();
+                },
+                Some(c) => {
+                    content.push(c);
+                    self.advance();
+                },
+                None => return Err(self.error(format!("unfinished long {}", what)))
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Token, ParseError> {
+        let c = match self.peek(0) {
+            Some(c) => c,
+            None => return Ok(Token::Eof)
+        };
+
+        if c.is_ascii_alphabetic() || c == b'_' {
+            return Ok(self.read_name());
+        }
+        if c.is_ascii_digit() || (c == b'.' && self.peek(1).map(|v| v.is_ascii_digit()).unwrap_or(false)) {
+            return self.read_number();
+        }
+
+        let token = match c {
+            b'"' | b'\'' => return self.read_string(c),
+            b'[' => {
+                if let Some(level) = self.long_bracket_level() {
+                    return Ok(Token::String(self.read_long_bracket(level, "string")?));
+                }
+                Token::LeftBracket
+            },
+            b'+' => Token::Plus,
+            b'-' => Token::Minus,
+            b'*' => Token::Star,
+            b'/' => if self.peek(1) == Some(b'/') {
+                self.advance();
+                Token::DoubleSlash
+            } else {
+                Token::Slash
+            },
+            b'%' => Token::Percent,
+            b'^' => Token::Caret,
+            b'#' => Token::Hash,
+            b'&' => Token::Ampersand,
+            b'~' => if self.peek(1) == Some(b'=') {
+                self.advance();
+                Token::Ne
+            } else {
+                Token::Tilde
+            },
+            b'|' => Token::Pipe,
+            b'<' => match self.peek(1) {
+                Some(b'<') => {
+                    self.advance();
+                    Token::ShiftLeft
+                },
+                Some(b'=') => {
+                    self.advance();
+                    Token::Le
+                },
+                _ => Token::Lt
+            },
+            b'>' => match self.peek(1) {
+                Some(b'>') => {
+                    self.advance();
+                    Token::ShiftRight
+                },
+                Some(b'=') => {
+                    self.advance();
+                    Token::Ge
+                },
+                _ => Token::Gt
+            },
+            b'=' => if self.peek(1) == Some(b'=') {
+                self.advance();
+                Token::Eq
+            } else {
+                Token::Assign
+            },
+            b'(' => Token::LeftParen,
+            b')' => Token::RightParen,
+            b'{' => Token::LeftBrace,
+            b'}' => Token::RightBrace,
+            b']' => Token::RightBracket,
+            b';' => Token::Semicolon,
+            b':' => if self.peek(1) == Some(b':') {
+                self.advance();
+                Token::DoubleColon
+            } else {
+                Token::Colon
+            },
+            b',' => Token::Comma,
+            b'.' => if self.peek(1) == Some(b'.') {
+                self.advance();
+                if self.peek(1) == Some(b'.') {
+                    self.advance();
+                    Token::Dots
+                } else {
+                    Token::Concat
+                }
+            } else {
+                Token::Dot
+            },
+            _ => return Err(self.error(format!("unexpected symbol '{}'", c as char)))
+        };
+        self.advance();
+        Ok(token)
+    }
+
+    fn read_name(&mut self) -> Token {
+        let begin = self.pos;
+        while let Some(c) = self.peek(0) {
+            if c.is_ascii_alphanumeric() || c == b'_' {
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        let name = String::from_utf8_lossy(&self.source[begin..self.pos]).into_owned();
+        match keyword(name.as_str()) {
+            Some(v) => v,
+            None => Token::Name(name)
+        }
+    }
+
+    fn read_number(&mut self) -> Result<Token, ParseError> {
+        let begin = self.pos;
+        let is_hex = self.peek(0) == Some(b'0') && (self.peek(1) == Some(b'x') || self.peek(1) == Some(b'X'));
+        if is_hex {
+            self.advance();
+            self.advance();
+        }
+
+        loop {
+            match self.peek(0) {
+                Some(c) if c.is_ascii_alphanumeric() || c == b'.' => {
+                    let is_exponent = if is_hex {
+                        c == b'p' || c == b'P'
+                    } else {
+                        c == b'e' || c == b'E'
+                    };
+                    self.advance();
+                    if is_exponent && (self.peek(0) == Some(b'+') || self.peek(0) == Some(b'-')) {
+                        self.advance();
+                    }
+                },
+                _ => break
+            }
+        }
+
+        let text = String::from_utf8_lossy(&self.source[begin..self.pos]).into_owned();
+        match parse_numeral(&text) {
+            Some(v) => Ok(v),
+            None => Err(self.error(format!("malformed number near '{}'", text)))
+        }
+    }
+
+    fn read_string(&mut self, delimiter: u8) -> Result<Token, ParseError> {
+        self.advance();
+
+        let mut content: Vec<u8> = Vec::new();
+        loop {
+            let c = match self.peek(0) {
+                Some(c) => c,
+                None => return Err(self.error("unfinished string"))
+            };
+            if c == delimiter {
+                self.advance();
+                break;
+            }
+            match c {
+                b'\n' | b'\r' => return Err(self.error("unfinished string")),
+                b'\\' => {
+                    self.advance();
+                    self.read_escape(&mut content)?;
+                },
+                _ => {
+                    content.push(c);
+                    self.advance();
+                }
+            }
+        }
+
+        Ok(Token::String(bytes_to_string(&content)))
+    }
+
+    fn read_escape(&mut self, out: &mut Vec<u8>) -> Result<(), ParseError> {
+        let c = match self.peek(0) {
+            Some(c) => c,
+            None => return Err(self.error("unfinished string"))
+        };
+        match c {
+            b'a' => out.push(0x07),
+            b'b' => out.push(0x08),
+            b'f' => out.push(0x0c),
+            b'n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b't' => out.push(b'\t'),
+            b'v' => out.push(0x0b),
+            b'\\' => out.push(b'\\'),
+            b'"' => out.push(b'"'),
+            b'\'' => out.push(b'\''),
+            b'\n' | b'\r' => {
+                // Any of "\n", "\r", "\n\r" and "\r\n" is a newline.
+                self.advance();
+                let next = self.peek(0);
+                if (next == Some(b'\n') || next == Some(b'\r')) && next != Some(c) {
+                    self.advance();
+                }
+                out.push(b'\n');
+                return Ok(());
+            },
+            b'x' => {
+                self.advance();
+                let mut v: u32 = 0;
+                for _ in 0..2 {
+                    let digit = self.peek(0).and_then(|c| (c as char).to_digit(16));
+                    match digit {
+                        Some(d) => v = v * 16 + d,
+                        None => return Err(self.error("hexadecimal digit expected"))
+                    }
+                    self.advance();
+                }
+                out.push(v as u8);
+                return Ok(());
+            },
+            b'z' => {
+                self.advance();
+                while let Some(c) = self.peek(0) {
+                    if c.is_ascii_whitespace() {
+                        self.advance();
+                    } else {
+                        break;
+                    }
+                }
+                return Ok(());
+            },
+            b'u' => {
+                self.advance();
+                if self.peek(0) != Some(b'{') {
+                    return Err(self.error("missing '{' in \\u{xxxx}"));
+                }
+                self.advance();
+                let mut v: u32 = 0;
+                while let Some(d) = self.peek(0).and_then(|c| (c as char).to_digit(16)) {
+                    v = v.checked_mul(16).and_then(|v| v.checked_add(d))
+                        .ok_or_else(|| self.error("UTF-8 value too large"))?;
+                    self.advance();
+                }
+                if self.peek(0) != Some(b'}') {
+                    return Err(self.error("missing '}' in \\u{xxxx}"));
+                }
+                self.advance();
+                match ::std::char::from_u32(v) {
+                    Some(ch) => {
+                        let mut buf = [0u8; 4];
+                        out.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+                    },
+                    None => return Err(self.error("UTF-8 value too large"))
+                }
+                return Ok(());
+            },
+            c if c.is_ascii_digit() => {
+                let mut v: u32 = 0;
+                for _ in 0..3 {
+                    match self.peek(0) {
+                        Some(c) if c.is_ascii_digit() => {
+                            v = v * 10 + (c - b'0') as u32;
+                            self.advance();
+                        },
+                        _ => break
+                    }
+                }
+                if v > 255 {
+                    return Err(self.error("decimal escape too large"));
+                }
+                out.push(v as u8);
+                return Ok(());
+            },
+            _ => return Err(self.error("invalid escape sequence"))
+        }
+        self.advance();
+        Ok(())
+    }
+}
+
+/// Reads an unsigned numeral as a `Token::Integer` or a `Token::Number`.
+/// Strings are converted to numbers with the same syntax.
+pub(crate) fn parse_numeral(text: &str) -> Option<Token> {
+    if text.starts_with("0x") || text.starts_with("0X") {
+        return parse_hex_integer(&text[2..])
+            .map(Token::Integer)
+            .or_else(|| parse_hex_number(&text[2..]).map(Token::Number));
+    }
+
+    // Rules out the signs, infinities and NaNs that Rust would accept.
+    if !text.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+        || !text.chars().all(|c| c.is_ascii_digit() || ".eE+-".contains(c)) {
+        return None;
+    }
+    // Decimal integers that do not fit are read as floats.
+    text.parse::<i64>().ok()
+        .map(Token::Integer)
+        .or_else(|| text.parse::<f64>().ok().map(Token::Number))
+}
+
+/// Parses the digits of a hexadecimal integer, which wraps around on
+/// overflow.
+fn parse_hex_integer(text: &str) -> Option<i64> {
+    if text.is_empty() {
+        return None;
+    }
+    let mut value: i64 = 0;
+    for c in text.chars() {
+        value = value.wrapping_mul(16).wrapping_add(c.to_digit(16)? as i64);
+    }
+    Some(value)
+}
+
+fn parse_hex_number(text: &str) -> Option<f64> {
+    let (mantissa, exponent) = match text.find(['p', 'P']) {
+        Some(pos) => (&text[..pos], Some(&text[pos + 1..])),
+        None => (text, None)
+    };
+
+    let mut value: f64 = 0.0;
+    let mut scale: i32 = 0;
+    let mut seen_dot = false;
+    let mut n_digits = 0;
+    for c in mantissa.chars() {
+        if c == '.' {
+            if seen_dot {
+                return None;
+            }
+            seen_dot = true;
+            continue;
+        }
+        let d = c.to_digit(16)?;
+        value = value * 16.0 + d as f64;
+        if seen_dot {
+            scale -= 4;
+        }
+        n_digits += 1;
+    }
+    if n_digits == 0 {
+        return None;
+    }
+
+    if let Some(exponent) = exponent {
+        scale += exponent.parse::<i32>().ok()?;
+    }
+
+    Some(value * 2f64.powi(scale))
+}
@@ -1,5 +1,3 @@
-#![feature(nll)]
-
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -11,8 +9,18 @@ pub use hexagon as vm;
 pub mod ast_codegen;
 pub mod ast;
 pub mod codegen;
+pub mod coroutine;
+pub mod gc;
+pub mod lexer;
+pub mod lua_pattern;
 pub mod lua_types;
+pub mod math_lib;
+pub mod parser;
 pub mod runtime;
+pub mod string_format;
+pub mod string_lib;
+pub mod string_pack;
+pub mod table_lib;
 
 #[cfg(test)]
 mod test_programs;
@@ -22,3 +30,6 @@ mod ast_test;
 
 #[cfg(test)]
 mod codegen_test;
+
+#[cfg(test)]
+mod parser_test;
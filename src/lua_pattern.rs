@@ -0,0 +1,399 @@
+//! Lua patterns, as used by `string.find`, `match`, `gmatch` and `gsub`.
+//!
+//! This follows the backtracking matcher of the reference implementation,
+//! working on bytes. On top of its recursion limit, the number of matching
+//! steps is bounded in proportion to the subject length, so that patterns
+//! like `a-a-a-a-b`, whose matching time grows with a high power of the
+//! subject length, raise an error instead of hanging the executor.
+
+use std::panic::panic_any;
+use hexagon::errors::VMError;
+
+pub const MAX_CAPTURES: usize = 32;
+
+/// Nesting limit of the recursive matcher.
+const MAX_DEPTH: usize = 200;
+
+/// Steps allowed for a subject of length 0, and per byte of the subject.
+/// A step is a recursive call or a repetition tried by a quantifier.
+const MAX_STEPS_BASE: usize = 1_000_000;
+const MAX_STEPS_PER_BYTE: usize = 256;
+
+const ESC: u8 = b'%';
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+/// A capture of the last match.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Capture {
+    /// The bytes `start..end` of the subject.
+    Bytes(usize, usize),
+    /// A position capture `()`, 0-based.
+    Position(usize)
+}
+
+#[derive(Copy, Clone)]
+enum CaptureLen {
+    Unfinished,
+    Position,
+    Closed(usize)
+}
+
+fn error(msg: &str) -> ! {
+    panic_any(VMError::from(msg.to_string()))
+}
+
+/// Whether a pattern has no special characters, and can be searched for
+/// as a plain string.
+pub fn is_plain(pattern: &[u8]) -> bool {
+    !pattern.iter().any(|c| SPECIALS.contains(c))
+}
+
+/// Returns the position of the first occurrence of `needle` in `haystack`
+/// at or after `from`.
+pub fn find_plain(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if needle.is_empty() {
+        return if from <= haystack.len() { Some(from) } else { None };
+    }
+    if haystack.len() < needle.len() {
+        return None;
+    }
+    (from..haystack.len() - needle.len() + 1).find(|&i| &haystack[i..i + needle.len()] == needle)
+}
+
+pub struct Matcher<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    level: usize,
+    captures: [(usize, CaptureLen); MAX_CAPTURES],
+    depth: usize,
+    steps_left: usize
+}
+
+impl<'a> Matcher<'a> {
+    /// Creates a matcher of `pat` against `src`. An anchor at the start
+    /// of the pattern must have been removed by the caller if it applies.
+    ///
+    /// The step limit is shared by all the matches tried with the same
+    /// matcher.
+    pub fn new(src: &'a [u8], pat: &'a [u8]) -> Matcher<'a> {
+        Matcher {
+            src,
+            pat,
+            level: 0,
+            captures: [(0, CaptureLen::Unfinished); MAX_CAPTURES],
+            depth: MAX_DEPTH,
+            steps_left: MAX_STEPS_BASE.saturating_add(src.len().saturating_mul(MAX_STEPS_PER_BYTE))
+        }
+    }
+
+    /// Tries to match the pattern at position `s` of the subject, returning
+    /// the end of the match.
+    pub fn match_at(&mut self, s: usize) -> Option<usize> {
+        self.level = 0;
+        self.depth = MAX_DEPTH;
+        self.do_match(s, 0)
+    }
+
+    /// The number of captures of the last match.
+    pub fn n_captures(&self) -> usize {
+        self.level
+    }
+
+    /// Returns the `i`-th capture of the last match `s..e`, which is the
+    /// whole match for a pattern without captures.
+    pub fn get_capture(&self, i: usize, s: usize, e: usize) -> Capture {
+        if i >= self.level {
+            if i != 0 {
+                error(&format!("invalid capture index %{}", i + 1));
+            }
+            return Capture::Bytes(s, e);
+        }
+        match self.captures[i] {
+            (_, CaptureLen::Unfinished) => error("unfinished capture"),
+            (start, CaptureLen::Position) => Capture::Position(start),
+            (start, CaptureLen::Closed(len)) => Capture::Bytes(start, start + len)
+        }
+    }
+
+    fn step(&mut self) {
+        if self.steps_left == 0 {
+            error("pattern too complex");
+        }
+        self.steps_left -= 1;
+    }
+
+    fn class_end(&self, mut p: usize) -> usize {
+        let c = self.pat[p];
+        p += 1;
+        if c == ESC {
+            if p >= self.pat.len() {
+                error("malformed pattern (ends with '%')");
+            }
+            return p + 1;
+        }
+        if c == b'[' {
+            if self.pat.get(p) == Some(&b'^') {
+                p += 1;
+            }
+            // The first character of a set may be a ']'.
+            loop {
+                if p >= self.pat.len() {
+                    error("malformed pattern (missing ']')");
+                }
+                let c = self.pat[p];
+                p += 1;
+                if c == ESC && p < self.pat.len() {
+                    p += 1;
+                }
+                if self.pat.get(p) == Some(&b']') {
+                    return p + 1;
+                }
+            }
+        }
+        p
+    }
+
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        if s >= self.src.len() {
+            return false;
+        }
+        let c = self.src[s];
+        match self.pat[p] {
+            b'.' => true,
+            ESC => match_class(c, self.pat[p + 1]),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c
+        }
+    }
+
+    // `p` is at the opening '[' of the set, and `ec` at its closing ']'.
+    fn match_bracket_class(&self, c: u8, mut p: usize, ec: usize) -> bool {
+        let mut sig = true;
+        if self.pat[p + 1] == b'^' {
+            sig = false;
+            p += 1;
+        }
+        p += 1;
+        while p < ec {
+            if self.pat[p] == ESC {
+                p += 1;
+                if match_class(c, self.pat[p]) {
+                    return sig;
+                }
+            } else if self.pat[p + 1] == b'-' && p + 2 < ec {
+                if self.pat[p] <= c && c <= self.pat[p + 2] {
+                    return sig;
+                }
+                p += 2;
+            } else if self.pat[p] == c {
+                return sig;
+            }
+            p += 1;
+        }
+        !sig
+    }
+
+    fn do_match(&mut self, s: usize, p: usize) -> Option<usize> {
+        if self.depth == 0 {
+            error("pattern too complex");
+        }
+        self.depth -= 1;
+        self.step();
+        let ret = self.match_here(s, p);
+        self.depth += 1;
+        ret
+    }
+
+    fn match_here(&mut self, mut s: usize, mut p: usize) -> Option<usize> {
+        loop {
+            if p == self.pat.len() {
+                return Some(s);
+            }
+            match self.pat[p] {
+                b'(' => return if self.pat.get(p + 1) == Some(&b')') {
+                    self.start_capture(s, p + 2, CaptureLen::Position)
+                } else {
+                    self.start_capture(s, p + 1, CaptureLen::Unfinished)
+                },
+                b')' => return self.end_capture(s, p + 1),
+                b'$' if p + 1 == self.pat.len() => {
+                    return if s == self.src.len() { Some(s) } else { None };
+                },
+                ESC if self.pat.get(p + 1) == Some(&b'b') => {
+                    s = self.match_balance(s, p + 2)?;
+                    p += 4;
+                    continue;
+                },
+                ESC if self.pat.get(p + 1) == Some(&b'f') => {
+                    p += 2;
+                    if self.pat.get(p) != Some(&b'[') {
+                        error("missing '[' after '%f' in pattern");
+                    }
+                    let ep = self.class_end(p);
+                    let prev = if s == 0 { 0 } else { self.src[s - 1] };
+                    let cur = self.src.get(s).cloned().unwrap_or(0);
+                    if !self.match_bracket_class(prev, p, ep - 1) && self.match_bracket_class(cur, p, ep - 1) {
+                        p = ep;
+                        continue;
+                    }
+                    return None;
+                },
+                ESC if self.pat.get(p + 1).map(|c| c.is_ascii_digit()).unwrap_or(false) => {
+                    s = self.match_capture(s, self.pat[p + 1])?;
+                    p += 2;
+                    continue;
+                },
+                _ => {}
+            }
+
+            let ep = self.class_end(p);
+            let quantifier = self.pat.get(ep).cloned();
+            if !self.single_match(s, p, ep) {
+                match quantifier {
+                    // Accepts an empty match.
+                    Some(b'*') | Some(b'?') | Some(b'-') => {
+                        p = ep + 1;
+                        continue;
+                    },
+                    _ => return None
+                }
+            }
+            match quantifier {
+                Some(b'?') => {
+                    if let Some(res) = self.do_match(s + 1, ep + 1) {
+                        return Some(res);
+                    }
+                    p = ep + 1;
+                },
+                Some(b'+') => return self.max_expand(s + 1, p, ep),
+                Some(b'*') => return self.max_expand(s, p, ep),
+                Some(b'-') => return self.min_expand(s, p, ep),
+                _ => {
+                    s += 1;
+                    p = ep;
+                }
+            }
+        }
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Option<usize> {
+        let mut i = 0;
+        while self.single_match(s + i, p, ep) {
+            self.step();
+            i += 1;
+        }
+        // Tries the longest repetition first.
+        loop {
+            if let Some(res) = self.do_match(s + i, ep + 1) {
+                return Some(res);
+            }
+            if i == 0 {
+                return None;
+            }
+            i -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Option<usize> {
+        loop {
+            if let Some(res) = self.do_match(s, ep + 1) {
+                return Some(res);
+            }
+            if !self.single_match(s, p, ep) {
+                return None;
+            }
+            s += 1;
+        }
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> Option<usize> {
+        if p + 1 >= self.pat.len() {
+            error("malformed pattern (missing arguments to '%b')");
+        }
+        let (open, close) = (self.pat[p], self.pat[p + 1]);
+        if self.src.get(s) != Some(&open) {
+            return None;
+        }
+        let mut depth = 1;
+        for i in s + 1..self.src.len() {
+            let c = self.src[i];
+            if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            } else if c == open {
+                depth += 1;
+            }
+        }
+        None
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: CaptureLen) -> Option<usize> {
+        if self.level >= MAX_CAPTURES {
+            error("too many captures");
+        }
+        self.captures[self.level] = (s, what);
+        self.level += 1;
+        let res = self.do_match(s, p);
+        if res.is_none() {
+            self.level -= 1;
+        }
+        res
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Option<usize> {
+        let l = match (0..self.level).rev().find(|&i| matches!(self.captures[i].1, CaptureLen::Unfinished)) {
+            Some(l) => l,
+            None => error("invalid pattern capture")
+        };
+        self.captures[l].1 = CaptureLen::Closed(s - self.captures[l].0);
+        let res = self.do_match(s, p);
+        if res.is_none() {
+            self.captures[l].1 = CaptureLen::Unfinished;
+        }
+        res
+    }
+
+    fn match_capture(&self, s: usize, l: u8) -> Option<usize> {
+        let (start, len) = match (l as usize).checked_sub(b'1' as usize) {
+            Some(i) if i < self.level => match self.captures[i] {
+                (start, CaptureLen::Closed(len)) => (start, len),
+                // A position never matches, as in the reference
+                // implementation.
+                (_, CaptureLen::Position) => return None,
+                (_, CaptureLen::Unfinished) => error(&format!("invalid capture index %{} in pattern", l as char))
+            },
+            _ => error(&format!("invalid capture index %{} in pattern", l as char))
+        };
+        if self.src.len() - s >= len && self.src[start..start + len] == self.src[s..s + len] {
+            Some(s + len)
+        } else {
+            None
+        }
+    }
+}
+
+/// Matches a character against a class like `%a`. Upper case classes are
+/// complemented, and other characters stand for themselves.
+fn match_class(c: u8, class: u8) -> bool {
+    let res = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        // Unlike `is_ascii_whitespace`, this includes the vertical tab.
+        b's' => c == b' ' || (b'\t'..=b'\r').contains(&c),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return class == c
+    };
+    if class.is_ascii_uppercase() {
+        !res
+    } else {
+        res
+    }
+}
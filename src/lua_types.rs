@@ -1,11 +1,110 @@
 use std::any::Any;
 use std::collections::HashMap;
+use std::panic::panic_any;
 use std::cell::{Cell, RefCell};
 use hexagon::object::Object;
-use hexagon::value::{Value, ValueContext};
+use hexagon::value::Value;
 use hexagon::executor::ExecutorImpl;
 use hexagon::errors::{VMError, FieldNotFoundError};
 use hexagon::builtin::array::Array;
+use hexagon::function::Function;
+use gc::{self, GcMark};
+use coroutine::{Coroutine, CoroutineFunction};
+use runtime;
+
+/// Key of the static object holding the metatable shared by all strings.
+pub const STRING_METATABLE_KEY: &str = "@__luax_internal.string_metatable";
+
+/// Returns the Lua type name of a value.
+pub fn type_name(executor: &ExecutorImpl, v: &Value) -> String {
+    match *v {
+        Value::Null => "nil".into(),
+        Value::Bool(_) => "boolean".into(),
+        Value::Int(_) | Value::Float(_) => "number".into(),
+        Value::Object(id) => {
+            let obj = executor.get_object_pool().get_direct(id);
+            if obj.as_any().downcast_ref::<Function>().is_some() {
+                "function".into()
+            } else {
+                obj.typename().to_string()
+            }
+        }
+    }
+}
+
+/// Whether a value is a Lua function, compiled or native.
+pub fn is_function(executor: &ExecutorImpl, v: &Value) -> bool {
+    match *v {
+        Value::Object(id) => {
+            let obj = executor.get_object_pool().get_direct(id).as_any();
+            obj.downcast_ref::<Closure>().is_some()
+                || obj.downcast_ref::<Function>().is_some()
+                || obj.downcast_ref::<CoroutineFunction>().is_some()
+        },
+        _ => false
+    }
+}
+
+/// Indexes a value, as `v[key]` does. Tables are indexed with `__get__`,
+/// and other values through the `__index` metamethod of their type.
+pub fn index_value(executor: &mut ExecutorImpl, v: Value, key: Value) -> Value {
+    if let Value::Object(id) = v {
+        if executor.get_object_pool().get_direct_typed::<Table>(id).is_some() {
+            executor.invoke(v, v, Some("__get__"), &[key]);
+            return executor.get_current_frame().pop_exec();
+        }
+    }
+
+    let handler = get_metamethod(executor, v, "__index");
+    if handler == Value::Null {
+        panic_any(VMError::from(format!("attempt to index a {} value", type_name(executor, &v))))
+    } else if is_function(executor, &handler) {
+        call_metamethod(executor, handler, &[v, key])
+    } else {
+        index_value(executor, handler, key)
+    }
+}
+
+/// Returns the metatable of a value. All strings share one metatable.
+pub fn get_metatable(executor: &ExecutorImpl, v: Value) -> Value {
+    match v {
+        Value::Object(id) => {
+            let pool = executor.get_object_pool();
+            if let Some(t) = pool.get_direct_typed::<Table>(id) {
+                t.get_metatable()
+            } else if pool.get_direct_typed::<String>(id).is_some() {
+                pool.get_static_object(STRING_METATABLE_KEY).cloned().unwrap_or(Value::Null)
+            } else {
+                Value::Null
+            }
+        },
+        _ => Value::Null
+    }
+}
+
+/// Looks up a metamethod of a value, which is nil for values without
+/// a metatable.
+pub fn get_metamethod(executor: &ExecutorImpl, v: Value, name: &str) -> Value {
+    match get_metatable(executor, v) {
+        Value::Object(id) => match executor.get_object_pool().get_direct_typed::<Table>(id) {
+            Some(mt) => mt.get_by_key(&TableKey::String(name.to_string())),
+            None => Value::Null
+        },
+        _ => Value::Null
+    }
+}
+
+/// Whether a value counts as true in a condition.
+pub fn is_truthy(v: Value) -> bool {
+    !matches!(v, Value::Null | Value::Bool(false))
+}
+
+/// Calls a metamethod, adjusting its results to one value.
+pub fn call_metamethod(executor: &mut ExecutorImpl, f: Value, args: &[Value]) -> Value {
+    executor.invoke(f, f, None, args);
+    let ret = executor.get_current_frame().pop_exec();
+    MultiValue::nth(executor, ret, 0)
+}
 
 pub struct Pair {
     pub left: Value,
@@ -24,135 +123,840 @@ impl Object for Pair {
         ret
     }
 
-    fn as_any(&self) -> &Any {
-        self as &Any
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
     }
 
-    fn as_any_mut(&mut self) -> &mut Any {
-        self as &mut Any
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
     }
 }
 
-fn f64_to_u64(v: f64) -> u64 {
-    if v.is_nan() {
-        panic!(VMError::from("NaN"));
+/// Zero or more values produced by a function call.
+///
+/// A call producing exactly one value returns it directly instead.
+pub struct MultiValue {
+    pub values: Vec<Value>
+}
+
+impl MultiValue {
+    /// Returns the result of a call producing `values`.
+    pub fn from_values(executor: &mut ExecutorImpl, values: Vec<Value>) -> Value {
+        if values.len() == 1 {
+            values[0]
+        } else {
+            Value::Object(executor.get_object_pool_mut().allocate(Box::new(MultiValue {
+                values
+            })))
+        }
+    }
+
+    /// Appends the values carried by `v` to `out`.
+    pub fn expand_into(executor: &ExecutorImpl, v: Value, out: &mut Vec<Value>) {
+        if let Value::Object(id) = v {
+            if let Some(mv) = executor.get_object_pool().get_direct_typed::<MultiValue>(id) {
+                out.extend(mv.values.iter().cloned());
+                return;
+            }
+        }
+        out.push(v);
+    }
+
+    /// Returns the `n`-th value carried by `v`, which is either a
+    /// `MultiValue` or a single plain value.
+    pub fn nth(executor: &ExecutorImpl, v: Value, n: usize) -> Value {
+        if let Value::Object(id) = v {
+            if let Some(mv) = executor.get_object_pool().get_direct_typed::<MultiValue>(id) {
+                return mv.values.get(n).cloned().unwrap_or(Value::Null);
+            }
+        }
+        if n == 0 {
+            v
+        } else {
+            Value::Null
+        }
     }
-    unsafe { ::std::mem::transmute::<f64, u64>(v) }
 }
 
-fn u64_to_f64(v: u64) -> f64 {
-    let result = unsafe { ::std::mem::transmute::<u64, f64>(v) };
-    if result.is_nan() {
-        panic!(VMError::from("NaN"));
+impl Object for MultiValue {
+    fn get_children(&self) -> Vec<usize> {
+        self.values.iter().filter_map(|v| match *v {
+            Value::Object(id) => Some(id),
+            _ => None
+        }).collect()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
     }
-    result
 }
 
-pub struct Table {
-    string_values: RefCell<HashMap<String, Value>>,
-    number_values: RefCell<HashMap<u64, Value>> // f64 keys actually
+/// A variable captured by closures.
+pub struct UpvalueCell {
+    pub value: Cell<Value>
 }
 
-macro_rules! do_val_insert {
-    ($t:expr, $k:expr, $v:expr) => ({
-        if $v == Value::Null {
-            $t.remove(&$k);
-        } else {
-            $t.insert($k, $v);
+impl Object for UpvalueCell {
+    fn get_children(&self) -> Vec<usize> {
+        match self.value.get() {
+            Value::Object(id) => vec! [ id ],
+            _ => Vec::new()
         }
-    })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
+    }
+
+    fn call_field(&self, name: &str, executor: &mut ExecutorImpl) -> Value {
+        match name {
+            "__get__" => self.value.get(),
+            "__set__" => {
+                self.value.set(executor.get_current_frame().must_get_argument(0));
+                Value::Null
+            },
+            _ => panic_any(VMError::from(FieldNotFoundError::from_field_name(name)))
+        }
+    }
+}
+
+/// An error raised by `error`, carrying an arbitrary Lua value.
+///
+/// It is raised as the payload of a `VMError`, like errors from the VM, so
+/// that `pcall` can tell the two apart. The message is what is reported if
+/// the error is not caught.
+pub struct LuaError {
+    pub value: Value,
+    pub message: String
+}
+
+impl Object for LuaError {
+    fn get_children(&self) -> Vec<usize> {
+        match self.value {
+            Value::Object(id) => vec! [ id ],
+            _ => Vec::new()
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
+    }
+
+    fn to_str(&self) -> &str {
+        self.message.as_str()
+    }
+}
+
+/// A Lua function value: a compiled function together with
+/// the upvalue cells of this instance.
+///
+/// Calling a closure adjusts the arguments to the number of parameters,
+/// packs the extra arguments of a vararg function into a multi-value
+/// and passes the upvalue cells after them.
+pub struct Closure {
+    pub function: Value,
+    pub n_params: usize,
+    pub is_vararg: bool,
+    pub upvalues: Vec<Value>,
+    pub gc_mark: GcMark
+}
+
+impl Object for Closure {
+    fn get_children(&self) -> Vec<usize> {
+        self.gc_mark.mark();
+
+        let mut ret: Vec<usize> = Vec::new();
+        if let Value::Object(id) = self.function {
+            ret.push(id);
+        }
+        for v in &self.upvalues {
+            if let Value::Object(id) = *v {
+                ret.push(id);
+            }
+        }
+        ret
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
+    }
+
+    fn typename(&self) -> &str {
+        "function"
+    }
+
+    fn to_bool(&self) -> bool {
+        true
+    }
+
+    fn call(&self, executor: &mut ExecutorImpl) -> Value {
+        let mut args: Vec<Value> = Vec::with_capacity(self.n_params + 1 + self.upvalues.len());
+        let mut varargs: Vec<Value> = Vec::new();
+        {
+            let frame = executor.get_current_frame();
+            for i in 0..self.n_params {
+                args.push(frame.get_argument(i).unwrap_or(Value::Null));
+            }
+            if self.is_vararg {
+                for i in self.n_params..frame.get_n_arguments() {
+                    varargs.push(frame.must_get_argument(i));
+                }
+            }
+        }
+        if self.is_vararg {
+            args.push(if varargs.len() == 1 {
+                varargs[0]
+            } else {
+                Value::Object(executor.get_object_pool_mut().allocate(Box::new(MultiValue {
+                    values: varargs
+                })))
+            });
+        }
+        args.extend(self.upvalues.iter().cloned());
+
+        executor.invoke(self.function, Value::Null, None, &args);
+        executor.get_current_frame().pop_exec()
+    }
+}
+
+/// A Lua table.
+///
+/// As in reference Lua, values of the integer keys `1..n` live in a
+/// contiguous array part, which may contain nils, and all other entries
+/// live in a hash part. The array part is resized whenever the hash part
+/// would grow, so that more than half of its slots are in use.
+pub struct Table {
+    array: RefCell<Vec<Value>>,
+    values: RefCell<HashMap<TableKey, Value>>,
+    metatable: Cell<Value>,
+
+    // Traversal order of the hash part used by `next`, rebuilt after new
+    // keys are added.
+    key_order: RefCell<Option<Vec<TableKey>>>,
+    last_next_index: Cell<usize>,
+
+    gc_mark: GcMark,
+    // Set from `__mode` by `setmetatable`.
+    weak_keys: Cell<bool>,
+    weak_values: Cell<bool>,
+    // Marks of the collectable objects this table refers to weakly, by id.
+    weak_refs: RefCell<HashMap<usize, GcMark>>,
+    weak_refs_checked_cycle: Cell<usize>
+}
+
+/// A table key, following Lua's raw equality.
+///
+/// Strings are compared by content and other objects by identity. Floats
+/// with an integral value are normalized to integers, so that `t[1]` and
+/// `t[1.0]` refer to the same entry.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum TableKey {
+    Bool(bool),
+    Int(i64),
+    Float(u64), // f64 bits, never NaN
+    String(String),
+    Object(usize)
+}
+
+// Integer keys in the array part range from 1 to 2^MAX_ARRAY_BITS.
+const MAX_ARRAY_BITS: usize = 26;
+
+impl Default for Table {
+    fn default() -> Self {
+        Table::new()
+    }
 }
 
 impl Table {
     pub fn new() -> Table {
         Table {
-            string_values: RefCell::new(HashMap::new()),
-            number_values: RefCell::new(HashMap::new())
+            array: RefCell::new(Vec::new()),
+            values: RefCell::new(HashMap::new()),
+            metatable: Cell::new(Value::Null),
+            key_order: RefCell::new(None),
+            last_next_index: Cell::new(0),
+            gc_mark: GcMark::new(),
+            weak_keys: Cell::new(false),
+            weak_values: Cell::new(false),
+            weak_refs: RefCell::new(HashMap::new()),
+            weak_refs_checked_cycle: Cell::new(0)
         }
     }
 
+    pub fn gc_mark(&self) -> &GcMark {
+        &self.gc_mark
+    }
+
+    pub fn is_weak(&self) -> bool {
+        self.weak_keys.get() || self.weak_values.get()
+    }
+
+    pub fn set_weak_mode(&self, executor: &ExecutorImpl, weak_keys: bool, weak_values: bool) {
+        self.clear_dead_weak_refs();
+        self.weak_keys.set(weak_keys);
+        self.weak_values.set(weak_values);
+        self.weak_refs.borrow_mut().clear();
+
+        let array = self.array.borrow();
+        for v in array.iter() {
+            self.track_weak_ref(executor, false, *v);
+        }
+        let values = self.values.borrow();
+        for (k, v) in values.iter() {
+            if let TableKey::Object(id) = *k {
+                self.track_weak_ref(executor, true, Value::Object(id));
+            }
+            self.track_weak_ref(executor, false, *v);
+        }
+    }
+
+    /// Remembers the mark of a table, closure or coroutine stored in a weak
+    /// part.
+    ///
+    /// Other objects, strings in particular, are never removed from weak
+    /// tables.
+    fn track_weak_ref(&self, executor: &ExecutorImpl, is_key: bool, v: Value) {
+        let weak = if is_key { self.weak_keys.get() } else { self.weak_values.get() };
+        let id = match v {
+            Value::Object(id) if weak => id,
+            _ => return
+        };
+        let obj = executor.get_object_pool().get_direct(id).as_any();
+        let mark = if let Some(t) = obj.downcast_ref::<Table>() {
+            t.gc_mark.clone()
+        } else if let Some(c) = obj.downcast_ref::<Closure>() {
+            c.gc_mark.clone()
+        } else if let Some(co) = obj.downcast_ref::<Coroutine>() {
+            co.gc_mark().clone()
+        } else {
+            return;
+        };
+        self.weak_refs.borrow_mut().insert(id, mark);
+    }
+
+    fn is_weak_ref(&self, weak: bool, id: usize) -> bool {
+        weak && self.weak_refs.borrow().contains_key(&id)
+    }
+
+    /// Removes the weak entries referring to collected objects.
+    ///
+    /// This must happen before any access to the table, as the ids of
+    /// collected objects may be reused.
+    fn clear_dead_weak_refs(&self) {
+        if !self.is_weak() || self.weak_refs_checked_cycle.get() == gc::cycle_id() {
+            return;
+        }
+        self.weak_refs_checked_cycle.set(gc::cycle_id());
+
+        let dead: Vec<usize> = {
+            let mut weak_refs = self.weak_refs.borrow_mut();
+            let dead = weak_refs.iter()
+                .filter(|&(_, mark)| !mark.is_alive())
+                .map(|(id, _)| *id)
+                .collect();
+            weak_refs.retain(|_, mark| mark.is_alive());
+            dead
+        };
+        if dead.is_empty() {
+            return;
+        }
+
+        let is_dead = |v: &Value| match *v {
+            Value::Object(id) => dead.contains(&id),
+            _ => false
+        };
+        if self.weak_values.get() {
+            for v in self.array.borrow_mut().iter_mut() {
+                if is_dead(v) {
+                    *v = Value::Null;
+                }
+            }
+        }
+        // Removed keys are skipped by `next`, so the traversal order is kept.
+        let (weak_keys, weak_values) = (self.weak_keys.get(), self.weak_values.get());
+        self.values.borrow_mut().retain(|k, v| {
+            let dead_key = match *k {
+                TableKey::Object(id) => weak_keys && dead.contains(&id),
+                _ => false
+            };
+            !dead_key && !(weak_values && is_dead(v))
+        });
+    }
+
     pub fn clear(&self) {
-        let mut string_values = self.string_values.borrow_mut();
-        let mut number_values = self.number_values.borrow_mut();
+        self.array.borrow_mut().clear();
+        self.values.borrow_mut().clear();
+        *self.key_order.borrow_mut() = None;
+    }
 
-        string_values.clear();
-        number_values.clear();
+    pub fn get_metatable(&self) -> Value {
+        self.metatable.get()
     }
 
-    pub fn len(&self) -> usize {
-        self.string_values.borrow().len() + self.number_values.borrow().len()
+    pub fn set_metatable(&self, mt: Value) {
+        self.metatable.set(mt);
     }
 
-    pub fn set(&self, executor: &mut ExecutorImpl, k: Value, ins_value: Value) {
-        match k {
-            Value::Int(v) => {
-                do_val_insert!(self.number_values.borrow_mut(), f64_to_u64(v as f64), ins_value);
-            },
-            Value::Float(v) => {
-                do_val_insert!(self.number_values.borrow_mut(), f64_to_u64(v), ins_value);
-            },
-            Value::Object(_) => {
-                let k = ValueContext::new(&k, executor.get_object_pool()).to_str().to_string();
-                do_val_insert!(self.string_values.borrow_mut(), k, ins_value);
+    /// Looks up a field of the metatable without invoking metamethods.
+    pub fn get_metamethod(&self, executor: &ExecutorImpl, name: &str) -> Value {
+        match self.metatable.get() {
+            Value::Object(id) => match executor.get_object_pool().get_direct_typed::<Table>(id) {
+                Some(mt) => mt.get_by_key(&TableKey::String(name.to_string())),
+                None => Value::Null
             },
-            _ => panic!(VMError::from("Table: Unsupported key"))
+            _ => Value::Null
         }
     }
 
-    pub fn get(&self, executor: &mut ExecutorImpl, k: Value) -> Value {
-        match k {
-            Value::Int(v) => {
-                *self.number_values.borrow_mut().get(&f64_to_u64(v as f64)).unwrap_or(&Value::Null)
-            },
+    /// Returns a border of the table, i.e. an index `n` such that `t[n]`
+    /// is not nil and `t[n + 1]` is nil, or 0 if `t[1]` is nil.
+    ///
+    /// As in reference Lua, any border may be returned if there are
+    /// several of them.
+    pub fn len(&self) -> usize {
+        self.clear_dead_weak_refs();
+        let array = self.array.borrow();
+        let n = array.len();
+
+        if n > 0 && array[n - 1] == Value::Null {
+            // Binary search inside the array part, keeping `array[i - 1]`
+            // non-nil (or `i == 0`) and `array[j - 1]` nil.
+            let (mut i, mut j) = (0, n);
+            while j - i > 1 {
+                let m = (i + j) / 2;
+                if array[m - 1] == Value::Null {
+                    j = m;
+                } else {
+                    i = m;
+                }
+            }
+            return i;
+        }
+
+        let values = self.values.borrow();
+        if values.is_empty() {
+            return n;
+        }
+
+        // Unbound search in the hash part.
+        let is_nil = |k: usize| !values.contains_key(&TableKey::Int(k as i64));
+        let (mut i, mut j) = (n, n + 1);
+        while !is_nil(j) {
+            i = j;
+            if j > i64::MAX as usize / 2 {
+                // Pathological case, resort to a linear search.
+                let mut k = 1;
+                while !is_nil(k) {
+                    k += 1;
+                }
+                return k - 1;
+            }
+            j *= 2;
+        }
+        while j - i > 1 {
+            let m = (i + j) / 2;
+            if is_nil(m) {
+                j = m;
+            } else {
+                i = m;
+            }
+        }
+        i
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the index into the array part for a numeric key, if any.
+    fn array_index(&self, k: &Value) -> Option<usize> {
+        let k = match *k {
+            Value::Int(v) => v as f64,
+            Value::Float(v) => v,
+            _ => return None
+        };
+        if k >= 1.0 && k <= self.array.borrow().len() as f64 && k.fract() == 0.0 {
+            Some(k as usize - 1)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `None` for keys that can never be present, i.e. nil and NaN.
+    fn make_key(executor: &ExecutorImpl, k: Value) -> Option<TableKey> {
+        Some(match k {
+            Value::Null => return None,
+            Value::Bool(v) => TableKey::Bool(v),
+            Value::Int(v) => TableKey::Int(v),
             Value::Float(v) => {
-                *self.number_values.borrow_mut().get(&f64_to_u64(v)).unwrap_or(&Value::Null)
-            },
-            Value::Object(_) => {
-                let k = ValueContext::new(&k, executor.get_object_pool());
-                *self.string_values.borrow_mut().get(k.to_str().as_ref()).unwrap_or(&Value::Null)
+                if v.is_nan() {
+                    return None;
+                }
+                match runtime::float_to_integer(v) {
+                    Some(v) => TableKey::Int(v),
+                    None => TableKey::Float(v.to_bits())
+                }
             },
-            _ => panic!(VMError::from("Table: Unsupported key"))
+            Value::Object(id) => match executor.get_object_pool().get_direct_typed::<String>(id) {
+                Some(v) => TableKey::String(v.clone()),
+                None => TableKey::Object(id)
+            }
+        })
+    }
+
+    fn key_to_value(executor: &mut ExecutorImpl, k: &TableKey) -> Value {
+        match *k {
+            TableKey::Bool(v) => Value::Bool(v),
+            TableKey::Int(v) => Value::Int(v),
+            TableKey::Float(v) => Value::Float(f64::from_bits(v)),
+            TableKey::String(ref v) => Value::Object(
+                executor.get_object_pool_mut().allocate(Box::new(v.clone()))
+            ),
+            TableKey::Object(id) => Value::Object(id)
+        }
+    }
+
+    fn get_by_key(&self, k: &TableKey) -> Value {
+        self.clear_dead_weak_refs();
+        if let TableKey::Int(i) = *k {
+            let array = self.array.borrow();
+            if i >= 1 && i as u64 <= array.len() as u64 {
+                return array[i as usize - 1];
+            }
+        }
+        *self.values.borrow().get(k).unwrap_or(&Value::Null)
+    }
+
+    pub fn set(&self, executor: &ExecutorImpl, k: Value, ins_value: Value) {
+        self.clear_dead_weak_refs();
+        if self.is_weak() {
+            self.track_weak_ref(executor, true, k);
+            self.track_weak_ref(executor, false, ins_value);
+        }
+
+        if let Some(i) = self.array_index(&k) {
+            self.array.borrow_mut()[i] = ins_value;
+            return;
+        }
+
+        let k = match Table::make_key(executor, k) {
+            Some(k) => k,
+            None => if k == Value::Null {
+                panic_any(VMError::from("table index is nil"))
+            } else {
+                panic_any(VMError::from("table index is NaN"))
+            }
+        };
+
+        if ins_value == Value::Null {
+            self.values.borrow_mut().remove(&k);
+            return;
+        }
+
+        // Appending right after the array part extends it directly.
+        if let TableKey::Int(i) = k {
+            if i as u64 == self.array.borrow().len() as u64 + 1 && i < 1 << MAX_ARRAY_BITS {
+                self.values.borrow_mut().remove(&k);
+                self.array.borrow_mut().push(ins_value);
+                self.migrate_to_array();
+                return;
+            }
+        }
+
+        let needs_rehash = {
+            let mut values = self.values.borrow_mut();
+            if let Some(v) = values.get_mut(&k) {
+                *v = ins_value;
+                return;
+            }
+            let full = values.len() == values.capacity();
+            values.insert(k.clone(), ins_value);
+            full
+        };
+        *self.key_order.borrow_mut() = None;
+
+        if needs_rehash {
+            if let TableKey::Int(_) = k {
+                self.rehash();
+            }
+        }
+    }
+
+    /// Moves entries following the array part from the hash part.
+    fn migrate_to_array(&self) {
+        let mut array = self.array.borrow_mut();
+        let mut values = self.values.borrow_mut();
+        if values.is_empty() {
+            return;
+        }
+
+        let mut migrated = false;
+        while let Some(v) = values.remove(&TableKey::Int(array.len() as i64 + 1)) {
+            array.push(v);
+            migrated = true;
+        }
+        if migrated {
+            *self.key_order.borrow_mut() = None;
+        }
+    }
+
+    /// Resizes the array part to the largest power of two such that more
+    /// than half of its slots are in use, moving entries between the two
+    /// parts accordingly.
+    fn rehash(&self) {
+        let mut array = self.array.borrow_mut();
+        let mut values = self.values.borrow_mut();
+
+        // nums[b]: number of integer keys k with 2^(b-1) < k <= 2^b.
+        let mut nums = [0usize; MAX_ARRAY_BITS + 1];
+        let mut total = 0;
+        let mut count_key = |k: i64| {
+            if (1..=1 << MAX_ARRAY_BITS).contains(&k) {
+                let b = 64 - ((k - 1) as u64).leading_zeros() as usize;
+                nums[b] += 1;
+                total += 1;
+            }
+        };
+        for (i, v) in array.iter().enumerate() {
+            if *v != Value::Null {
+                count_key(i as i64 + 1);
+            }
+        }
+        for k in values.keys() {
+            if let TableKey::Int(k) = *k {
+                count_key(k);
+            }
+        }
+
+        let mut new_size = 0;
+        let mut n_below = 0;
+        for (b, n) in nums.iter().enumerate() {
+            let size = 1usize << b;
+            if total <= size / 2 {
+                break;
+            }
+            n_below += n;
+            if n_below > size / 2 {
+                new_size = size;
+            }
+        }
+
+        if new_size < array.len() {
+            for (i, v) in array.drain(new_size..).enumerate() {
+                if v != Value::Null {
+                    values.insert(TableKey::Int((new_size + i) as i64 + 1), v);
+                }
+            }
+        } else {
+            for i in array.len()..new_size {
+                let v = values.remove(&TableKey::Int(i as i64 + 1)).unwrap_or(Value::Null);
+                array.push(v);
+            }
+        }
+        *self.key_order.borrow_mut() = None;
+    }
+
+    pub fn get(&self, executor: &ExecutorImpl, k: Value) -> Value {
+        self.clear_dead_weak_refs();
+        if let Some(i) = self.array_index(&k) {
+            return self.array.borrow()[i];
+        }
+        match Table::make_key(executor, k) {
+            Some(k) => self.get_by_key(&k),
+            None => Value::Null
+        }
+    }
+
+    /// Returns the entry following the key `k` in traversal order, or the
+    /// first one if `k` is nil. The array part is visited first.
+    pub fn next(&self, executor: &mut ExecutorImpl, k: Value) -> Option<(Value, Value)> {
+        self.clear_dead_weak_refs();
+        let array_len = self.array.borrow().len();
+        let start = match Table::make_key(executor, k) {
+            None if k == Value::Null => 0,
+            None => panic_any(VMError::from("invalid key to 'next'")),
+            Some(TableKey::Int(i)) if i >= 1 && i as u64 <= array_len as u64 => i as usize,
+            Some(k) => array_len + self.hash_position(&k) + 1
+        };
+
+        if start < array_len {
+            let array = self.array.borrow();
+            for (i, v) in array.iter().enumerate().skip(start) {
+                if *v != Value::Null {
+                    return Some((Value::Int(i as i64 + 1), *v));
+                }
+            }
+        }
+
+        let mut key_order = self.key_order.borrow_mut();
+        let key_order = key_order.get_or_insert_with(|| {
+            self.values.borrow().keys().cloned().collect()
+        });
+        let start = start.saturating_sub(array_len);
+
+        for (i, k) in key_order.iter().enumerate().skip(start) {
+            let v = self.get_by_key(k);
+            if v != Value::Null {
+                self.last_next_index.set(i);
+                return Some((Table::key_to_value(executor, k), v));
+            }
+        }
+        None
+    }
+
+    /// Position of a key in the traversal order of the hash part.
+    fn hash_position(&self, k: &TableKey) -> usize {
+        let mut key_order = self.key_order.borrow_mut();
+        let key_order = key_order.get_or_insert_with(|| {
+            self.values.borrow().keys().cloned().collect()
+        });
+
+        let last = self.last_next_index.get();
+        if last < key_order.len() && key_order[last] == *k {
+            return last;
+        }
+        match key_order.iter().position(|v| v == k) {
+            Some(i) => i,
+            None => panic_any(VMError::from("invalid key to 'next'"))
         }
     }
 }
 
 impl Object for Table {
     fn get_children(&self) -> Vec<usize> {
+        self.gc_mark.mark();
+        self.clear_dead_weak_refs();
+
+        let (weak_keys, weak_values) = (self.weak_keys.get(), self.weak_values.get());
         let mut ret: Vec<usize> = Vec::new();
-        for (_, v) in self.string_values.borrow().iter() {
+        if let Value::Object(id) = self.metatable.get() {
+            ret.push(id);
+        }
+        for v in self.array.borrow().iter() {
             if let Value::Object(id) = *v {
-                ret.push(id);
+                if !self.is_weak_ref(weak_values, id) {
+                    ret.push(id);
+                }
             }
         }
-        for (_, v) in self.number_values.borrow().iter() {
+        for (k, v) in self.values.borrow().iter() {
+            if let TableKey::Object(id) = *k {
+                if !self.is_weak_ref(weak_keys, id) {
+                    ret.push(id);
+                }
+            }
             if let Value::Object(id) = *v {
-                ret.push(id);
+                if !self.is_weak_ref(weak_values, id) {
+                    ret.push(id);
+                }
             }
         }
         ret
     }
 
-    fn as_any(&self) -> &Any {
-        self as &Any
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
     }
 
-    fn as_any_mut(&mut self) -> &mut Any {
-        self as &mut Any
+    fn typename(&self) -> &str {
+        "table"
+    }
+
+    fn to_bool(&self) -> bool {
+        true
+    }
+
+    // Callers pass the table itself as `this`.
+    fn call(&self, executor: &mut ExecutorImpl) -> Value {
+        let handler = self.get_metamethod(executor, "__call");
+        if handler == Value::Null {
+            panic_any(VMError::from("attempt to call a table value"));
+        }
+
+        let args: Vec<Value> = {
+            let frame = executor.get_current_frame();
+            ::std::iter::once(frame.get_this())
+                .chain((0..frame.get_n_arguments()).map(|i| frame.must_get_argument(i)))
+                .collect()
+        };
+        executor.invoke(handler, handler, None, &args);
+        executor.get_current_frame().pop_exec()
     }
 
     fn call_field(&self, name: &str, executor: &mut ExecutorImpl) -> Value {
         match name {
             "__get__" => {
                 let key = executor.get_current_frame().must_get_argument(0);
-                self.get(executor, key)
+                let v = self.get(executor, key);
+                if v != Value::Null || self.metatable.get() == Value::Null {
+                    return v;
+                }
+
+                let handler = self.get_metamethod(executor, "__index");
+                if handler == Value::Null {
+                    Value::Null
+                } else if is_function(executor, &handler) {
+                    let this = executor.get_current_frame().get_this();
+                    call_metamethod(executor, handler, &[this, key])
+                } else {
+                    index_value(executor, handler, key)
+                }
             },
             "__set__" => {
                 let key = executor.get_current_frame().must_get_argument(0);
                 let value = executor.get_current_frame().must_get_argument(1);
-                self.set(executor, key, value);
+
+                let handler = if self.metatable.get() == Value::Null || self.get(executor, key) != Value::Null {
+                    Value::Null
+                } else {
+                    self.get_metamethod(executor, "__newindex")
+                };
+                if handler == Value::Null {
+                    self.set(executor, key, value);
+                } else if is_function(executor, &handler) {
+                    let this = executor.get_current_frame().get_this();
+                    call_metamethod(executor, handler, &[this, key, value]);
+                } else {
+                    executor.invoke(handler, handler, Some("__set__"), &[key, value]);
+                    executor.get_current_frame().pop_exec();
+                }
                 Value::Null
             },
+            "__next__" => {
+                let key = executor.get_current_frame().must_get_argument(0);
+                match self.next(executor, key) {
+                    Some((k, v)) => Value::Object(executor.get_object_pool_mut().allocate(Box::new(MultiValue {
+                        values: vec! [ k, v ]
+                    }))),
+                    None => Value::Null
+                }
+            },
             "__len__" => {
+                let handler = self.get_metamethod(executor, "__len");
+                if handler != Value::Null {
+                    let this = executor.get_current_frame().get_this();
+                    return call_metamethod(executor, handler, &[this]);
+                }
                 Value::Int(self.len() as i64)
             },
             "__copy_from_array__" => {
@@ -173,7 +977,8 @@ impl Object for Table {
                             continue;
                         }
                     }
-                    deferred_items.push(*elem);
+                    // Only a trailing multi-value can reach here.
+                    MultiValue::expand_into(executor, *elem, &mut deferred_items);
                 }
 
                 for (k, v) in deferred_pairs {
@@ -185,7 +990,8 @@ impl Object for Table {
 
                 Value::Null
             },
-            _ => panic!(VMError::from(FieldNotFoundError::from_field_name(name)))
+            _ => panic_any(VMError::from(FieldNotFoundError::from_field_name(name)))
         }
     }
 }
+
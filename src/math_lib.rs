@@ -0,0 +1,305 @@
+//! The `math` library.
+//!
+//! Integers and floats are distinct subtypes, as for the operators. Floats
+//! with an exact integer value are still accepted where an integer is
+//! expected, and `math.tointeger` converts them.
+//!
+//! `math.random` uses the xoshiro256** generator of the reference
+//! implementation, so a given seed produces the same sequence.
+
+use std::any::Any;
+use std::cell::Cell;
+use std::panic::panic_any;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hexagon::object::Object;
+use hexagon::executor::ExecutorImpl;
+use hexagon::value::Value;
+use hexagon::errors::VMError;
+use lua_types::MultiValue;
+use runtime::{new_library, to_numeric};
+use string_lib::{arg_error, check_integer, check_number, get_arg, native, new_string, opt_integer, type_error};
+
+const STATE_KEY: &str = "@__luax_internal.random_state";
+
+/// State of the pseudo-random generator, stored as a static object.
+#[derive(Default)]
+struct RandomState {
+    s: Cell<[u64; 4]>
+}
+
+impl Object for RandomState {
+    fn get_children(&self) -> Vec<usize> {
+        Vec::new()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
+    }
+}
+
+impl RandomState {
+    fn next(&self) -> u64 {
+        let mut s = self.s.get();
+        let res = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        self.s.set(s);
+        res
+    }
+
+    fn seed(&self, n1: u64, n2: u64) {
+        // 0xff avoids a zero state.
+        self.s.set([n1, 0xff, n2, 0]);
+        // Discards the initial values to spread the seed.
+        for _ in 0..16 {
+            self.next();
+        }
+    }
+
+    /// Projects a random integer into `[0, n]`.
+    fn project(&self, mut ran: u64, n: u64) -> u64 {
+        if n & n.wrapping_add(1) == 0 {
+            return ran & n;
+        }
+        // Smallest 2^b - 1 not smaller than n.
+        let mut lim = n;
+        lim |= lim >> 1;
+        lim |= lim >> 2;
+        lim |= lim >> 4;
+        lim |= lim >> 8;
+        lim |= lim >> 16;
+        lim |= lim >> 32;
+        loop {
+            ran &= lim;
+            if ran <= n {
+                return ran;
+            }
+            ran = self.next();
+        }
+    }
+}
+
+fn get_state(executor: &ExecutorImpl) -> &RandomState {
+    match executor.get_object_pool().get_static_object(STATE_KEY) {
+        Some(&Value::Object(id)) => executor.get_object_pool().must_get_direct_typed::<RandomState>(id),
+        _ => panic!("Random state not initialized")
+    }
+}
+
+/// Seeds the generator from the clock, returning the seed.
+fn random_seed(e: &ExecutorImpl) -> (u64, u64) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seed = (now.as_secs(), now.subsec_nanos() as u64);
+    get_state(e).seed(seed.0, seed.1);
+    seed
+}
+
+/// Returns the integer value of a number, if it has one.
+fn as_integer(v: Value) -> Option<i64> {
+    match v {
+        Value::Int(v) => Some(v),
+        Value::Float(v) if v.fract() == 0.0 && v >= i64::MIN as f64 && v < i64::MAX as f64 => Some(v as i64),
+        _ => None
+    }
+}
+
+fn check_any(e: &ExecutorImpl, n: usize, fn_name: &str) {
+    if e.get_current_frame().get_argument(n).is_none() {
+        arg_error(n, fn_name, "value expected");
+    }
+}
+
+fn float_fn(e: &mut ExecutorImpl, name: &'static str, f: fn(f64) -> f64) -> Value {
+    native(e, move |e| Value::Float(f(check_number(e, 0, name))))
+}
+
+/// Implements `math.floor` and `math.ceil`, which give an integer when the
+/// result fits in one.
+fn round_fn(e: &mut ExecutorImpl, name: &'static str, f: fn(f64) -> f64) -> Value {
+    native(e, move |e| match get_arg(e, 0) {
+        Value::Int(v) => Value::Int(v),
+        _ => {
+            let v = Value::Float(f(check_number(e, 0, name)));
+            as_integer(v).map(Value::Int).unwrap_or(v)
+        }
+    })
+}
+
+/// Implements `math.min` and `math.max`, returning the argument for
+/// which `better` holds against all others.
+fn select_fn(e: &mut ExecutorImpl, name: &'static str, better: fn(f64, f64) -> bool) -> Value {
+    native(e, move |e| {
+        let n = e.get_current_frame().get_n_arguments();
+        let mut best = 0;
+        let mut best_value = check_number(e, 0, name);
+        for i in 1..n {
+            let v = check_number(e, i, name);
+            if better(v, best_value) {
+                best = i;
+                best_value = v;
+            }
+        }
+        get_arg(e, best)
+    })
+}
+
+fn lua_abs(e: &mut ExecutorImpl) -> Value {
+    match get_arg(e, 0) {
+        Value::Int(v) => Value::Int(v.wrapping_abs()),
+        _ => Value::Float(check_number(e, 0, "abs").abs())
+    }
+}
+
+fn lua_atan(e: &mut ExecutorImpl) -> Value {
+    let y = check_number(e, 0, "atan");
+    let x = match get_arg(e, 1) {
+        Value::Null => 1.0,
+        _ => check_number(e, 1, "atan")
+    };
+    Value::Float(y.atan2(x))
+}
+
+fn lua_log(e: &mut ExecutorImpl) -> Value {
+    let x = check_number(e, 0, "log");
+    let v = match get_arg(e, 1) {
+        Value::Null => x.ln(),
+        _ => {
+            let base = check_number(e, 1, "log");
+            if base == 2.0 {
+                x.log2()
+            } else if base == 10.0 {
+                x.log10()
+            } else {
+                x.ln() / base.ln()
+            }
+        }
+    };
+    Value::Float(v)
+}
+
+fn lua_fmod(e: &mut ExecutorImpl) -> Value {
+    if let (Value::Int(a), Value::Int(b)) = (get_arg(e, 0), get_arg(e, 1)) {
+        return match b {
+            0 => arg_error(1, "fmod", "zero"),
+            // Avoids overflowing with the minimum integer.
+            -1 => Value::Int(0),
+            _ => Value::Int(a % b)
+        };
+    }
+    let a = check_number(e, 0, "fmod");
+    let b = check_number(e, 1, "fmod");
+    Value::Float(a % b)
+}
+
+fn lua_modf(e: &mut ExecutorImpl) -> Value {
+    let x = check_number(e, 0, "modf");
+    let int_part = if x < 0.0 { x.ceil() } else { x.floor() };
+    let frac_part = if x == int_part { 0.0 } else { x - int_part };
+    MultiValue::from_values(e, vec! [ Value::Float(int_part), Value::Float(frac_part) ])
+}
+
+fn lua_tointeger(e: &mut ExecutorImpl) -> Value {
+    let v = get_arg(e, 0);
+    match to_numeric(e, v).and_then(as_integer) {
+        Some(n) => Value::Int(n),
+        None => {
+            check_any(e, 0, "tointeger");
+            Value::Null
+        }
+    }
+}
+
+fn lua_type(e: &mut ExecutorImpl) -> Value {
+    check_any(e, 0, "type");
+    let v = get_arg(e, 0);
+    let name = match v {
+        Value::Int(_) => "integer",
+        Value::Float(_) => "float",
+        _ => return Value::Null
+    };
+    new_string(e, name.to_string())
+}
+
+fn lua_ult(e: &mut ExecutorImpl) -> Value {
+    let a = check_integer(e, 0, "ult");
+    let b = check_integer(e, 1, "ult");
+    Value::Bool((a as u64) < (b as u64))
+}
+
+fn lua_random(e: &mut ExecutorImpl) -> Value {
+    let rv = get_state(e).next();
+    let (low, up) = match e.get_current_frame().get_n_arguments() {
+        // A float in [0, 1) made of the 53 high bits.
+        0 => return Value::Float((rv >> 11) as f64 * 0.5f64.powi(53)),
+        1 => match check_integer(e, 0, "random") {
+            0 => return Value::Int(rv as i64),
+            up => (1, up)
+        },
+        2 => (check_integer(e, 0, "random"), check_integer(e, 1, "random")),
+        _ => panic_any(VMError::from("wrong number of arguments"))
+    };
+    if low > up {
+        arg_error(0, "random", "interval is empty");
+    }
+    let p = get_state(e).project(rv, (up as u64).wrapping_sub(low as u64));
+    Value::Int(p.wrapping_add(low as u64) as i64)
+}
+
+fn lua_randomseed(e: &mut ExecutorImpl) -> Value {
+    let (n1, n2) = match get_arg(e, 0) {
+        Value::Null if e.get_current_frame().get_argument(0).is_none() => random_seed(e),
+        Value::Int(_) | Value::Float(_) => {
+            let n1 = check_integer(e, 0, "randomseed") as u64;
+            let n2 = opt_integer(e, 1, "randomseed", 0) as u64;
+            get_state(e).seed(n1, n2);
+            (n1, n2)
+        },
+        _ => type_error(e, 0, "randomseed", "number")
+    };
+    MultiValue::from_values(e, vec! [ Value::Int(n1 as i64), Value::Int(n2 as i64) ])
+}
+
+pub fn init(e: &mut ExecutorImpl) -> Value {
+    if e.get_object_pool().get_static_object(STATE_KEY).is_none() {
+        e.create_static_object(STATE_KEY, Box::new(RandomState::default()));
+    }
+    random_seed(e);
+
+    let fields = vec! [
+        ("floor", round_fn(e, "floor", f64::floor)),
+        ("ceil", round_fn(e, "ceil", f64::ceil)),
+        ("abs", native(e, lua_abs)),
+        ("sqrt", float_fn(e, "sqrt", f64::sqrt)),
+        ("sin", float_fn(e, "sin", f64::sin)),
+        ("cos", float_fn(e, "cos", f64::cos)),
+        ("tan", float_fn(e, "tan", f64::tan)),
+        ("asin", float_fn(e, "asin", f64::asin)),
+        ("acos", float_fn(e, "acos", f64::acos)),
+        ("atan", native(e, lua_atan)),
+        ("exp", float_fn(e, "exp", f64::exp)),
+        ("log", native(e, lua_log)),
+        ("fmod", native(e, lua_fmod)),
+        ("modf", native(e, lua_modf)),
+        ("min", select_fn(e, "min", |a, b| a < b)),
+        ("max", select_fn(e, "max", |a, b| a > b)),
+        ("huge", Value::Float(f64::INFINITY)),
+        ("pi", Value::Float(::std::f64::consts::PI)),
+        ("maxinteger", Value::Int(i64::MAX)),
+        ("mininteger", Value::Int(i64::MIN)),
+        ("tointeger", native(e, lua_tointeger)),
+        ("type", native(e, lua_type)),
+        ("ult", native(e, lua_ult)),
+        ("random", native(e, lua_random)),
+        ("randomseed", native(e, lua_randomseed))
+    ];
+    new_library(e, fields)
+}
@@ -0,0 +1,620 @@
+use ast::{Attrib, Block, Expr, ExprKind, Lhs, LhsKind, Pos, Stmt, StmtKind};
+use lexer::{Lexer, Token, TokenInfo};
+
+pub use lexer::ParseError;
+
+/// Parses Lua source code into an AST.
+pub fn parse(source: &str) -> Result<Block, ParseError> {
+    let tokens = Lexer::new(source).tokenize()?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0
+    };
+    let stmts = parser.parse_block()?;
+    parser.expect(Token::Eof)?;
+    Ok(Block::Block(stmts))
+}
+
+// (left, right) binding power of binary operators, as in the
+// reference implementation.
+fn binary_priority(token: &Token) -> Option<(u8, u8)> {
+    Some(match *token {
+        Token::Or => (1, 1),
+        Token::And => (2, 2),
+        Token::Lt | Token::Gt | Token::Le | Token::Ge | Token::Ne | Token::Eq => (3, 3),
+        Token::Pipe => (4, 4),
+        Token::Tilde => (5, 5),
+        Token::Ampersand => (6, 6),
+        Token::ShiftLeft | Token::ShiftRight => (7, 7),
+        Token::Concat => (9, 8),
+        Token::Plus | Token::Minus => (10, 10),
+        Token::Star | Token::Slash | Token::DoubleSlash | Token::Percent => (11, 11),
+        Token::Caret => (14, 13),
+        _ => return None
+    })
+}
+
+const UNARY_PRIORITY: u8 = 12;
+
+fn build_binary(op: &Token, left: Expr, right: Expr) -> Option<Expr> {
+    let pos = left.pos;
+    let (left, right) = (Box::new(left), Box::new(right));
+    Some(Expr::new(match *op {
+        Token::Or => ExprKind::Or(left, right),
+        Token::And => ExprKind::And(left, right),
+        Token::Lt => ExprKind::Lt(left, right),
+        Token::Gt => ExprKind::Gt(left, right),
+        Token::Le => ExprKind::Le(left, right),
+        Token::Ge => ExprKind::Ge(left, right),
+        Token::Ne => ExprKind::Ne(left, right),
+        Token::Eq => ExprKind::Eq(left, right),
+        Token::Concat => ExprKind::Concat(left, right),
+        Token::Plus => ExprKind::Add(left, right),
+        Token::Minus => ExprKind::Sub(left, right),
+        Token::Star => ExprKind::Mul(left, right),
+        Token::Slash => ExprKind::Div(left, right),
+        Token::DoubleSlash => ExprKind::Idiv(left, right),
+        Token::Percent => ExprKind::Mod(left, right),
+        Token::Caret => ExprKind::Pow(left, right),
+        _ => return None
+    }, pos))
+}
+
+struct Parser {
+    tokens: Vec<TokenInfo>,
+    pos: usize
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].token
+    }
+
+    fn peek_nth(&self, n: usize) -> &Token {
+        let id = ::std::cmp::min(self.pos + n, self.tokens.len() - 1);
+        &self.tokens[id].token
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].token.clone();
+        if token != Token::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn check(&mut self, token: Token) -> bool {
+        if *self.peek() == token {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error<T: ToString>(&self, desc: T) -> ParseError {
+        let current = &self.tokens[self.pos];
+        ParseError::new(current.line, current.column, desc)
+    }
+
+    fn error_near<T: ToString>(&self, desc: T) -> ParseError {
+        self.error(format!("{} near {}", desc.to_string(), self.peek()))
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), ParseError> {
+        if self.check(token.clone()) {
+            Ok(())
+        } else {
+            Err(self.error_near(format!("{} expected", token)))
+        }
+    }
+
+    fn expect_match(&mut self, token: Token, opening: Token, line: usize) -> Result<(), ParseError> {
+        if self.check(token.clone()) {
+            Ok(())
+        } else if self.tokens[self.pos].line == line {
+            Err(self.error_near(format!("{} expected", token)))
+        } else {
+            Err(self.error_near(format!("{} expected (to close {} at line {})", token, opening, line)))
+        }
+    }
+
+    fn expect_name(&mut self) -> Result<String, ParseError> {
+        match *self.peek() {
+            Token::Name(_) => {},
+            _ => return Err(self.error_near("<name> expected"))
+        }
+        match self.next() {
+            Token::Name(v) => Ok(v),
+            _ => unreachable!()
+        }
+    }
+
+    fn current_line(&self) -> usize {
+        self.tokens[self.pos].line
+    }
+
+    fn current_pos(&self) -> Option<Pos> {
+        let current = &self.tokens[self.pos];
+        Some(Pos::new(current.line, current.column))
+    }
+
+    fn block_follows(&self, with_until: bool) -> bool {
+        match *self.peek() {
+            Token::Else | Token::Elseif | Token::End | Token::Eof => true,
+            Token::Until => with_until,
+            _ => false
+        }
+    }
+
+    fn parse_block(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        let mut stmts: Vec<Stmt> = Vec::new();
+        while !self.block_follows(true) {
+            if *self.peek() == Token::Return {
+                stmts.push(self.parse_return()?);
+                break;
+            }
+            if let Some(stmt) = self.parse_statement()? {
+                stmts.push(stmt);
+            }
+        }
+        Ok(stmts)
+    }
+
+    fn parse_return(&mut self) -> Result<Stmt, ParseError> {
+        let pos = self.current_pos();
+        self.expect(Token::Return)?;
+        let values = if self.block_follows(true) || *self.peek() == Token::Semicolon {
+            Vec::new()
+        } else {
+            self.parse_expr_list()?
+        };
+        self.check(Token::Semicolon);
+        if !self.block_follows(true) {
+            return Err(self.error_near("<eof> expected"));
+        }
+        Ok(Stmt::new(StmtKind::Return(values), pos))
+    }
+
+    fn parse_statement(&mut self) -> Result<Option<Stmt>, ParseError> {
+        let line = self.current_line();
+        let pos = self.current_pos();
+        let kind = match *self.peek() {
+            Token::Semicolon => {
+                self.next();
+                return Ok(None);
+            },
+            Token::If => return Ok(Some(self.parse_if(line)?)),
+            Token::While => {
+                self.next();
+                let cond = self.parse_expr()?;
+                self.expect(Token::Do)?;
+                let body = self.parse_block()?;
+                self.expect_match(Token::End, Token::While, line)?;
+                StmtKind::While(cond, Block::Block(body))
+            },
+            Token::Do => {
+                self.next();
+                let body = self.parse_block()?;
+                self.expect_match(Token::End, Token::Do, line)?;
+                StmtKind::Do(body)
+            },
+            Token::For => return Ok(Some(self.parse_for(line)?)),
+            Token::Repeat => {
+                self.next();
+                let body = self.parse_block()?;
+                self.expect_match(Token::Until, Token::Repeat, line)?;
+                let cond = self.parse_expr()?;
+                StmtKind::Repeat(Block::Block(body), cond)
+            },
+            Token::Function => {
+                self.next();
+                let (target, is_method) = self.parse_function_name()?;
+                let mut f = self.parse_function_body(line, pos)?;
+                if is_method {
+                    if let ExprKind::Function(ref mut params, _) = f.kind {
+                        params.insert(0, Lhs::new(LhsKind::Id("self".into()), pos));
+                    }
+                }
+                StmtKind::Set(vec! [ target ], vec! [ f ])
+            },
+            Token::Local => {
+                self.next();
+                if self.check(Token::Function) {
+                    let name = self.expect_name()?;
+                    let f = self.parse_function_body(line, pos)?;
+                    StmtKind::Localrec(Lhs::new(LhsKind::Id(name), pos), f)
+                } else {
+                    return Ok(Some(self.parse_local(pos)?));
+                }
+            },
+            Token::DoubleColon => {
+                self.next();
+                let name = self.expect_name()?;
+                self.expect(Token::DoubleColon)?;
+                StmtKind::Label(name)
+            },
+            Token::Break => {
+                self.next();
+                StmtKind::Break
+            },
+            Token::Goto => {
+                self.next();
+                StmtKind::Goto(self.expect_name()?)
+            },
+            _ => return Ok(Some(self.parse_expr_statement()?))
+        };
+        Ok(Some(Stmt::new(kind, pos)))
+    }
+
+    fn parse_if(&mut self, line: usize) -> Result<Stmt, ParseError> {
+        let pos = self.current_pos();
+        let mut branches: Vec<(Expr, Block)> = Vec::new();
+        let mut else_branch: Option<Block> = None;
+
+        self.expect(Token::If)?;
+        loop {
+            let cond = self.parse_expr()?;
+            self.expect(Token::Then)?;
+            let body = self.parse_block()?;
+            branches.push((cond, Block::Block(body)));
+
+            if self.check(Token::Elseif) {
+                continue;
+            }
+            if self.check(Token::Else) {
+                else_branch = Some(Block::Block(self.parse_block()?));
+            }
+            self.expect_match(Token::End, Token::If, line)?;
+            break;
+        }
+
+        Ok(Stmt::new(StmtKind::If(branches, else_branch), pos))
+    }
+
+    fn parse_for(&mut self, line: usize) -> Result<Stmt, ParseError> {
+        let pos = self.current_pos();
+        self.expect(Token::For)?;
+        let first_pos = self.current_pos();
+        let first = Lhs::new(LhsKind::Id(self.expect_name()?), first_pos);
+
+        match *self.peek() {
+            Token::Assign => {
+                self.next();
+                let start = self.parse_expr()?;
+                self.expect(Token::Comma)?;
+                let end = self.parse_expr()?;
+                let step = if self.check(Token::Comma) {
+                    Some(self.parse_expr()?)
+                } else {
+                    None
+                };
+                self.expect(Token::Do)?;
+                let body = self.parse_block()?;
+                self.expect_match(Token::End, Token::For, line)?;
+                Ok(Stmt::new(StmtKind::Fornum(first, start, end, step, Block::Block(body)), pos))
+            },
+            Token::Comma | Token::In => {
+                let mut names = vec! [ first ];
+                while self.check(Token::Comma) {
+                    let name_pos = self.current_pos();
+                    names.push(Lhs::new(LhsKind::Id(self.expect_name()?), name_pos));
+                }
+                self.expect(Token::In)?;
+                let exprs = self.parse_expr_list()?;
+                self.expect(Token::Do)?;
+                let body = self.parse_block()?;
+                self.expect_match(Token::End, Token::For, line)?;
+                Ok(Stmt::new(StmtKind::Forin(names, exprs, Block::Block(body)), pos))
+            },
+            _ => Err(self.error_near("'=' or 'in' expected"))
+        }
+    }
+
+    /// Parses `a.b.c` or `a.b:c`, returning whether it names a method.
+    fn parse_function_name(&mut self) -> Result<(Lhs, bool), ParseError> {
+        let pos = self.current_pos();
+        let mut target = Expr::new(ExprKind::Id(self.expect_name()?), pos);
+        let mut is_method = false;
+        while *self.peek() == Token::Dot || *self.peek() == Token::Colon {
+            is_method = self.next() == Token::Colon;
+            let key_pos = self.current_pos();
+            let key = Expr::new(ExprKind::String(self.expect_name()?), key_pos);
+            target = Expr::new(ExprKind::Index(Box::new(target), Box::new(key)), pos);
+            if is_method {
+                break;
+            }
+        }
+        let target = match target.kind {
+            ExprKind::Id(name) => Lhs::new(LhsKind::Id(name), pos),
+            ExprKind::Index(target, key) => Lhs::new(LhsKind::Index(*target, *key), pos),
+            _ => unreachable!()
+        };
+        Ok((target, is_method))
+    }
+
+    fn parse_local(&mut self, pos: Option<Pos>) -> Result<Stmt, ParseError> {
+        let mut names: Vec<Lhs> = Vec::new();
+        let mut attribs: Vec<Option<Attrib>> = Vec::new();
+        loop {
+            let name_pos = self.current_pos();
+            names.push(Lhs::new(LhsKind::Id(self.expect_name()?), name_pos));
+            attribs.push(self.parse_attrib()?);
+            if !self.check(Token::Comma) {
+                break;
+            }
+        }
+        if attribs.iter().filter(|v| **v == Some(Attrib::Close)).count() > 1 {
+            return Err(self.error("multiple to-be-closed variables in local list"));
+        }
+        let exprs = if self.check(Token::Assign) {
+            self.parse_expr_list()?
+        } else {
+            Vec::new()
+        };
+        if attribs.iter().all(|v| v.is_none()) {
+            Ok(Stmt::new(StmtKind::Local(names, exprs), pos))
+        } else {
+            Ok(Stmt::new(StmtKind::LocalAttrib(names, attribs, exprs), pos))
+        }
+    }
+
+    /// Parses an optional `<const>` or `<close>` after a local name.
+    fn parse_attrib(&mut self) -> Result<Option<Attrib>, ParseError> {
+        if !self.check(Token::Lt) {
+            return Ok(None);
+        }
+        let attrib = match self.expect_name()?.as_str() {
+            "const" => Attrib::Const,
+            "close" => Attrib::Close,
+            name => return Err(self.error(format!("unknown attribute '{}'", name)))
+        };
+        self.expect(Token::Gt)?;
+        Ok(Some(attrib))
+    }
+
+    fn parse_expr_statement(&mut self) -> Result<Stmt, ParseError> {
+        let pos = self.current_pos();
+        let first = self.parse_suffixed_expr()?;
+
+        if *self.peek() == Token::Assign || *self.peek() == Token::Comma {
+            let mut targets = vec! [ self.expr_to_lhs(first)? ];
+            while self.check(Token::Comma) {
+                let target = self.parse_suffixed_expr()?;
+                targets.push(self.expr_to_lhs(target)?);
+            }
+            self.expect(Token::Assign)?;
+            let exprs = self.parse_expr_list()?;
+            return Ok(Stmt::new(StmtKind::Set(targets, exprs), pos));
+        }
+
+        match first.kind {
+            ExprKind::Call(target, args) => Ok(Stmt::new(StmtKind::Call(*target, args), pos)),
+            ExprKind::Invoke(target, method, args) => Ok(Stmt::new(StmtKind::Invoke(*target, method, args), pos)),
+            _ => Err(self.error_near("syntax error"))
+        }
+    }
+
+    fn expr_to_lhs(&self, expr: Expr) -> Result<Lhs, ParseError> {
+        match expr.kind {
+            ExprKind::Id(name) => Ok(Lhs::new(LhsKind::Id(name), expr.pos)),
+            ExprKind::Index(target, key) => Ok(Lhs::new(LhsKind::Index(*target, *key), expr.pos)),
+            _ => Err(self.error_near("syntax error"))
+        }
+    }
+
+    fn parse_function_body(&mut self, line: usize, pos: Option<Pos>) -> Result<Expr, ParseError> {
+        let mut params: Vec<Lhs> = Vec::new();
+
+        self.expect(Token::LeftParen)?;
+        if *self.peek() != Token::RightParen {
+            loop {
+                match *self.peek() {
+                    Token::Name(_) => {
+                        let name_pos = self.current_pos();
+                        params.push(Lhs::new(LhsKind::Id(self.expect_name()?), name_pos));
+                    },
+                    Token::Dots => {
+                        let dots_pos = self.current_pos();
+                        self.next();
+                        params.push(Lhs::new(LhsKind::Dots, dots_pos));
+                        break;
+                    },
+                    _ => return Err(self.error_near("<name> expected"))
+                }
+                if !self.check(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.expect(Token::RightParen)?;
+
+        let body = self.parse_block()?;
+        self.expect_match(Token::End, Token::Function, line)?;
+
+        Ok(Expr::new(ExprKind::Function(params, Block::Block(body)), pos))
+    }
+
+    fn parse_expr_list(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let mut exprs = vec! [ self.parse_expr()? ];
+        while self.check(Token::Comma) {
+            exprs.push(self.parse_expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, ParseError> {
+        self.parse_subexpr(0)
+    }
+
+    fn parse_subexpr(&mut self, limit: u8) -> Result<Expr, ParseError> {
+        let pos = self.current_pos();
+        let mut left = match *self.peek() {
+            Token::Not => {
+                self.next();
+                Expr::new(ExprKind::Not(Box::new(self.parse_subexpr(UNARY_PRIORITY)?)), pos)
+            },
+            Token::Minus => {
+                self.next();
+                Expr::new(ExprKind::Unm(Box::new(self.parse_subexpr(UNARY_PRIORITY)?)), pos)
+            },
+            Token::Hash => {
+                self.next();
+                Expr::new(ExprKind::Len(Box::new(self.parse_subexpr(UNARY_PRIORITY)?)), pos)
+            },
+            Token::Tilde => return Err(self.error("bitwise operators are not supported")),
+            _ => self.parse_simple_expr()?
+        };
+
+        while let Some((left_priority, right_priority)) = binary_priority(self.peek()) {
+            if left_priority <= limit {
+                break;
+            }
+            let op = self.next();
+            let right = self.parse_subexpr(right_priority)?;
+            left = match build_binary(&op, left, right) {
+                Some(v) => v,
+                None => return Err(self.error("bitwise operators are not supported"))
+            };
+        }
+
+        Ok(left)
+    }
+
+    fn parse_simple_expr(&mut self) -> Result<Expr, ParseError> {
+        let line = self.current_line();
+        let pos = self.current_pos();
+        let kind = match *self.peek() {
+            Token::Nil => ExprKind::Nil,
+            Token::True => ExprKind::Boolean(true),
+            Token::False => ExprKind::Boolean(false),
+            Token::Integer(v) => ExprKind::Integer(v),
+            Token::Number(v) => ExprKind::Number(v),
+            Token::String(ref s) => ExprKind::String(s.clone()),
+            Token::Dots => ExprKind::Dots,
+            Token::LeftBrace => return self.parse_table(),
+            Token::Function => {
+                self.next();
+                return self.parse_function_body(line, pos);
+            },
+            _ => return self.parse_suffixed_expr()
+        };
+        self.next();
+        Ok(Expr::new(kind, pos))
+    }
+
+    fn parse_primary_expr(&mut self) -> Result<Expr, ParseError> {
+        match *self.peek() {
+            Token::Name(_) => {
+                let pos = self.current_pos();
+                Ok(Expr::new(ExprKind::Id(self.expect_name()?), pos))
+            },
+            Token::LeftParen => {
+                let line = self.current_line();
+                let pos = self.current_pos();
+                self.next();
+                let expr = self.parse_expr()?;
+                self.expect_match(Token::RightParen, Token::LeftParen, line)?;
+                match expr.kind {
+                    ExprKind::Call(_, _) | ExprKind::Invoke(_, _, _) | ExprKind::Dots => {
+                        Ok(Expr::new(ExprKind::Paren(Box::new(expr)), pos))
+                    },
+                    _ => Ok(expr)
+                }
+            },
+            _ => Err(self.error_near("unexpected symbol"))
+        }
+    }
+
+    fn parse_suffixed_expr(&mut self) -> Result<Expr, ParseError> {
+        let pos = self.current_pos();
+        let mut expr = self.parse_primary_expr()?;
+        loop {
+            match *self.peek() {
+                Token::Dot => {
+                    self.next();
+                    let key_pos = self.current_pos();
+                    let key = Expr::new(ExprKind::String(self.expect_name()?), key_pos);
+                    expr = Expr::new(ExprKind::Index(Box::new(expr), Box::new(key)), pos);
+                },
+                Token::LeftBracket => {
+                    self.next();
+                    let key = self.parse_expr()?;
+                    self.expect(Token::RightBracket)?;
+                    expr = Expr::new(ExprKind::Index(Box::new(expr), Box::new(key)), pos);
+                },
+                Token::Colon => {
+                    self.next();
+                    let method = self.expect_name()?;
+                    let args = self.parse_call_args()?;
+                    expr = Expr::new(ExprKind::Invoke(Box::new(expr), method, args), pos);
+                },
+                Token::LeftParen | Token::LeftBrace | Token::String(_) => {
+                    let args = self.parse_call_args()?;
+                    expr = Expr::new(ExprKind::Call(Box::new(expr), args), pos);
+                },
+                _ => return Ok(expr)
+            }
+        }
+    }
+
+    fn parse_call_args(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let line = self.current_line();
+        let pos = self.current_pos();
+        match *self.peek() {
+            Token::String(ref s) => {
+                let s = s.clone();
+                self.next();
+                Ok(vec! [ Expr::new(ExprKind::String(s), pos) ])
+            },
+            Token::LeftBrace => Ok(vec! [ self.parse_table()? ]),
+            Token::LeftParen => {
+                self.next();
+                let args = if *self.peek() == Token::RightParen {
+                    Vec::new()
+                } else {
+                    self.parse_expr_list()?
+                };
+                self.expect_match(Token::RightParen, Token::LeftParen, line)?;
+                Ok(args)
+            },
+            _ => Err(self.error_near("function arguments expected"))
+        }
+    }
+
+    fn parse_table(&mut self) -> Result<Expr, ParseError> {
+        let line = self.current_line();
+        let pos = self.current_pos();
+        let mut elems: Vec<Expr> = Vec::new();
+
+        self.expect(Token::LeftBrace)?;
+        while *self.peek() != Token::RightBrace {
+            let elem_pos = self.current_pos();
+            let elem = match *self.peek() {
+                Token::LeftBracket => {
+                    self.next();
+                    let key = self.parse_expr()?;
+                    self.expect(Token::RightBracket)?;
+                    self.expect(Token::Assign)?;
+                    let value = self.parse_expr()?;
+                    Expr::new(ExprKind::Pair(Box::new(key), Box::new(value)), elem_pos)
+                },
+                Token::Name(_) if *self.peek_nth(1) == Token::Assign => {
+                    let key = Expr::new(ExprKind::String(self.expect_name()?), elem_pos);
+                    self.next();
+                    let value = self.parse_expr()?;
+                    Expr::new(ExprKind::Pair(Box::new(key), Box::new(value)), elem_pos)
+                },
+                _ => self.parse_expr()?
+            };
+            elems.push(elem);
+
+            if !self.check(Token::Comma) && !self.check(Token::Semicolon) {
+                break;
+            }
+        }
+        self.expect_match(Token::RightBrace, Token::LeftBrace, line)?;
+
+        Ok(Expr::new(ExprKind::Table(elems), pos))
+    }
+}
@@ -0,0 +1,253 @@
+use ast::*;
+use parser;
+use serde_json;
+use test_programs;
+
+fn strip_pos(v: &mut serde_json::Value) {
+    match *v {
+        serde_json::Value::Object(ref mut m) => {
+            m.remove("pos");
+            for (_, child) in m.iter_mut() {
+                strip_pos(child);
+            }
+        },
+        serde_json::Value::Array(ref mut elems) => {
+            for child in elems.iter_mut() {
+                strip_pos(child);
+            }
+        },
+        _ => {}
+    }
+    // Unit variants with their position removed.
+    let tag = match *v {
+        serde_json::Value::Object(ref m) if m.len() == 1 && m.values().all(|x| x.is_null()) => {
+            m.keys().next().unwrap().clone()
+        },
+        _ => return
+    };
+    *v = serde_json::Value::String(tag);
+}
+
+// The external parser reads all numerals as floats, as Lua 5.1 does.
+fn integers_as_floats(v: &mut serde_json::Value) {
+    match *v {
+        serde_json::Value::Object(ref mut m) => {
+            if let Some(n) = m.remove("Integer") {
+                m.insert("Number".to_string(), serde_json::Value::from(n.as_f64().unwrap()));
+            }
+            for (_, child) in m.iter_mut() {
+                integers_as_floats(child);
+            }
+        },
+        serde_json::Value::Array(ref mut elems) => {
+            for child in elems.iter_mut() {
+                integers_as_floats(child);
+            }
+        },
+        _ => {}
+    }
+}
+
+fn ast_json(ast: &Block) -> serde_json::Value {
+    let mut v = serde_json::to_value(ast).unwrap();
+    strip_pos(&mut v);
+    v
+}
+
+fn assert_same_ast(left: &Block, right: &Block) {
+    assert_eq!(ast_json(left), ast_json(right));
+}
+
+#[test]
+fn test_parse_test_programs() {
+    for name in test_programs::SOURCE_NAMES {
+        if let Err(e) = parser::parse(test_programs::get_source(name)) {
+            panic!("{}: {}", name, e);
+        }
+    }
+}
+
+#[test]
+fn test_parse_matches_external_parser() {
+    for name in &["simple_local", "function_def_call", "loops"] {
+        let expected: Block = serde_json::from_str(test_programs::get(name)).unwrap();
+        let ast = parser::parse(test_programs::get_source(name)).unwrap();
+        let mut ast = ast_json(&ast);
+        integers_as_floats(&mut ast);
+        assert_eq!(ast, ast_json(&expected));
+    }
+}
+
+#[test]
+fn test_parse_precedence() {
+    let ast = parser::parse("local a = -x ^ 2 .. 'a' .. 'b' or not y and z == 1 + 2 * 3").unwrap();
+    let expected = parser::parse(
+        "local a = ((-(x ^ 2)) .. ('a' .. 'b')) or ((not y) and (z == (1 + (2 * 3))))"
+    ).unwrap();
+    assert_same_ast(&ast, &expected);
+}
+
+#[test]
+fn test_parse_literals() {
+    let ast = parser::parse(r#"
+        local a = { 0x10, 1e2, 0x1p4, .5, "\65\x42\u{43}\z
+                    D", [[
+long]], [==[a]]b]==], k = 1, ["x"] = 2; 3 }
+        -- comment
+        --[[ long
+        comment ]]
+    "#).unwrap();
+    let expected: Block = serde_json::from_str(r#"{ "Block": [
+        { "Local": [ [ { "Id": "a" } ], [
+            { "Table": [
+                { "Integer": 16 },
+                { "Number": 100.0 },
+                { "Number": 16.0 },
+                { "Number": 0.5 },
+                { "String": "ABCD" },
+                { "String": "long" },
+                { "String": "a]]b" },
+                { "Pair": [ { "String": "k" }, { "Integer": 1 } ] },
+                { "Pair": [ { "String": "x" }, { "Integer": 2 } ] },
+                { "Integer": 3 }
+            ] }
+        ] ] }
+    ] }"#).unwrap();
+    assert_same_ast(&ast, &expected);
+}
+
+#[test]
+fn test_parse_string_bytes() {
+    let ast = parser::parse("local a = { \"\\xff\\200\", \"\\u{e9}\", [[\u{e9}]] }").unwrap();
+    let expected: Block = serde_json::from_str(r#"{ "Block": [
+        { "Local": [ [ { "Id": "a" } ], [
+            { "Table": [
+                { "String": "\u00ff\u00c8" },
+                { "String": "\u00c3\u00a9" },
+                { "String": "\u00c3\u00a9" }
+            ] }
+        ] ] }
+    ] }"#).unwrap();
+    assert_same_ast(&ast, &expected);
+}
+
+#[test]
+fn test_parse_escaped_newlines() {
+    let ast = parser::parse("local a = { \"a\\\nb\", \"a\\\rb\", \"a\\\r\nb\", \"a\\\n\rb\", \"a\\\n\\\nb\" }").unwrap();
+    let expected: Block = serde_json::from_str(r#"{ "Block": [
+        { "Local": [ [ { "Id": "a" } ], [
+            { "Table": [
+                { "String": "a\nb" },
+                { "String": "a\nb" },
+                { "String": "a\nb" },
+                { "String": "a\nb" },
+                { "String": "a\n\nb" }
+            ] }
+        ] ] }
+    ] }"#).unwrap();
+    assert_same_ast(&ast, &expected);
+}
+
+#[test]
+fn test_parse_integers() {
+    let ast = parser::parse(
+        "local a = { 9223372036854775807, 9223372036854775808, 0xffffffffffffffff, 0x10000000000000001, 1.0, 0x10p0 }"
+    ).unwrap();
+    let expected: Block = serde_json::from_str(r#"{ "Block": [
+        { "Local": [ [ { "Id": "a" } ], [
+            { "Table": [
+                { "Integer": 9223372036854775807 },
+                { "Number": 9223372036854775808.0 },
+                { "Integer": -1 },
+                { "Integer": 1 },
+                { "Number": 1.0 },
+                { "Number": 16.0 }
+            ] }
+        ] ] }
+    ] }"#).unwrap();
+    assert_same_ast(&ast, &expected);
+}
+
+#[test]
+fn test_parse_errors() {
+    let e = parser::parse("local a = 1\nlocal b = = 2").unwrap_err();
+    assert_eq!((e.line, e.column), (2, 11));
+
+    let e = parser::parse("if a then\n  b()\n").unwrap_err();
+    assert_eq!(e.line, 3);
+    assert!(e.to_string().contains("'end' expected (to close 'if' at line 1)"));
+
+    assert!(parser::parse("x").is_err());
+    assert!(parser::parse("return 1 x = 2").is_err());
+    assert!(parser::parse("a = 'unfinished").is_err());
+}
+
+#[test]
+fn test_parse_positions() {
+    let ast = parser::parse("local a = 1\nif a then\n  print(a + f(2))\nend").unwrap();
+    let stmts = ast.statements();
+    assert_eq!(stmts[0].pos, Some(Pos::new(1, 1)));
+    assert_eq!(stmts[1].pos, Some(Pos::new(2, 1)));
+
+    let call = match stmts[1].kind {
+        StmtKind::If(ref branches, _) => &branches[0].1.statements()[0],
+        _ => panic!("expecting if")
+    };
+    assert_eq!(call.pos, Some(Pos::new(3, 3)));
+    match call.kind {
+        StmtKind::Call(_, ref args) => {
+            assert_eq!(args[0].pos, Some(Pos::new(3, 9)));
+            match args[0].kind {
+                ExprKind::Add(_, ref right) => assert_eq!(right.pos, Some(Pos::new(3, 13))),
+                _ => panic!("expecting add")
+            }
+        },
+        _ => panic!("expecting call")
+    }
+}
+
+#[test]
+fn test_parse_parens() {
+    let ast = parser::parse("return (f()), (a), ((g()))").unwrap();
+    let values = match ast.statements()[0].kind {
+        StmtKind::Return(ref v) => v,
+        _ => panic!("expecting return")
+    };
+    assert!(matches!(values[0].kind, ExprKind::Paren(_)));
+    assert!(matches!(values[1].kind, ExprKind::Id(_)));
+    match values[2].kind {
+        ExprKind::Paren(ref inner) => assert!(matches!(inner.kind, ExprKind::Call(_, _))),
+        _ => panic!("expecting paren")
+    }
+}
+
+#[test]
+fn test_parse_methods() {
+    let ast = parser::parse("function a.b:c(x) end\nobj:m(1, 2)\nreturn obj:m \"s\"").unwrap();
+    let stmts = ast.statements();
+
+    match stmts[0].kind {
+        StmtKind::Set(ref lhs, ref exprs) => {
+            assert!(matches!(lhs[0].kind, LhsKind::Index(_, _)));
+            match exprs[0].kind {
+                ExprKind::Function(ref params, _) => {
+                    assert_eq!(params[0].id(), Some("self"));
+                    assert_eq!(params[1].id(), Some("x"));
+                },
+                _ => panic!("expecting function")
+            }
+        },
+        _ => panic!("expecting set")
+    }
+    match stmts[1].kind {
+        StmtKind::Invoke(_, ref method, ref args) => {
+            assert_eq!(method, "m");
+            assert_eq!(args.len(), 2);
+        },
+        _ => panic!("expecting invoke")
+    }
+    match stmts[2].kind {
+        StmtKind::Return(ref v) => assert!(matches!(v[0].kind, ExprKind::Invoke(_, _, _))),
+        _ => panic!("expecting return")
+    }
+}
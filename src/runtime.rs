@@ -1,3 +1,8 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::io::{self, Write};
+use std::panic::{catch_unwind, panic_any, resume_unwind, AssertUnwindSafe};
 use hexagon::executor::ExecutorImpl;
 use hexagon::value::{Value, ValueContext};
 use hexagon::builtin::array::Array;
@@ -6,10 +11,17 @@ use hexagon::object::Object;
 use hexagon::builtin::dynamic_object::DynamicObject;
 use hexagon::function::Function;
 use hexagon::errors::VMError;
-use hexagon;
 use codegen::ModuleBuilder;
-use lua_types::{Pair, Table};
+use gc::{self, GcMark};
+use lexer::{self, Token};
+use coroutine;
+use math_lib;
+use string_lib;
+use string_format;
+use table_lib;
+use lua_types::{type_name, get_metatable, get_metamethod, index_value, call_metamethod, is_function, is_truthy, Closure, LuaError, MultiValue, Pair, Table, UpvalueCell};
 
+#[allow(dead_code)]
 pub struct ModuleRuntime<'a> {
     executor: &'a mut ExecutorImpl
 }
@@ -37,27 +49,684 @@ macro_rules! set_fields {
     }
 }
 
+fn must_get_table(e: &ExecutorImpl, v: Value, fn_name: &str) -> usize {
+    match v {
+        Value::Object(id) if e.get_object_pool().get_direct_typed::<Table>(id).is_some() => id,
+        _ => panic_any(VMError::from(format!("bad argument #1 to '{}' (table expected)", fn_name)))
+    }
+}
+
+/// Primitive equality, without metamethods.
+fn raw_equal(e: &ExecutorImpl, a: Value, b: Value) -> bool {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => a == b,
+        (Value::Int(a), Value::Float(b)) | (Value::Float(b), Value::Int(a)) => float_to_integer(b) == Some(a),
+        (Value::Float(a), Value::Float(b)) => a == b,
+        (Value::Object(a), Value::Object(b)) => {
+            if a == b {
+                return true;
+            }
+            let pool = e.get_object_pool();
+            match (pool.get_direct_typed::<String>(a), pool.get_direct_typed::<String>(b)) {
+                (Some(a), Some(b)) => a == b,
+                _ => false
+            }
+        },
+        (a, b) => a == b
+    }
+}
+
+/// Returns the integer equal to a float, if there is one.
+pub(crate) fn float_to_integer(v: f64) -> Option<i64> {
+    if v.fract() == 0.0 && (-9223372036854775808.0..9223372036854775808.0).contains(&v) {
+        Some(v as i64)
+    } else {
+        None
+    }
+}
+
+/// Orders an integer against a float without rounding either of them, as
+/// converting the integer to a float would for magnitudes above 2^53.
+fn int_float_order(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if f >= 9223372036854775808.0 {
+        Some(Ordering::Less)
+    } else if f < -9223372036854775808.0 {
+        Some(Ordering::Greater)
+    } else {
+        // Within range the integer part is exact, and the fractional part
+        // only matters when the integer parts are equal.
+        Some(i.cmp(&(f.trunc() as i64)).then_with(|| 0.0.partial_cmp(&f.fract()).unwrap()))
+    }
+}
+
+fn as_number(v: Value) -> Option<f64> {
+    match v {
+        Value::Int(v) => Some(v as f64),
+        Value::Float(v) => Some(v),
+        _ => None
+    }
+}
+
+/// Converts an arithmetic operand to a number, coercing numeric strings.
+/// Strings holding an integer numeral become integers.
+pub(crate) fn to_numeric(e: &ExecutorImpl, v: Value) -> Option<Value> {
+    match v {
+        Value::Int(_) | Value::Float(_) => Some(v),
+        _ => get_string(e, v).and_then(|s| str_to_number(s))
+    }
+}
+
+/// Reads a Lua numeral with an optional sign and surrounding whitespace.
+fn str_to_number(s: &str) -> Option<Value> {
+    let s = s.trim_matches(|c| " \t\n\r\x0b\x0c".contains(c));
+    // Integers are read with their sign, as the minimum integer only fits
+    // once negated.
+    if let Ok(v) = s.parse::<i64>() {
+        return Some(Value::Int(v));
+    }
+    let (negative, numeral) = if let Some(rest) = s.strip_prefix('-') {
+        (true, rest)
+    } else {
+        (false, s.strip_prefix('+').unwrap_or(s))
+    };
+    match lexer::parse_numeral(numeral)? {
+        Token::Integer(v) => Some(Value::Int(if negative { v.wrapping_neg() } else { v })),
+        Token::Number(v) => Some(Value::Float(if negative { -v } else { v })),
+        _ => unreachable!()
+    }
+}
+
+pub(crate) fn to_number(e: &ExecutorImpl, v: Value) -> Option<f64> {
+    to_numeric(e, v).and_then(as_number)
+}
+
+/// Converts a number to a string as Lua does, with floats written with
+/// `%.14g` and kept distinct from integers by a trailing `.0`.
+pub(crate) fn number_to_string(v: Value) -> String {
+    let v = match v {
+        Value::Int(v) => return v.to_string(),
+        Value::Float(v) => v,
+        _ => unreachable!()
+    };
+    if v.is_infinite() {
+        return if v > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    if v.is_nan() {
+        return if v.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    let mut s = string_format::format_general(v, 14, false, false);
+    if s.bytes().all(|c| c == b'-' || c.is_ascii_digit()) {
+        s.push_str(".0");
+    }
+    s
+}
+
+pub(crate) fn get_string(e: &ExecutorImpl, v: Value) -> Option<&String> {
+    match v {
+        Value::Object(id) => e.get_object_pool().get_direct_typed::<String>(id),
+        _ => None
+    }
+}
+
+/// Calls the metamethod `event` of the first operand that has it.
+fn call_binary_metamethod(e: &mut ExecutorImpl, event: &str, a: Value, b: Value) -> Option<Value> {
+    let mut handler = get_metamethod(e, a, event);
+    if handler == Value::Null {
+        handler = get_metamethod(e, b, event);
+    }
+    if handler == Value::Null {
+        None
+    } else {
+        Some(call_metamethod(e, handler, &[a, b]))
+    }
+}
+
+fn get_binary_operands(e: &mut ExecutorImpl) -> (Value, Value) {
+    let frame = e.get_current_frame();
+    (frame.must_get_argument(0), frame.must_get_argument(1))
+}
+
+/// Performs an arithmetic operation, on integers with `int_op` when both
+/// operands are integers and there is one, and on floats otherwise.
+fn arith(e: &mut ExecutorImpl, event: &str, int_op: Option<fn(i64, i64) -> i64>, float_op: fn(f64, f64) -> f64) -> Value {
+    let (a, b) = get_binary_operands(e);
+    if let (Some(x), Some(y)) = (to_numeric(e, a), to_numeric(e, b)) {
+        if let (Value::Int(x), Value::Int(y), Some(op)) = (x, y, int_op) {
+            return Value::Int(op(x, y));
+        }
+        return Value::Float(float_op(as_number(x).unwrap(), as_number(y).unwrap()));
+    }
+    match call_binary_metamethod(e, event, a, b) {
+        Some(v) => v,
+        None => {
+            let culprit = if to_number(e, a).is_none() { a } else { b };
+            panic_any(VMError::from(format!("attempt to perform arithmetic on a {} value", type_name(e, &culprit))))
+        }
+    }
+}
+
+fn int_idiv(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        panic_any(VMError::from("attempt to perform 'n//0'"));
+    }
+    // Rounds towards minus infinity. The minimum integer divided by -1
+    // wraps around to itself.
+    let q = a.wrapping_div(b);
+    if a.wrapping_rem(b) != 0 && (a ^ b) < 0 {
+        q - 1
+    } else {
+        q
+    }
+}
+
+fn int_mod(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        panic_any(VMError::from("attempt to perform 'n%0'"));
+    }
+    let m = a.wrapping_rem(b);
+    if m != 0 && (m ^ b) < 0 {
+        m + b
+    } else {
+        m
+    }
+}
+
+fn lua_mod(a: f64, b: f64) -> f64 {
+    let m = a % b;
+    if m != 0.0 && (m < 0.0) != (b < 0.0) {
+        m + b
+    } else {
+        m
+    }
+}
+
+/// Equality with the `__eq` metamethod, which is only consulted for two
+/// distinct tables.
+pub(crate) fn lua_equal(e: &mut ExecutorImpl, a: Value, b: Value) -> bool {
+    if raw_equal(e, a, b) {
+        return true;
+    }
+    if let (Value::Object(x), Value::Object(y)) = (a, b) {
+        let pool = e.get_object_pool();
+        if pool.get_direct_typed::<Table>(x).is_none() || pool.get_direct_typed::<Table>(y).is_none() {
+            return false;
+        }
+        if let Some(v) = call_binary_metamethod(e, "__eq", a, b) {
+            return is_truthy(v);
+        }
+    }
+    false
+}
+
+pub(crate) fn compare(e: &mut ExecutorImpl, event: &str, a: Value, b: Value) -> bool {
+    let order = match (a, b) {
+        (Value::Int(x), Value::Int(y)) => Some(Some(x.cmp(&y))),
+        (Value::Int(x), Value::Float(y)) => Some(int_float_order(x, y)),
+        (Value::Float(x), Value::Int(y)) => Some(int_float_order(y, x).map(Ordering::reverse)),
+        (Value::Float(x), Value::Float(y)) => Some(x.partial_cmp(&y)),
+        _ => match (get_string(e, a), get_string(e, b)) {
+            (Some(x), Some(y)) => Some(Some(x.cmp(y))),
+            _ => None
+        }
+    };
+    if let Some(order) = order {
+        return match order {
+            Some(Ordering::Less) => true,
+            Some(Ordering::Equal) => event == "__le",
+            _ => false
+        };
+    }
+    match call_binary_metamethod(e, event, a, b) {
+        Some(v) => is_truthy(v),
+        None => {
+            let (ta, tb) = (type_name(e, &a), type_name(e, &b));
+            panic_any(VMError::from(if ta == tb {
+                format!("attempt to compare two {} values", ta)
+            } else {
+                format!("attempt to compare {} with {}", ta, tb)
+            }))
+        }
+    }
+}
+
+pub(crate) fn concat_operand(e: &ExecutorImpl, v: Value) -> Option<String> {
+    match v {
+        Value::Int(_) | Value::Float(_) => Some(number_to_string(v)),
+        _ => get_string(e, v).cloned()
+    }
+}
+
+/// Converts a value to a string as `tostring` does, honoring `__tostring`.
+pub(crate) fn lua_tostring(e: &mut ExecutorImpl, v: Value) -> String {
+    let handler = get_metamethod(e, v, "__tostring");
+    if handler != Value::Null {
+        let s = call_metamethod(e, handler, &[v]);
+        return match get_string(e, s) {
+            Some(s) => s.clone(),
+            None => panic_any(VMError::from("'__tostring' must return a string"))
+        };
+    }
+
+    match v {
+        Value::Null => "nil".to_string(),
+        Value::Object(id) => match get_string(e, v) {
+            Some(s) => s.clone(),
+            None => format!("{}: 0x{:08x}", type_name(e, &v), id)
+        },
+        Value::Int(_) | Value::Float(_) => number_to_string(v),
+        _ => ValueContext::new(&v, e.get_object_pool()).to_str().to_string()
+    }
+}
+
+/// Prepares a numeric for loop, returning the control variable, the limit,
+/// the step and whether the loop counts with integers.
+///
+/// With an integer initial value and step, the limit is replaced with the
+/// number of iterations after the first one, so the control variable never
+/// overflows. An integer loop that does not run is returned as an empty
+/// float loop.
+fn fornum_prep(e: &mut ExecutorImpl) -> [Value; 4] {
+    let args = {
+        let frame = e.get_current_frame();
+        [frame.must_get_argument(0), frame.must_get_argument(1), frame.must_get_argument(2)]
+    };
+    for (v, name) in args.iter().zip(["initial value", "limit", "step"].iter()) {
+        match *v {
+            Value::Int(_) | Value::Float(_) => {},
+            _ => panic_any(VMError::from(format!("'for' {} must be a number", name)))
+        }
+    }
+    if as_number(args[2]) == Some(0.0) {
+        panic_any(VMError::from("'for' step is zero"));
+    }
+    let empty = [Value::Float(1.0), Value::Float(0.0), Value::Float(1.0), Value::Bool(false)];
+
+    if let (Value::Int(init), Value::Int(step)) = (args[0], args[2]) {
+        let limit = match args[1] {
+            Value::Int(v) => v,
+            Value::Float(v) => {
+                let v = if step < 0 { v.ceil() } else { v.floor() };
+                if v >= -(i64::MIN as f64) {
+                    if step < 0 {
+                        return empty;
+                    }
+                    i64::MAX
+                } else if v >= i64::MIN as f64 {
+                    v as i64
+                } else {
+                    // Below the minimum integer, or NaN.
+                    if step > 0 {
+                        return empty;
+                    }
+                    i64::MIN
+                }
+            },
+            _ => unreachable!()
+        };
+        let skip = if step > 0 { init > limit } else { init < limit };
+        if skip {
+            return empty;
+        }
+        let count = if step > 0 {
+            (limit as u64).wrapping_sub(init as u64) / step as u64
+        } else {
+            // Avoids negating the minimum integer.
+            (init as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1)
+        };
+        return [Value::Int(init), Value::Int(count as i64), Value::Int(step), Value::Bool(true)];
+    }
+    [
+        Value::Float(as_number(args[0]).unwrap()),
+        Value::Float(as_number(args[1]).unwrap()),
+        Value::Float(as_number(args[2]).unwrap()),
+        Value::Bool(false)
+    ]
+}
+
+/// Raises `v` as a Lua error.
+pub(crate) fn raise_error(e: &mut ExecutorImpl, v: Value) -> ! {
+    let message = match v {
+        Value::Int(_) | Value::Float(_) => lua_tostring(e, v),
+        _ => match get_string(e, v) {
+            Some(s) => String::from_utf8_lossy(&string_lib::string_bytes(s)).into_owned(),
+            None => format!("(error object is a {} value)", type_name(e, &v))
+        }
+    };
+    panic_any(VMError::from(LuaError {
+        value: v,
+        message
+    }))
+}
+
+/// Returns the Lua value carried by a caught error. VM errors become their
+/// message, and other panics are not meant to be caught and are resumed.
+pub(crate) fn error_value(e: &mut ExecutorImpl, payload: Box<dyn Any + Send>) -> Value {
+    let err = match payload.downcast::<VMError>() {
+        Ok(err) => err.unwrap(),
+        Err(payload) => resume_unwind(payload)
+    };
+    match err.as_any().downcast_ref::<LuaError>() {
+        Some(err) => err.value,
+        None => alloc_object!(e, err.to_string())
+    }
+}
+
+fn tbc_depth(e: &ExecutorImpl, tbc_stack: Value) -> usize {
+    match tbc_stack {
+        Value::Object(id) => e.get_object_pool().must_get_direct_typed::<Array>(id).elements.borrow().len(),
+        _ => unreachable!()
+    }
+}
+
+/// Returns the to-be-closed variables of the running coroutine, or `main`
+/// outside of coroutines.
+fn current_tbc_stack(e: &ExecutorImpl, main: Value) -> Value {
+    coroutine::running_tbc_stack(e).unwrap_or(main)
+}
+
+/// Calls `f`, returning either its results or the error it raised.
+///
+/// To-be-closed variables left open by the error are closed before
+/// returning it.
+fn protected_call(e: &mut ExecutorImpl, tbc_stack: Value, f: Value, args: &[Value]) -> Result<Value, Value> {
+    let tbc_stack = current_tbc_stack(e, tbc_stack);
+    let depth = tbc_depth(e, tbc_stack);
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        e.invoke(f, f, None, args);
+        e.get_current_frame().pop_exec()
+    }));
+    match result {
+        Ok(v) => Ok(v),
+        Err(payload) => {
+            let err = error_value(e, payload);
+            close_tbc(e, tbc_stack, depth, err);
+            Err(err)
+        }
+    }
+}
+
+/// Closes the to-be-closed variables registered above `depth`, most recent
+/// first, passing `err` to their `__close` metamethods.
+pub(crate) fn close_tbc(e: &mut ExecutorImpl, tbc_stack: Value, depth: usize, err: Value) {
+    let tbc_stack = match tbc_stack {
+        Value::Object(id) => id,
+        _ => unreachable!()
+    };
+    loop {
+        let v = {
+            let mut values = e.get_object_pool().must_get_direct_typed::<Array>(tbc_stack).elements.borrow_mut();
+            if values.len() <= depth {
+                break;
+            }
+            values.pop().unwrap()
+        };
+        // Placeholder for a nil or false value.
+        if v == Value::Null {
+            continue;
+        }
+        let handler = get_metamethod(e, v, "__close");
+        if handler != Value::Null {
+            call_metamethod(e, handler, &[v, err]);
+        }
+    }
+}
+
+fn lua_next(e: &mut ExecutorImpl) -> Value {
+    let (t, k) = {
+        let frame = e.get_current_frame();
+        (frame.must_get_argument(0), frame.get_argument(1).unwrap_or(Value::Null))
+    };
+    must_get_table(e, t, "next");
+    e.invoke(t, t, Some("__next__"), &[k]);
+    e.get_current_frame().pop_exec()
+}
+
+fn lua_ipairs_iter(e: &mut ExecutorImpl) -> Value {
+    let (t, i) = {
+        let frame = e.get_current_frame();
+        (frame.must_get_argument(0), frame.must_get_argument(1))
+    };
+    must_get_table(e, t, "ipairs");
+    let i = Value::Int(ValueContext::new(&i, e.get_object_pool()).to_i64() + 1);
+    e.invoke(t, t, Some("__get__"), &[i]);
+    let v = e.get_current_frame().pop_exec();
+    if v == Value::Null {
+        Value::Null
+    } else {
+        alloc_object!(e, MultiValue {
+            values: vec! [ i, v ]
+        })
+    }
+}
+
+/// Creates a library table such as `coroutine` from its functions.
+pub(crate) fn new_library(e: &mut ExecutorImpl, fields: Vec<(&str, Value)>) -> Value {
+    let t = Table::new();
+    for (k, v) in fields {
+        let k = alloc_object!(e, k.to_string());
+        t.set(e, k, v);
+    }
+    alloc_object!(e, t)
+}
+
+fn get_arguments(e: &ExecutorImpl, from: usize) -> Vec<Value> {
+    let frame = e.get_current_frame();
+    (from..frame.get_n_arguments()).map(|i| frame.must_get_argument(i)).collect()
+}
+
+fn init_coroutine_lib(e: &mut ExecutorImpl) -> Value {
+    let fields = vec! [
+        ("create", native!(e, |e| {
+            let f = e.get_current_frame().get_argument(0).unwrap_or(Value::Null);
+            if !is_function(e, &f) {
+                panic_any(VMError::from("bad argument #1 to 'create' (function expected)"));
+            }
+            coroutine::create(e, f)
+        })),
+        ("resume", native!(e, |e| {
+            let co = e.get_current_frame().get_argument(0).unwrap_or(Value::Null);
+            let args = get_arguments(e, 1);
+            let mut values = Vec::new();
+            match coroutine::resume(e, co, args) {
+                Ok(ret) => {
+                    values.push(Value::Bool(true));
+                    values.extend(ret);
+                },
+                Err(err) => {
+                    values.push(Value::Bool(false));
+                    values.push(err);
+                }
+            }
+            MultiValue::from_values(e, values)
+        })),
+        ("yield", native!(e, |e| {
+            let args = get_arguments(e, 0);
+            let values = coroutine::yield_values(e, args);
+            MultiValue::from_values(e, values)
+        })),
+        ("status", native!(e, |e| {
+            let co = e.get_current_frame().get_argument(0).unwrap_or(Value::Null);
+            let id = coroutine::must_get_coroutine(e, co, "status");
+            let status = e.get_object_pool().must_get_direct_typed::<coroutine::Coroutine>(id).status();
+            alloc_object!(e, status.name().to_string())
+        })),
+        ("wrap", native!(e, |e| {
+            let f = e.get_current_frame().get_argument(0).unwrap_or(Value::Null);
+            if !is_function(e, &f) {
+                panic_any(VMError::from("bad argument #1 to 'wrap' (function expected)"));
+            }
+            let co = coroutine::create(e, f);
+            alloc_object!(e, coroutine::CoroutineFunction {
+                coroutine: co
+            })
+        })),
+        ("isyieldable", native!(e, |e| {
+            Value::Bool(coroutine::running(e).is_some())
+        })),
+        ("close", native!(e, |e| {
+            let co = e.get_current_frame().get_argument(0).unwrap_or(Value::Null);
+            match coroutine::close(e, co) {
+                Ok(()) => Value::Bool(true),
+                Err(err) => alloc_object!(e, MultiValue {
+                    values: vec! [ Value::Bool(false), err ]
+                })
+            }
+        }))
+    ];
+    new_library(e, fields)
+}
+
 fn init_global_resources(e: &mut ExecutorImpl, g: &mut DynamicObject) {
+    let next = native!(e, lua_next);
+    let ipairs_iter = native!(e, lua_ipairs_iter);
+    let tbc_stack = alloc_object!(e, Array::new());
+
     set_fields!(
         g,
-        "print" => native!(e, |e| {
+        "next" => next,
+        "setmetatable" => native!(e, |e| {
+            let (t, mt) = {
+                let frame = e.get_current_frame();
+                (frame.must_get_argument(0), frame.get_argument(1).unwrap_or(Value::Null))
+            };
+            let t_id = must_get_table(e, t, "setmetatable");
+            match mt {
+                Value::Null => {},
+                Value::Object(id) if e.get_object_pool().get_direct_typed::<Table>(id).is_some() => {},
+                _ => panic_any(VMError::from("bad argument #2 to 'setmetatable' (nil or table expected)"))
+            }
+
+            let t = e.get_object_pool().must_get_direct_typed::<Table>(t_id);
+            if t.get_metamethod(e, "__metatable") != Value::Null {
+                panic_any(VMError::from("cannot change a protected metatable"));
+            }
+            t.set_metatable(mt);
+
+            let (weak_keys, weak_values) = match get_string(e, t.get_metamethod(e, "__mode")) {
+                Some(mode) => (mode.contains('k'), mode.contains('v')),
+                None => (false, false)
+            };
+            t.set_weak_mode(e, weak_keys, weak_values);
+            if t.get_metamethod(e, "__gc") != Value::Null {
+                gc::register_finalizer(e, t_id, t.gc_mark());
+            }
+            Value::Object(t_id)
+        }),
+        "getmetatable" => native!(e, |e| {
             let v = e.get_current_frame().must_get_argument(0);
-            let s = ValueContext::new(&v, e.get_object_pool()).to_str().to_string();
-            println!("{}", s);
+            match get_metamethod(e, v, "__metatable") {
+                Value::Null => get_metatable(e, v),
+                protected => protected
+            }
+        }),
+        "rawget" => native!(e, |e| {
+            let (t, k) = {
+                let frame = e.get_current_frame();
+                (frame.must_get_argument(0), frame.get_argument(1).unwrap_or(Value::Null))
+            };
+            let t = must_get_table(e, t, "rawget");
+            e.get_object_pool().must_get_direct_typed::<Table>(t).get(e, k)
+        }),
+        "rawset" => native!(e, |e| {
+            let (t, k, v) = {
+                let frame = e.get_current_frame();
+                (
+                    frame.must_get_argument(0),
+                    frame.get_argument(1).unwrap_or(Value::Null),
+                    frame.get_argument(2).unwrap_or(Value::Null)
+                )
+            };
+            let t_id = must_get_table(e, t, "rawset");
+            e.get_object_pool().must_get_direct_typed::<Table>(t_id).set(e, k, v);
+            t
+        }),
+        "rawequal" => native!(e, |e| {
+            let (a, b) = {
+                let frame = e.get_current_frame();
+                (frame.must_get_argument(0), frame.must_get_argument(1))
+            };
+            Value::Bool(raw_equal(e, a, b))
+        }),
+        "pairs" => native!(e, move |e| {
+            let t = e.get_current_frame().must_get_argument(0);
+            must_get_table(e, t, "pairs");
+            alloc_object!(e, MultiValue {
+                values: vec! [ next, t, Value::Null ]
+            })
+        }),
+        "ipairs" => native!(e, move |e| {
+            let t = e.get_current_frame().must_get_argument(0);
+            must_get_table(e, t, "ipairs");
+            alloc_object!(e, MultiValue {
+                values: vec! [ ipairs_iter, t, Value::Int(0) ]
+            })
+        }),
+        "@__luax_internal.ipairs_iter" => ipairs_iter,
+        "print" => native!(e, |e| {
+            let args: Vec<Value> = {
+                let frame = e.get_current_frame();
+                (0..frame.get_n_arguments()).map(|i| frame.must_get_argument(i)).collect()
+            };
+            let parts: Vec<String> = args.into_iter().map(|v| lua_tostring(e, v)).collect();
+            let mut line = string_lib::string_bytes(&parts.join("\t"));
+            line.push(b'\n');
+            io::stdout().write_all(&line).unwrap();
             Value::Null
         }),
+        "tostring" => native!(e, |e| {
+            let v = e.get_current_frame().must_get_argument(0);
+            let s = lua_tostring(e, v);
+            alloc_object!(e, s)
+        }),
         "assert" => native!(e, |e| {
             let v = e.get_current_frame().must_get_argument(0);
-            let cond = ValueContext::new(&v, e.get_object_pool()).to_bool();
-            if !cond {
-                panic!(if let Some(reason) = e.get_current_frame().get_argument(1) {
-                    let reason = ValueContext::new(&reason, e.get_object_pool());;
-                    VMError::from(format!("Assertion failed: {}", reason.to_str()))
-                } else {
-                    VMError::from("Assertion failed")
-                });
+            if !is_truthy(v) {
+                let reason = match e.get_current_frame().get_argument(1) {
+                    Some(reason) => reason,
+                    None => alloc_object!(e, "assertion failed!".to_string())
+                };
+                raise_error(e, reason);
+            }
+            let values = get_arguments(e, 0);
+            MultiValue::from_values(e, values)
+        }),
+        "select" => native!(e, |e| {
+            let (n, values) = {
+                let frame = e.get_current_frame();
+                let n = frame.must_get_argument(0);
+                let values: Vec<Value> = (1..frame.get_n_arguments()).map(|i| frame.must_get_argument(i)).collect();
+                (n, values)
+            };
+
+            let n = match n {
+                Value::Int(v) => v,
+                Value::Float(v) => v as i64,
+                Value::Object(id) if e.get_object_pool().get_direct_typed::<String>(id).map(|v| v == "#").unwrap_or(false) => {
+                    return Value::Int(values.len() as i64);
+                },
+                _ => panic_any(VMError::from("bad argument #1 to 'select' (number expected)"))
+            };
+            let start = if n < 0 {
+                if -n > values.len() as i64 {
+                    panic_any(VMError::from("bad argument #1 to 'select' (index out of range)"));
+                }
+                values.len() - (-n) as usize
+            } else if n == 0 {
+                panic_any(VMError::from("bad argument #1 to 'select' (index out of range)"));
+            } else {
+                ::std::cmp::min(n as usize - 1, values.len())
+            };
+
+            let values = values[start..].to_vec();
+            if values.len() == 1 {
+                values[0]
+            } else {
+                alloc_object!(e, MultiValue {
+                    values
+                })
             }
-            Value::Null
         }),
         "typedarray" => native!(e, |e| {
             let array_type = e.get_current_frame().must_get_argument(0);
@@ -66,7 +735,7 @@ fn init_global_resources(e: &mut ExecutorImpl, g: &mut DynamicObject) {
                 e.get_object_pool()
             ).to_i64() as usize;
 
-            let array: Box<Object> = match ValueContext::new(&array_type, e.get_object_pool()).to_str().as_ref() {
+            let array: Box<dyn Object> = match ValueContext::new(&array_type, e.get_object_pool()).to_str().as_ref() {
                 "i8" => Box::new(TypedArray::new(0i8, len)),
                 "u8" => Box::new(TypedArray::new(0u8, len)),
                 "i16" => Box::new(TypedArray::new(0i16, len)),
@@ -77,11 +746,102 @@ fn init_global_resources(e: &mut ExecutorImpl, g: &mut DynamicObject) {
                 "u64" => Box::new(TypedArray::new(0u64, len)),
                 "f32" => Box::new(TypedArray::new(0f32, len)),
                 "f64" => Box::new(TypedArray::new(0f64, len)),
-                _ => panic!(VMError::from("Unsupported array type"))
+                _ => panic_any(VMError::from("Unsupported array type"))
             };
             Value::Object(e.get_object_pool_mut().allocate(array))
         }),
+        "error" => native!(e, |e| {
+            // There is no position information at runtime, so the level
+            // argument has no effect.
+            let v = e.get_current_frame().get_argument(0).unwrap_or(Value::Null);
+            raise_error(e, v)
+        }),
+        "pcall" => native!(e, move |e| {
+            let (f, args) = {
+                let frame = e.get_current_frame();
+                let f = frame.get_argument(0).unwrap_or(Value::Null);
+                let args: Vec<Value> = (1..frame.get_n_arguments()).map(|i| frame.must_get_argument(i)).collect();
+                (f, args)
+            };
+
+            let mut values = Vec::new();
+            match protected_call(e, tbc_stack, f, &args) {
+                Ok(ret) => {
+                    values.push(Value::Bool(true));
+                    MultiValue::expand_into(e, ret, &mut values);
+                },
+                Err(err) => {
+                    values.push(Value::Bool(false));
+                    values.push(err);
+                }
+            }
+            alloc_object!(e, MultiValue {
+                values
+            })
+        }),
+        "xpcall" => native!(e, move |e| {
+            let (f, handler, args) = {
+                let frame = e.get_current_frame();
+                let f = frame.get_argument(0).unwrap_or(Value::Null);
+                let handler = frame.get_argument(1).unwrap_or(Value::Null);
+                let args: Vec<Value> = (2..frame.get_n_arguments()).map(|i| frame.must_get_argument(i)).collect();
+                (f, handler, args)
+            };
+
+            let mut values = Vec::new();
+            match protected_call(e, tbc_stack, f, &args) {
+                Ok(ret) => {
+                    values.push(Value::Bool(true));
+                    MultiValue::expand_into(e, ret, &mut values);
+                },
+                Err(err) => {
+                    // An error in the handler is returned in place of the
+                    // original one.
+                    let ret = protected_call(e, tbc_stack, handler, &[err]).unwrap_or_else(|v| v);
+                    values.push(Value::Bool(false));
+                    values.push(MultiValue::nth(e, ret, 0));
+                }
+            }
+            alloc_object!(e, MultiValue {
+                values
+            })
+        }),
+        "collectgarbage" => native!(e, |e| {
+            let opt = match e.get_current_frame().get_argument(0) {
+                None | Some(Value::Null) => "collect".to_string(),
+                Some(v) => match get_string(e, v) {
+                    Some(v) => v.clone(),
+                    None => panic_any(VMError::from("bad argument #1 to 'collectgarbage' (string expected)"))
+                }
+            };
+            match opt.as_str() {
+                "collect" => {
+                    gc::collect(e);
+                    Value::Int(0)
+                },
+                "step" => {
+                    gc::collect(e);
+                    Value::Bool(true)
+                },
+                "count" => {
+                    // The pool does not know the size of its objects, so each
+                    // one is counted as a typical small table.
+                    const OBJECT_SIZE: usize = 64;
+                    let objects = e.get_object_pool().get_object_count();
+                    Value::Float((objects * OBJECT_SIZE) as f64 / 1024.0)
+                },
+                "isrunning" => Value::Bool(true),
+                _ => panic_any(VMError::from(format!("bad argument #1 to 'collectgarbage' (invalid option '{}')", opt)))
+            }
+        }),
+        "coroutine" => init_coroutine_lib(e),
+        "math" => math_lib::init(e),
+        "string" => string_lib::init(e),
+        "table" => table_lib::init(e),
         "@__luax_internal.new_table" => native!(e, |e| {
+            // Finalizers of tables found unreachable by a collection are run
+            // at the next allocation of a table.
+            gc::run_pending_finalizers(e);
             alloc_object!(e, Table::new())
         }),
         "@__luax_internal.new_array" => native!(e, |e| {
@@ -92,10 +852,184 @@ fn init_global_resources(e: &mut ExecutorImpl, g: &mut DynamicObject) {
             let right = e.get_current_frame().must_get_argument(1);
 
             alloc_object!(e, Pair {
-                left: left,
-                right: right
+                left,
+                right
+            })
+        }),
+        "@__luax_internal.new_closure" => native!(e, |e| {
+            let (function, n_params, is_vararg, upvalues) = {
+                let frame = e.get_current_frame();
+                let n_upvalues = frame.get_n_arguments() - 3;
+                (
+                    frame.must_get_argument(0),
+                    ValueContext::new(&frame.must_get_argument(1), e.get_object_pool()).to_i64() as usize,
+                    ValueContext::new(&frame.must_get_argument(2), e.get_object_pool()).to_bool(),
+                    (0..n_upvalues).map(|i| frame.must_get_argument(i + 3)).collect()
+                )
+            };
+
+            alloc_object!(e, Closure {
+                function,
+                n_params,
+                is_vararg,
+                upvalues,
+                gc_mark: GcMark::new()
             })
         }),
+        "@__luax_internal.new_cell" => native!(e, |e| {
+            let value = e.get_current_frame().must_get_argument(0);
+
+            alloc_object!(e, UpvalueCell {
+                value: Cell::new(value)
+            })
+        }),
+        "@__luax_internal.nth_value" => native!(e, |e| {
+            let v = e.get_current_frame().must_get_argument(0);
+            let n = ValueContext::new(
+                &e.get_current_frame().must_get_argument(1),
+                e.get_object_pool()
+            ).to_i64() as usize;
+
+            MultiValue::nth(e, v, n)
+        }),
+        "@__luax_internal.index" => native!(e, |e| {
+            let (target, key) = {
+                let frame = e.get_current_frame();
+                (frame.must_get_argument(0), frame.must_get_argument(1))
+            };
+            index_value(e, target, key)
+        }),
+        "@__luax_internal.len" => native!(e, |e| {
+            let v = e.get_current_frame().must_get_argument(0);
+            if let Value::Object(id) = v {
+                if let Some(s) = e.get_object_pool().get_direct_typed::<String>(id) {
+                    return Value::Int(string_lib::string_len(s) as i64);
+                }
+                // `__len__` is also where the `__len` metamethod hooks in.
+                if e.get_object_pool().get_direct_typed::<Table>(id).is_some() {
+                    e.invoke(v, v, Some("__len__"), &[]);
+                    return e.get_current_frame().pop_exec();
+                }
+            }
+            panic_any(VMError::from(format!("attempt to get length of a {} value", type_name(e, &v))))
+        }),
+        "@__luax_internal.add" => native!(e, |e| arith(e, "__add", Some(i64::wrapping_add), |a, b| a + b)),
+        "@__luax_internal.sub" => native!(e, |e| arith(e, "__sub", Some(i64::wrapping_sub), |a, b| a - b)),
+        "@__luax_internal.mul" => native!(e, |e| arith(e, "__mul", Some(i64::wrapping_mul), |a, b| a * b)),
+        "@__luax_internal.div" => native!(e, |e| arith(e, "__div", None, |a, b| a / b)),
+        "@__luax_internal.mod" => native!(e, |e| arith(e, "__mod", Some(int_mod), lua_mod)),
+        "@__luax_internal.pow" => native!(e, |e| arith(e, "__pow", None, f64::powf)),
+        "@__luax_internal.idiv" => native!(e, |e| arith(e, "__idiv", Some(int_idiv), |a, b| (a / b).floor())),
+        "@__luax_internal.unm" => native!(e, |e| {
+            let v = e.get_current_frame().must_get_argument(0);
+            match to_numeric(e, v) {
+                Some(Value::Int(v)) => return Value::Int(v.wrapping_neg()),
+                Some(Value::Float(v)) => return Value::Float(-v),
+                _ => {}
+            }
+            match get_metamethod(e, v, "__unm") {
+                Value::Null => panic_any(VMError::from(format!("attempt to perform arithmetic on a {} value", type_name(e, &v)))),
+                handler => call_metamethod(e, handler, &[v, v])
+            }
+        }),
+        "@__luax_internal.concat" => native!(e, |e| {
+            let (a, b) = get_binary_operands(e);
+            if let (Some(a), Some(b)) = (concat_operand(e, a), concat_operand(e, b)) {
+                return alloc_object!(e, a + b.as_str());
+            }
+            match call_binary_metamethod(e, "__concat", a, b) {
+                Some(v) => v,
+                None => {
+                    let culprit = if concat_operand(e, a).is_none() { a } else { b };
+                    panic_any(VMError::from(format!("attempt to concatenate a {} value", type_name(e, &culprit))))
+                }
+            }
+        }),
+        "@__luax_internal.eq" => native!(e, |e| {
+            let (a, b) = get_binary_operands(e);
+            Value::Bool(lua_equal(e, a, b))
+        }),
+        "@__luax_internal.lt" => native!(e, |e| {
+            let (a, b) = get_binary_operands(e);
+            Value::Bool(compare(e, "__lt", a, b))
+        }),
+        "@__luax_internal.le" => native!(e, |e| {
+            let (a, b) = get_binary_operands(e);
+            Value::Bool(compare(e, "__le", a, b))
+        }),
+        "@__luax_internal.is_nil" => native!(e, |e| {
+            Value::Bool(e.get_current_frame().must_get_argument(0) == Value::Null)
+        }),
+        "@__luax_internal.fornum_prep" => native!(e, |e| {
+            let values = fornum_prep(e);
+            MultiValue::from_values(e, values.to_vec())
+        }),
+        "@__luax_internal.new_multi_value" => native!(e, |e| {
+            let values = {
+                let frame = e.get_current_frame();
+                let n = frame.get_n_arguments() - 1;
+                let expand_last = ValueContext::new(&frame.must_get_argument(n), e.get_object_pool()).to_bool();
+
+                let mut values: Vec<Value> = Vec::with_capacity(n);
+                for i in 0..n {
+                    let v = frame.must_get_argument(i);
+                    if expand_last && i + 1 == n {
+                        MultiValue::expand_into(e, v, &mut values);
+                    } else {
+                        values.push(v);
+                    }
+                }
+                values
+            };
+
+            if values.len() == 1 {
+                values[0]
+            } else {
+                alloc_object!(e, MultiValue {
+                    values
+                })
+            }
+        }),
+        "@__luax_internal.tbc_stack" => tbc_stack,
+        "@__luax_internal.tbc_push" => native!(e, move |e| {
+            let (v, name) = {
+                let frame = e.get_current_frame();
+                (frame.must_get_argument(0), frame.must_get_argument(1))
+            };
+            let v = if is_truthy(v) {
+                if get_metamethod(e, v, "__close") == Value::Null {
+                    let name = get_string(e, name).unwrap();
+                    panic_any(VMError::from(format!("variable '{}' got a non-closable value", name)));
+                }
+                v
+            } else {
+                Value::Null
+            };
+            if let Value::Object(id) = current_tbc_stack(e, tbc_stack) {
+                e.get_object_pool().must_get_direct_typed::<Array>(id).elements.borrow_mut().push(v);
+            }
+            Value::Null
+        }),
+        "@__luax_internal.tbc_close" => native!(e, move |e| {
+            let n = ValueContext::new(&e.get_current_frame().must_get_argument(0), e.get_object_pool()).to_i64() as usize;
+            let tbc_stack = current_tbc_stack(e, tbc_stack);
+            let depth = tbc_depth(e, tbc_stack) - n;
+            close_tbc(e, tbc_stack, depth, Value::Null);
+            Value::Null
+        }),
+        "@__luax_internal.apply" => native!(e, |e| {
+            let (target, args) = {
+                let frame = e.get_current_frame();
+                let n = frame.get_n_arguments();
+
+                let mut args: Vec<Value> = (1..n - 1).map(|i| frame.must_get_argument(i)).collect();
+                MultiValue::expand_into(e, frame.must_get_argument(n - 1), &mut args);
+                (frame.must_get_argument(0), args)
+            };
+
+            e.invoke(target, target, None, &args);
+            e.get_current_frame().pop_exec()
+        }),
         "panic" => Value::Null
     );
 }
@@ -104,7 +1038,10 @@ pub fn invoke(executor: &mut ExecutorImpl, builder: ModuleBuilder, entry_fn_id:
     let functions = builder.functions.into_inner();
     let mut global_resources = DynamicObject::new(None);
 
-    let mut fn_res = Array::new();
+    gc::init(executor);
+    coroutine::init(executor);
+
+    let fn_res = Array::new();
     let mut local_fn_res: Vec<Value> = Vec::new();
 
     for mut f in functions {
@@ -124,6 +1061,7 @@ pub fn invoke(executor: &mut ExecutorImpl, builder: ModuleBuilder, entry_fn_id:
     );
 
     init_global_resources(executor, &mut global_resources);
+    let tbc_stack = global_resources.get_field(executor.get_object_pool(), "@__luax_internal.tbc_stack").unwrap();
 
     //global_resources.freeze();
 
@@ -144,5 +1082,18 @@ pub fn invoke(executor: &mut ExecutorImpl, builder: ModuleBuilder, entry_fn_id:
         }
     }
 
-    executor.invoke(target, Value::Null, None, &[]);
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        executor.invoke(target, Value::Null, None, &[]);
+        coroutine::discard_all(executor);
+        gc::run_all_finalizers(executor);
+    }));
+
+    // To-be-closed variables still open when an error reaches the top
+    // level are closed with the error.
+    if let Err(e) = result {
+        let err = error_value(executor, e);
+        close_tbc(executor, tbc_stack, 0, err);
+        coroutine::discard_all(executor);
+        raise_error(executor, err);
+    }
 }
@@ -0,0 +1,463 @@
+//! `string.format`, following the reference implementation and the C
+//! `printf` conversions it relies on.
+
+use std::panic::panic_any;
+use hexagon::executor::ExecutorImpl;
+use hexagon::value::Value;
+use hexagon::errors::VMError;
+use runtime::{get_string, lua_tostring};
+use string_lib::{arg_error, check_integer, check_bytes, check_number, get_arg, new_string_from_bytes, string_bytes};
+
+/// Maximum length of a conversion specification, after the '%'.
+const MAX_SPEC_LEN: usize = 22;
+
+/// Strings at least this long are added as is by `%s` without a precision.
+const MAX_UNFORMATTED_LEN: usize = 100;
+
+// Flags accepted by each kind of conversion.
+const FLAGS_FLOAT: &str = "-+ #0";
+const FLAGS_HEX: &str = "-#0";
+const FLAGS_INT: &str = "-+ 0";
+const FLAGS_UNSIGNED: &str = "-0";
+const FLAGS_CHAR: &str = "-";
+
+/// A parsed conversion specification such as `%-8.3f`.
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alt: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    conv: u8
+}
+
+fn error(msg: String) -> ! {
+    panic_any(VMError::from(msg))
+}
+
+/// Parses the specification `form`, which includes the leading '%',
+/// allowing only the given flags and a precision if `precision` is set.
+fn parse_spec(form: &[u8], flags: &str, precision: bool) -> Spec {
+    let invalid = || -> ! {
+        error(format!("invalid conversion specification: '{}'", String::from_utf8_lossy(form)))
+    };
+    let mut spec = Spec {
+        left: false,
+        plus: false,
+        space: false,
+        alt: false,
+        zero: false,
+        width: 0,
+        precision: None,
+        conv: form[form.len() - 1]
+    };
+
+    let mut i = 1;
+    while i < form.len() && flags.as_bytes().contains(&form[i]) {
+        match form[i] {
+            b'-' => spec.left = true,
+            b'+' => spec.plus = true,
+            b' ' => spec.space = true,
+            b'#' => spec.alt = true,
+            _ => spec.zero = true
+        }
+        i += 1;
+    }
+    let read_digits = |i: &mut usize| -> usize {
+        let mut v = 0;
+        for _ in 0..2 {
+            match form.get(*i) {
+                Some(d) if d.is_ascii_digit() => {
+                    v = v * 10 + (d - b'0') as usize;
+                    *i += 1;
+                },
+                _ => break
+            }
+        }
+        v
+    };
+    // A width cannot start with '0'.
+    if form[i] != b'0' {
+        spec.width = read_digits(&mut i);
+        if form[i] == b'.' && precision {
+            i += 1;
+            spec.precision = Some(read_digits(&mut i));
+        }
+    }
+    if i != form.len() - 1 {
+        invalid();
+    }
+    spec
+}
+
+fn push_fill(out: &mut Vec<u8>, c: u8, n: usize) {
+    out.extend(::std::iter::repeat_n(c, n));
+}
+
+/// Pads `body`, which follows `prefix` (a sign or a base prefix), to the
+/// width of the specification. Zero padding goes between the two.
+fn pad(spec: &Spec, prefix: &str, body: &str, zero_pad: bool, out: &mut Vec<u8>) {
+    let fill = spec.width.saturating_sub(prefix.len() + body.len());
+    if spec.left {
+        out.extend_from_slice(prefix.as_bytes());
+        out.extend_from_slice(body.as_bytes());
+        push_fill(out, b' ', fill);
+    } else if zero_pad {
+        out.extend_from_slice(prefix.as_bytes());
+        push_fill(out, b'0', fill);
+        out.extend_from_slice(body.as_bytes());
+    } else {
+        push_fill(out, b' ', fill);
+        out.extend_from_slice(prefix.as_bytes());
+        out.extend_from_slice(body.as_bytes());
+    }
+}
+
+/// Pads raw bytes with spaces, as done for `%c` and `%s`.
+fn pad_bytes(spec: &Spec, body: &[u8], out: &mut Vec<u8>) {
+    let fill = spec.width.saturating_sub(body.len());
+    if !spec.left {
+        push_fill(out, b' ', fill);
+    }
+    out.extend_from_slice(body);
+    if spec.left {
+        push_fill(out, b' ', fill);
+    }
+}
+
+fn sign_prefix(spec: &Spec, negative: bool) -> &'static str {
+    if negative {
+        "-"
+    } else if spec.plus {
+        "+"
+    } else if spec.space {
+        " "
+    } else {
+        ""
+    }
+}
+
+/// Formats the digits of an integer conversion, which have at least
+/// `precision` digits. A zero precision prints nothing for zero.
+fn format_digits(spec: &Spec, digits: String) -> String {
+    match spec.precision {
+        Some(0) if digits == "0" => String::new(),
+        Some(p) if p > digits.len() => "0".repeat(p - digits.len()) + &digits,
+        _ => digits
+    }
+}
+
+fn format_integer(spec: &Spec, v: i64, out: &mut Vec<u8>) {
+    let body = format_digits(spec, v.unsigned_abs().to_string());
+    let zero_pad = spec.zero && spec.precision.is_none();
+    pad(spec, sign_prefix(spec, v < 0), &body, zero_pad, out);
+}
+
+fn format_unsigned(spec: &Spec, v: u64, out: &mut Vec<u8>) {
+    let digits = match spec.conv {
+        b'o' => format!("{:o}", v),
+        b'x' => format!("{:x}", v),
+        b'X' => format!("{:X}", v),
+        _ => v.to_string()
+    };
+    let mut body = format_digits(spec, digits);
+    let mut prefix = "";
+    if spec.alt {
+        match spec.conv {
+            b'o' if !body.starts_with('0') => body.insert(0, '0'),
+            b'x' if v != 0 => prefix = "0x",
+            b'X' if v != 0 => prefix = "0X",
+            _ => {}
+        }
+    }
+    let zero_pad = spec.zero && spec.precision.is_none();
+    pad(spec, prefix, &body, zero_pad, out);
+}
+
+/// Formats `v` as `%e` does, with a sign-less exponent of at least two
+/// digits.
+fn format_exp(v: f64, precision: usize, alt: bool, upper: bool) -> String {
+    let s = format!("{:.*e}", precision, v);
+    let (mantissa, exp) = s.split_at(s.find('e').unwrap());
+    let exp: i32 = exp[1..].parse().unwrap();
+    let mut out = mantissa.to_string();
+    if alt && precision == 0 {
+        out.push('.');
+    }
+    out.push(if upper { 'E' } else { 'e' });
+    out.push(if exp < 0 { '-' } else { '+' });
+    out.push_str(&format!("{:02}", exp.abs()));
+    out
+}
+
+fn format_fixed(v: f64, precision: usize, alt: bool) -> String {
+    let mut out = format!("{:.*}", precision, v);
+    if alt && precision == 0 {
+        out.push('.');
+    }
+    out
+}
+
+/// Formats `v` as `%g` does, choosing between the fixed and exponent forms
+/// depending on the exponent.
+pub(crate) fn format_general(v: f64, precision: usize, alt: bool, upper: bool) -> String {
+    let p = if precision == 0 { 1 } else { precision };
+    let exp_form = format!("{:.*e}", p - 1, v);
+    let exp: i32 = exp_form[exp_form.find('e').unwrap() + 1..].parse().unwrap();
+    let mut out = if exp < -4 || exp >= p as i32 {
+        format_exp(v, p - 1, alt, upper)
+    } else {
+        format_fixed(v, (p as i32 - 1 - exp) as usize, alt)
+    };
+    if !alt {
+        // Removes trailing zeros, and the point if nothing follows it.
+        let exp_start = out.find(['e', 'E']).unwrap_or(out.len());
+        let (mut mantissa, exp) = (out[..exp_start].to_string(), out[exp_start..].to_string());
+        if mantissa.contains('.') {
+            while mantissa.ends_with('0') {
+                mantissa.pop();
+            }
+            if mantissa.ends_with('.') {
+                mantissa.pop();
+            }
+        }
+        out = mantissa + &exp;
+    }
+    out
+}
+
+/// Formats `v` as `%a` does, with the leading digit of normal numbers
+/// being 1 before rounding.
+fn format_hex_float(v: f64, precision: Option<usize>, alt: bool, upper: bool) -> String {
+    let bits = v.to_bits();
+    let raw_exp = ((bits >> 52) & 0x7ff) as i64;
+    let mut mantissa = bits & ((1 << 52) - 1);
+    let (mut lead, exp) = if raw_exp == 0 {
+        (0, if mantissa == 0 { 0 } else { -1022 })
+    } else {
+        (1, raw_exp - 1023)
+    };
+
+    // The 52 mantissa bits are 13 hex digits.
+    let mut n_digits = 13;
+    if let Some(p) = precision {
+        if p < 13 {
+            let shift = 4 * (13 - p) as u32;
+            let rem = mantissa & ((1 << shift) - 1);
+            let half = 1 << (shift - 1);
+            mantissa >>= shift;
+            // Rounds half to even, the last digit being the leading one
+            // without fractional digits.
+            let odd = if p == 0 { lead & 1 == 1 } else { mantissa & 1 == 1 };
+            if rem > half || (rem == half && odd) {
+                mantissa += 1;
+                if mantissa >> (4 * p) != 0 {
+                    mantissa &= (1 << (4 * p)) - 1;
+                    lead += 1;
+                }
+            }
+            n_digits = p;
+        }
+    }
+    let mut digits = if n_digits == 0 {
+        String::new()
+    } else {
+        format!("{:0width$x}", mantissa, width = n_digits)
+    };
+    match precision {
+        None => {
+            while digits.ends_with('0') {
+                digits.pop();
+            }
+        },
+        Some(p) => {
+            while digits.len() < p {
+                digits.push('0');
+            }
+        }
+    }
+
+    let mut out = format!("{}", lead);
+    if !digits.is_empty() || alt {
+        out.push('.');
+    }
+    out.push_str(&digits);
+    out.push_str(&format!("p{}{}", if exp < 0 { '-' } else { '+' }, exp.abs()));
+    if upper {
+        out.to_uppercase()
+    } else {
+        out
+    }
+}
+
+fn format_float(spec: &Spec, v: f64, out: &mut Vec<u8>) {
+    let upper = spec.conv.is_ascii_uppercase();
+    let negative = v.is_sign_negative();
+    let prefix_sign = sign_prefix(spec, negative);
+
+    if !v.is_finite() {
+        let body = if v.is_nan() { "nan" } else { "inf" };
+        let body = if upper { body.to_uppercase() } else { body.to_string() };
+        pad(spec, prefix_sign, &body, false, out);
+        return;
+    }
+
+    let v = v.abs();
+    let precision = spec.precision.unwrap_or(6);
+    let (prefix, body) = match spec.conv.to_ascii_lowercase() {
+        b'e' => (prefix_sign.to_string(), format_exp(v, precision, spec.alt, upper)),
+        b'f' => (prefix_sign.to_string(), format_fixed(v, precision, spec.alt)),
+        b'g' => (prefix_sign.to_string(), format_general(v, precision, spec.alt, upper)),
+        _ => {
+            let body = format_hex_float(v, spec.precision, spec.alt, upper);
+            (format!("{}{}", prefix_sign, if upper { "0X" } else { "0x" }), body)
+        }
+    };
+    pad(spec, &prefix, &body, spec.zero && !spec.left, out);
+}
+
+/// Writes `s` as a Lua string literal.
+fn add_quoted_string(s: &[u8], out: &mut Vec<u8>) {
+    out.push(b'"');
+    for (i, &c) in s.iter().enumerate() {
+        if c == b'"' || c == b'\\' || c == b'\n' {
+            out.push(b'\\');
+            out.push(c);
+        } else if c.is_ascii_control() {
+            // Escapes are padded if a digit follows.
+            let next_is_digit = s.get(i + 1).map(|c| c.is_ascii_digit()).unwrap_or(false);
+            let escape = if next_is_digit {
+                format!("\\{:03}", c)
+            } else {
+                format!("\\{}", c)
+            };
+            out.extend_from_slice(escape.as_bytes());
+        } else {
+            out.push(c);
+        }
+    }
+    out.push(b'"');
+}
+
+/// Writes a value for `%q`, in a form that reads back as the same value.
+fn add_literal(e: &mut ExecutorImpl, arg: usize, out: &mut Vec<u8>) {
+    let v = get_arg(e, arg);
+    if let Some(s) = get_string(e, v) {
+        add_quoted_string(&string_bytes(s), out);
+        return;
+    }
+    let literal = match v {
+        Value::Object(_) => arg_error(arg, "format", "value has no literal form"),
+        Value::Int(v) => quote_integer(v),
+        Value::Float(v) => if v == f64::INFINITY {
+            "1e9999".to_string()
+        } else if v == f64::NEG_INFINITY {
+            "-1e9999".to_string()
+        } else if v.is_nan() {
+            "(0/0)".to_string()
+        } else {
+            let sign = if v < 0.0 { "-" } else { "" };
+            format!("{}0x{}", sign, format_hex_float(v.abs(), None, false, false))
+        },
+        Value::Null | Value::Bool(_) => lua_tostring(e, v)
+    };
+    out.extend_from_slice(literal.as_bytes());
+}
+
+fn quote_integer(v: i64) -> String {
+    // The minimum integer cannot be written as a decimal literal.
+    if v == i64::MIN {
+        format!("0x{:x}", v)
+    } else {
+        v.to_string()
+    }
+}
+
+fn add_string(e: &mut ExecutorImpl, form: &[u8], arg: usize, out: &mut Vec<u8>) {
+    let spec = parse_spec(form, FLAGS_CHAR, true);
+    let v = get_arg(e, arg);
+    let s = string_bytes(&lua_tostring(e, v));
+    if spec.precision.is_none() && s.len() >= MAX_UNFORMATTED_LEN {
+        out.extend_from_slice(&s);
+        return;
+    }
+    if s.contains(&0) {
+        arg_error(arg, "format", "string contains zeros");
+    }
+    let n = spec.precision.unwrap_or(s.len()).min(s.len());
+    pad_bytes(&spec, &s[..n], out);
+}
+
+/// Formats its arguments as `string.format` does.
+pub fn lua_format(e: &mut ExecutorImpl) -> Value {
+    let fmt = check_bytes(e, 0, "format");
+    let fmt = &fmt[..];
+    let n_args = e.get_current_frame().get_n_arguments();
+    let mut out: Vec<u8> = Vec::with_capacity(fmt.len());
+    let mut arg = 0;
+    let mut i = 0;
+
+    while i < fmt.len() {
+        let c = fmt[i];
+        i += 1;
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        if fmt.get(i) == Some(&b'%') {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
+
+        // Spans flags, width and precision, followed by the conversion.
+        let start = i - 1;
+        while i < fmt.len() && b"-+ #0123456789.".contains(&fmt[i]) {
+            i += 1;
+        }
+        if i - start > MAX_SPEC_LEN {
+            error("invalid format string to 'format'".to_string());
+        }
+        let form = &fmt[start..(i + 1).min(fmt.len())];
+        i += 1;
+
+        arg += 1;
+        if arg >= n_args {
+            arg_error(arg, "format", "no value");
+        }
+        match form[form.len() - 1] {
+            b'c' => {
+                let spec = parse_spec(form, FLAGS_CHAR, false);
+                let c = check_integer(e, arg, "format") as u8;
+                pad_bytes(&spec, &[c], &mut out);
+            },
+            b'd' | b'i' => {
+                let spec = parse_spec(form, FLAGS_INT, true);
+                format_integer(&spec, check_integer(e, arg, "format"), &mut out);
+            },
+            b'u' => {
+                let spec = parse_spec(form, FLAGS_UNSIGNED, true);
+                format_unsigned(&spec, check_integer(e, arg, "format") as u64, &mut out);
+            },
+            b'o' | b'x' | b'X' => {
+                let spec = parse_spec(form, FLAGS_HEX, true);
+                format_unsigned(&spec, check_integer(e, arg, "format") as u64, &mut out);
+            },
+            b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => {
+                let spec = parse_spec(form, FLAGS_FLOAT, true);
+                format_float(&spec, check_number(e, arg, "format"), &mut out);
+            },
+            b'q' => {
+                if form.len() > 2 {
+                    error("specifier '%q' cannot have modifiers".to_string());
+                }
+                add_literal(e, arg, &mut out);
+            },
+            b's' => add_string(e, form, arg, &mut out),
+            _ => error(format!("invalid conversion '{}' to 'format'", String::from_utf8_lossy(form)))
+        }
+    }
+    new_string_from_bytes(e, out)
+}
@@ -0,0 +1,439 @@
+//! The `string` library.
+//!
+//! Lua strings are byte strings. They are stored as Rust strings holding
+//! one char per byte, from U+0000 to U+00FF, so ASCII text is unchanged and
+//! any byte sequence round-trips. The library works on the bytes.
+
+use std::cell::Cell;
+use std::panic::panic_any;
+use hexagon::executor::ExecutorImpl;
+use hexagon::value::Value;
+use hexagon::function::Function;
+use hexagon::errors::VMError;
+use lua_types::{type_name, call_metamethod, index_value, is_function, is_truthy, MultiValue, Table, STRING_METATABLE_KEY};
+use lua_pattern::{self, Capture, Matcher};
+use string_format;
+use string_pack;
+use runtime::{concat_operand, new_library, to_number};
+
+/// Strings built by the library may not be longer than this.
+const MAX_STRING_SIZE: usize = i32::MAX as usize;
+
+pub(crate) fn native<F: Fn(&mut ExecutorImpl) -> Value + Send + 'static>(e: &mut ExecutorImpl, f: F) -> Value {
+    Value::Object(e.get_object_pool_mut().allocate(Box::new(Function::from_native(Box::new(f)))))
+}
+
+pub(crate) fn new_string(e: &mut ExecutorImpl, s: String) -> Value {
+    Value::Object(e.get_object_pool_mut().allocate(Box::new(s)))
+}
+
+pub(crate) fn new_string_from_bytes(e: &mut ExecutorImpl, bytes: Vec<u8>) -> Value {
+    new_string(e, bytes_to_string(&bytes))
+}
+
+/// Builds the string holding `bytes`.
+pub(crate) fn bytes_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// Returns the bytes a string holds. Chars above U+00FF, which only come
+/// from the host, are taken as their UTF-8 bytes.
+pub(crate) fn string_bytes(s: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        if (c as u32) < 0x100 {
+            out.push(c as u8);
+        } else {
+            out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+    }
+    out
+}
+
+/// Returns the length of a string in bytes.
+pub(crate) fn string_len(s: &str) -> usize {
+    if s.is_ascii() {
+        s.len()
+    } else {
+        s.chars().map(|c| if (c as u32) < 0x100 { 1 } else { c.len_utf8() }).sum()
+    }
+}
+
+pub(crate) fn arg_error(n: usize, fn_name: &str, msg: &str) -> ! {
+    panic_any(VMError::from(format!("bad argument #{} to '{}' ({})", n + 1, fn_name, msg)))
+}
+
+pub(crate) fn type_error(e: &ExecutorImpl, n: usize, fn_name: &str, expected: &str) -> ! {
+    let got = match e.get_current_frame().get_argument(n) {
+        Some(v) => type_name(e, &v),
+        None => "no value".to_string()
+    };
+    arg_error(n, fn_name, &format!("{} expected, got {}", expected, got))
+}
+
+pub(crate) fn get_arg(e: &ExecutorImpl, n: usize) -> Value {
+    e.get_current_frame().get_argument(n).unwrap_or(Value::Null)
+}
+
+/// Returns the `n`-th argument as a string, converting numbers.
+pub(crate) fn check_string(e: &ExecutorImpl, n: usize, fn_name: &str) -> String {
+    match concat_operand(e, get_arg(e, n)) {
+        Some(s) => s,
+        None => type_error(e, n, fn_name, "string")
+    }
+}
+
+/// Returns the bytes of the `n`-th argument, converting numbers.
+pub(crate) fn check_bytes(e: &ExecutorImpl, n: usize, fn_name: &str) -> Vec<u8> {
+    string_bytes(&check_string(e, n, fn_name))
+}
+
+pub(crate) fn check_number(e: &ExecutorImpl, n: usize, fn_name: &str) -> f64 {
+    match to_number(e, get_arg(e, n)) {
+        Some(v) => v,
+        None => type_error(e, n, fn_name, "number")
+    }
+}
+
+/// Returns the `n`-th argument as an integer, which must be a number with
+/// an exact integer representation.
+pub(crate) fn check_integer(e: &ExecutorImpl, n: usize, fn_name: &str) -> i64 {
+    if let Value::Int(v) = get_arg(e, n) {
+        return v;
+    }
+    let v = check_number(e, n, fn_name);
+    if v.fract() != 0.0 || v < i64::MIN as f64 || v >= i64::MAX as f64 {
+        arg_error(n, fn_name, "number has no integer representation");
+    }
+    v as i64
+}
+
+pub(crate) fn opt_integer(e: &ExecutorImpl, n: usize, fn_name: &str, default: i64) -> i64 {
+    match get_arg(e, n) {
+        Value::Null => default,
+        _ => check_integer(e, n, fn_name)
+    }
+}
+
+/// Converts a possibly negative start position to a 1-based index.
+pub(crate) fn start_position(pos: i64, len: usize) -> usize {
+    let len = len as i64;
+    if pos > 0 {
+        pos as usize
+    } else if pos == 0 || pos < -len {
+        1
+    } else {
+        (len + pos + 1) as usize
+    }
+}
+
+/// Converts a possibly negative end position to a 1-based index, clamped
+/// to the length.
+pub(crate) fn end_position(pos: i64, len: usize) -> usize {
+    let len = len as i64;
+    if pos > len {
+        len as usize
+    } else if pos >= 0 {
+        pos as usize
+    } else if pos < -len {
+        0
+    } else {
+        (len + pos + 1) as usize
+    }
+}
+
+fn lua_sub(e: &mut ExecutorImpl) -> Value {
+    let s = check_bytes(e, 0, "sub");
+    let i = start_position(opt_integer(e, 1, "sub", 1), s.len());
+    let j = end_position(opt_integer(e, 2, "sub", -1), s.len());
+    let bytes = if i > j {
+        Vec::new()
+    } else {
+        s[i - 1..j].to_vec()
+    };
+    new_string_from_bytes(e, bytes)
+}
+
+fn lua_rep(e: &mut ExecutorImpl) -> Value {
+    let s = check_string(e, 0, "rep");
+    let n = check_integer(e, 1, "rep");
+    let sep = match get_arg(e, 2) {
+        Value::Null => String::new(),
+        _ => check_string(e, 2, "rep")
+    };
+    if n <= 0 {
+        return new_string(e, String::new());
+    }
+
+    let n = n as usize;
+    let size = (string_len(&s) + string_len(&sep)).checked_mul(n).map(|v| v - string_len(&sep));
+    match size {
+        Some(size) if size <= MAX_STRING_SIZE => {},
+        _ => panic_any(VMError::from("resulting string too large"))
+    }
+    let mut out = String::with_capacity(size.unwrap());
+    for i in 0..n {
+        if i > 0 {
+            out.push_str(&sep);
+        }
+        out.push_str(&s);
+    }
+    new_string(e, out)
+}
+
+fn lua_byte(e: &mut ExecutorImpl) -> Value {
+    let s = check_bytes(e, 0, "byte");
+    let i = opt_integer(e, 1, "byte", 1);
+    let i_pos = start_position(i, s.len());
+    let j = end_position(opt_integer(e, 2, "byte", i), s.len());
+    let values: Vec<Value> = if i_pos > j {
+        Vec::new()
+    } else {
+        s[i_pos - 1..j].iter().map(|&b| Value::Int(b as i64)).collect()
+    };
+    MultiValue::from_values(e, values)
+}
+
+fn lua_char(e: &mut ExecutorImpl) -> Value {
+    let n = e.get_current_frame().get_n_arguments();
+    let mut bytes = Vec::with_capacity(n);
+    for i in 0..n {
+        let c = check_integer(e, i, "char");
+        if !(0..=255).contains(&c) {
+            arg_error(i, "char", "value out of range");
+        }
+        bytes.push(c as u8);
+    }
+    new_string_from_bytes(e, bytes)
+}
+
+fn capture_value(e: &mut ExecutorImpl, src: &[u8], capture: Capture) -> Value {
+    match capture {
+        Capture::Bytes(start, end) => new_string_from_bytes(e, src[start..end].to_vec()),
+        Capture::Position(pos) => Value::Int(pos as i64 + 1)
+    }
+}
+
+/// Returns the captures of the match `s..end`, or the whole match if the
+/// pattern has none and `whole_if_none` is set.
+fn get_captures(e: &mut ExecutorImpl, m: &Matcher, src: &[u8], s: usize, end: usize, whole_if_none: bool) -> Vec<Value> {
+    let n = if m.n_captures() == 0 && whole_if_none { 1 } else { m.n_captures() };
+    (0..n).map(|i| capture_value(e, src, m.get_capture(i, s, end))).collect()
+}
+
+/// Implements `string.find` and `string.match`.
+fn find_aux(e: &mut ExecutorImpl, find: bool) -> Value {
+    let fn_name = if find { "find" } else { "match" };
+    let s = check_bytes(e, 0, fn_name);
+    let pattern = check_bytes(e, 1, fn_name);
+    let (src, pat) = (&s[..], &pattern[..]);
+    let init = start_position(opt_integer(e, 2, fn_name, 1), src.len()) - 1;
+    if init > src.len() {
+        return Value::Null;
+    }
+
+    if find && (is_truthy(get_arg(e, 3)) || lua_pattern::is_plain(pat)) {
+        return match lua_pattern::find_plain(src, pat, init) {
+            Some(start) => MultiValue::from_values(e, vec! [
+                Value::Int(start as i64 + 1),
+                Value::Int((start + pat.len()) as i64)
+            ]),
+            None => Value::Null
+        };
+    }
+
+    let anchor = pat.first() == Some(&b'^');
+    let mut m = Matcher::new(src, if anchor { &pat[1..] } else { pat });
+    let mut start = init;
+    loop {
+        if let Some(end) = m.match_at(start) {
+            let mut values = Vec::new();
+            if find {
+                values.push(Value::Int(start as i64 + 1));
+                values.push(Value::Int(end as i64));
+            }
+            values.extend(get_captures(e, &m, src, start, end, !find));
+            return MultiValue::from_values(e, values);
+        }
+        start += 1;
+        if anchor || start > src.len() {
+            return Value::Null;
+        }
+    }
+}
+
+fn lua_gmatch(e: &mut ExecutorImpl) -> Value {
+    let s = check_bytes(e, 0, "gmatch");
+    let pattern = check_bytes(e, 1, "gmatch");
+    let init = start_position(opt_integer(e, 2, "gmatch", 1), s.len()) - 1;
+    let position = Cell::new(init.min(s.len() + 1));
+    // End of the last match, which an empty match may not follow.
+    let last_match: Cell<Option<usize>> = Cell::new(None);
+
+    native(e, move |e| {
+        let (src, pat) = (&s[..], &pattern[..]);
+        let mut m = Matcher::new(src, pat);
+        let mut start = position.get();
+        while start <= src.len() {
+            match m.match_at(start) {
+                Some(end) if Some(end) != last_match.get() => {
+                    position.set(end);
+                    last_match.set(Some(end));
+                    let values = get_captures(e, &m, src, start, end, true);
+                    return MultiValue::from_values(e, values);
+                },
+                _ => start += 1
+            }
+        }
+        position.set(start);
+        Value::Null
+    })
+}
+
+/// Appends the replacement of the match `s..end` by `gsub` to `out`.
+fn add_replacement(e: &mut ExecutorImpl, out: &mut Vec<u8>, m: &Matcher, src: &[u8], s: usize, end: usize, repl: Value) {
+    if let Some(repl) = concat_operand(e, repl) {
+        let repl = string_bytes(&repl);
+        let mut i = 0;
+        while i < repl.len() {
+            let c = repl[i];
+            i += 1;
+            if c != b'%' {
+                out.push(c);
+                continue;
+            }
+            match repl.get(i) {
+                Some(&b'%') => out.push(b'%'),
+                Some(&d) if d.is_ascii_digit() => {
+                    let capture = if d == b'0' {
+                        Capture::Bytes(s, end)
+                    } else {
+                        m.get_capture((d - b'1') as usize, s, end)
+                    };
+                    match capture {
+                        Capture::Bytes(start, end) => out.extend_from_slice(&src[start..end]),
+                        Capture::Position(pos) => out.extend_from_slice((pos + 1).to_string().as_bytes())
+                    }
+                },
+                _ => panic_any(VMError::from("invalid use of '%' in replacement string"))
+            }
+            i += 1;
+        }
+        return;
+    }
+
+    let key = capture_value(e, src, m.get_capture(0, s, end));
+    let v = if is_function(e, &repl) {
+        let args = get_captures(e, m, src, s, end, true);
+        call_metamethod(e, repl, &args)
+    } else {
+        index_value(e, repl, key)
+    };
+    if !is_truthy(v) {
+        // Keeps the original text.
+        out.extend_from_slice(&src[s..end]);
+    } else {
+        match concat_operand(e, v) {
+            Some(v) => out.extend_from_slice(&string_bytes(&v)),
+            None => panic_any(VMError::from(format!("invalid replacement value (a {})", type_name(e, &v))))
+        }
+    }
+}
+
+fn lua_gsub(e: &mut ExecutorImpl) -> Value {
+    let s = check_bytes(e, 0, "gsub");
+    let pattern = check_bytes(e, 1, "gsub");
+    let repl = get_arg(e, 2);
+    match repl {
+        Value::Int(_) | Value::Float(_) => {},
+        Value::Object(id) if e.get_object_pool().get_direct_typed::<String>(id).is_some()
+            || e.get_object_pool().get_direct_typed::<Table>(id).is_some()
+            || is_function(e, &repl) => {},
+        _ => type_error(e, 2, "gsub", "string/function/table")
+    }
+    let max_n = match get_arg(e, 3) {
+        Value::Null => None,
+        _ => Some(check_integer(e, 3, "gsub"))
+    };
+
+    let (src, pat) = (&s[..], &pattern[..]);
+    let anchor = pat.first() == Some(&b'^');
+    let mut m = Matcher::new(src, if anchor { &pat[1..] } else { pat });
+    let mut out: Vec<u8> = Vec::with_capacity(src.len());
+    let mut pos = 0;
+    let mut last_match = None;
+    let mut n = 0;
+    while max_n.map(|max_n| n < max_n).unwrap_or(true) {
+        match m.match_at(pos) {
+            Some(end) if Some(end) != last_match => {
+                n += 1;
+                add_replacement(e, &mut out, &m, src, pos, end, repl);
+                pos = end;
+                last_match = Some(end);
+            },
+            _ => if pos < src.len() {
+                out.push(src[pos]);
+                pos += 1;
+            } else {
+                break;
+            }
+        }
+        if anchor {
+            break;
+        }
+    }
+    out.extend_from_slice(&src[pos..]);
+
+    let out = new_string_from_bytes(e, out);
+    MultiValue::from_values(e, vec! [ out, Value::Int(n) ])
+}
+
+/// Creates the `string` library, and makes it the `__index` of the
+/// metatable shared by strings.
+pub fn init(e: &mut ExecutorImpl) -> Value {
+    let fields = vec! [
+        ("len", native(e, |e| {
+            let s = check_string(e, 0, "len");
+            Value::Int(string_len(&s) as i64)
+        })),
+        ("sub", native(e, lua_sub)),
+        ("upper", native(e, |e| {
+            let s = check_string(e, 0, "upper");
+            new_string(e, s.to_ascii_uppercase())
+        })),
+        ("lower", native(e, |e| {
+            let s = check_string(e, 0, "lower");
+            new_string(e, s.to_ascii_lowercase())
+        })),
+        ("rep", native(e, lua_rep)),
+        ("reverse", native(e, |e| {
+            let mut bytes = check_bytes(e, 0, "reverse");
+            bytes.reverse();
+            new_string_from_bytes(e, bytes)
+        })),
+        ("byte", native(e, lua_byte)),
+        ("char", native(e, lua_char)),
+        ("format", native(e, string_format::lua_format)),
+        ("find", native(e, |e| find_aux(e, true))),
+        ("match", native(e, |e| find_aux(e, false))),
+        ("gmatch", native(e, lua_gmatch)),
+        ("gsub", native(e, lua_gsub)),
+        ("pack", native(e, string_pack::lua_pack)),
+        ("unpack", native(e, string_pack::lua_unpack)),
+        ("packsize", native(e, string_pack::lua_packsize))
+    ];
+    let lib = new_library(e, fields);
+
+    let mt = match e.get_object_pool().get_static_object(STRING_METATABLE_KEY) {
+        Some(&mt) => mt,
+        None => {
+            e.create_static_object(STRING_METATABLE_KEY, Box::new(Table::new()));
+            *e.get_object_pool().get_static_object(STRING_METATABLE_KEY).unwrap()
+        }
+    };
+    let key = new_string(e, "__index".to_string());
+    if let Value::Object(id) = mt {
+        e.get_object_pool().must_get_direct_typed::<Table>(id).set(e, key, lib);
+    }
+    lib
+}
@@ -0,0 +1,370 @@
+//! `string.pack`, `string.unpack` and `string.packsize`, following the
+//! reference implementation with the layout of a 64-bit platform.
+
+use std::panic::panic_any;
+use hexagon::executor::ExecutorImpl;
+use hexagon::value::Value;
+use hexagon::errors::VMError;
+use lua_types::MultiValue;
+use string_lib::{arg_error, check_integer, check_bytes, check_number, new_string_from_bytes, opt_integer, start_position};
+
+/// Maximum size of a packed integer.
+const MAX_INT_SIZE: usize = 16;
+
+/// Size of a Lua integer.
+const INT_SIZE: usize = 8;
+
+/// Maximum alignment of native types, used by '!' without a size.
+const NATIVE_ALIGN: usize = 8;
+
+/// Limit on sizes and totals, as for strings.
+const MAX_SIZE: usize = i32::MAX as usize;
+
+/// Byte used for padding.
+const PAD_BYTE: u8 = 0;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Kind {
+    Int,
+    Uint,
+    Float,
+    Double,
+    Char,
+    String,
+    ZString,
+    Padding,
+    PadAlign,
+    Nop
+}
+
+/// Parsing state of a format string.
+struct Header<'a> {
+    fn_name: &'static str,
+    fmt: &'a [u8],
+    pos: usize,
+    little: bool,
+    max_align: usize
+}
+
+fn error(msg: String) -> ! {
+    panic_any(VMError::from(msg))
+}
+
+impl<'a> Header<'a> {
+    fn new(fn_name: &'static str, fmt: &'a [u8]) -> Header<'a> {
+        Header {
+            fn_name,
+            fmt,
+            pos: 0,
+            little: cfg!(target_endian = "little"),
+            max_align: 1
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.fmt.len()
+    }
+
+    fn read_num(&mut self, default: Option<usize>) -> Option<usize> {
+        match self.fmt.get(self.pos) {
+            Some(d) if d.is_ascii_digit() => {},
+            _ => return default
+        }
+        let mut v = 0;
+        while let Some(&d) = self.fmt.get(self.pos) {
+            if !d.is_ascii_digit() || v > (MAX_SIZE - 9) / 10 {
+                break;
+            }
+            v = v * 10 + (d - b'0') as usize;
+            self.pos += 1;
+        }
+        Some(v)
+    }
+
+    fn read_num_limit(&mut self, default: usize) -> usize {
+        let size = self.read_num(Some(default)).unwrap();
+        if size > MAX_INT_SIZE || size == 0 {
+            error(format!("integral size ({}) out of limits [1,{}]", size, MAX_INT_SIZE));
+        }
+        size
+    }
+
+    /// Reads the next option and its size.
+    fn read_option(&mut self) -> (Kind, usize) {
+        let opt = self.fmt[self.pos];
+        self.pos += 1;
+        match opt {
+            b'b' => (Kind::Int, 1),
+            b'B' => (Kind::Uint, 1),
+            b'h' => (Kind::Int, 2),
+            b'H' => (Kind::Uint, 2),
+            b'l' | b'j' => (Kind::Int, 8),
+            b'L' | b'J' | b'T' => (Kind::Uint, 8),
+            b'f' => (Kind::Float, 4),
+            b'n' | b'd' => (Kind::Double, 8),
+            b'i' => (Kind::Int, self.read_num_limit(4)),
+            b'I' => (Kind::Uint, self.read_num_limit(4)),
+            b's' => (Kind::String, self.read_num_limit(8)),
+            b'c' => match self.read_num(None) {
+                Some(size) => (Kind::Char, size),
+                None => error("missing size for format option 'c'".to_string())
+            },
+            b'z' => (Kind::ZString, 0),
+            b'x' => (Kind::Padding, 1),
+            b'X' => (Kind::PadAlign, 0),
+            b' ' => (Kind::Nop, 0),
+            b'<' => {
+                self.little = true;
+                (Kind::Nop, 0)
+            },
+            b'>' => {
+                self.little = false;
+                (Kind::Nop, 0)
+            },
+            b'=' => {
+                self.little = cfg!(target_endian = "little");
+                (Kind::Nop, 0)
+            },
+            b'!' => {
+                self.max_align = self.read_num_limit(NATIVE_ALIGN);
+                (Kind::Nop, 0)
+            },
+            _ => error(format!("invalid format option '{}'", opt as char))
+        }
+    }
+
+    /// Reads the next option, returning its kind, its size and the padding
+    /// needed to align it at `total`.
+    fn read_details(&mut self, total: usize) -> (Kind, usize, usize) {
+        let (kind, size) = self.read_option();
+        let mut align = size;
+        if kind == Kind::PadAlign {
+            if self.done() {
+                arg_error(0, self.fn_name, "invalid next option for option 'X'");
+            }
+            let (next, next_size) = self.read_option();
+            align = next_size;
+            if next == Kind::Char || align == 0 {
+                arg_error(0, self.fn_name, "invalid next option for option 'X'");
+            }
+        }
+        if align <= 1 || kind == Kind::Char {
+            return (kind, size, 0);
+        }
+        if align > self.max_align {
+            align = self.max_align;
+        }
+        if align & (align - 1) != 0 {
+            arg_error(0, self.fn_name, "format asks for alignment not power of 2");
+        }
+        (kind, size, (align - (total & (align - 1))) & (align - 1))
+    }
+}
+
+/// Writes the `size` low bytes of `v`, extending negative values.
+fn pack_int(out: &mut Vec<u8>, v: u64, little: bool, size: usize, negative: bool) {
+    let mut bytes = vec! [ 0; size ];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = if i < INT_SIZE {
+            (v >> (8 * i)) as u8
+        } else if negative {
+            0xff
+        } else {
+            0
+        };
+    }
+    if !little {
+        bytes.reverse();
+    }
+    out.extend(bytes);
+}
+
+fn unpack_int(data: &[u8], little: bool, size: usize, signed: bool) -> i64 {
+    let byte = |i: usize| if little { data[i] } else { data[size - 1 - i] };
+    let limit = size.min(INT_SIZE);
+    let mut res: u64 = 0;
+    for i in (0..limit).rev() {
+        res = (res << 8) | byte(i) as u64;
+    }
+    if size < INT_SIZE {
+        if signed {
+            let mask = 1u64 << (size * 8 - 1);
+            res = (res ^ mask).wrapping_sub(mask);
+        }
+    } else if size > INT_SIZE {
+        let fill = if !signed || (res as i64) >= 0 { 0 } else { 0xff };
+        if (limit..size).any(|i| byte(i) != fill) {
+            error(format!("{}-byte integer does not fit into Lua Integer", size));
+        }
+    }
+    res as i64
+}
+
+fn pack_bytes(out: &mut Vec<u8>, bytes: &[u8], little: bool) {
+    if little {
+        out.extend_from_slice(bytes);
+    } else {
+        out.extend(bytes.iter().rev());
+    }
+}
+
+fn unpack_bytes(data: &[u8], little: bool) -> Vec<u8> {
+    let mut bytes = data.to_vec();
+    if !little {
+        bytes.reverse();
+    }
+    bytes
+}
+
+pub fn lua_pack(e: &mut ExecutorImpl) -> Value {
+    let fmt = check_bytes(e, 0, "pack");
+    let mut h = Header::new("pack", &fmt);
+    let mut out: Vec<u8> = Vec::new();
+    let mut arg = 0;
+
+    while !h.done() {
+        let (kind, size, n_align) = h.read_details(out.len());
+        out.extend(::std::iter::repeat_n(PAD_BYTE, n_align));
+        arg += 1;
+        match kind {
+            Kind::Int => {
+                let v = check_integer(e, arg, "pack");
+                if size < INT_SIZE {
+                    let lim = 1i64 << (size * 8 - 1);
+                    if v < -lim || v >= lim {
+                        arg_error(arg, "pack", "integer overflow");
+                    }
+                }
+                pack_int(&mut out, v as u64, h.little, size, v < 0);
+            },
+            Kind::Uint => {
+                let v = check_integer(e, arg, "pack");
+                if size < INT_SIZE && (v as u64) >= 1u64 << (size * 8) {
+                    arg_error(arg, "pack", "unsigned overflow");
+                }
+                pack_int(&mut out, v as u64, h.little, size, false);
+            },
+            Kind::Float => {
+                let v = check_number(e, arg, "pack") as f32;
+                pack_bytes(&mut out, &v.to_bits().to_le_bytes(), h.little);
+            },
+            Kind::Double => {
+                let v = check_number(e, arg, "pack");
+                pack_bytes(&mut out, &v.to_bits().to_le_bytes(), h.little);
+            },
+            Kind::Char => {
+                let s = check_bytes(e, arg, "pack");
+                if s.len() > size {
+                    arg_error(arg, "pack", "string longer than given size");
+                }
+                out.extend_from_slice(&s);
+                out.extend(::std::iter::repeat_n(PAD_BYTE, size - s.len()));
+            },
+            Kind::String => {
+                let s = check_bytes(e, arg, "pack");
+                if size < INT_SIZE && s.len() as u64 >= 1u64 << (size * 8) {
+                    arg_error(arg, "pack", "string length does not fit in given size");
+                }
+                pack_int(&mut out, s.len() as u64, h.little, size, false);
+                out.extend_from_slice(&s);
+            },
+            Kind::ZString => {
+                let s = check_bytes(e, arg, "pack");
+                if s.contains(&0) {
+                    arg_error(arg, "pack", "string contains zeros");
+                }
+                out.extend_from_slice(&s);
+                out.push(0);
+            },
+            Kind::Padding => {
+                out.push(PAD_BYTE);
+                arg -= 1;
+            },
+            Kind::PadAlign | Kind::Nop => arg -= 1
+        }
+    }
+    new_string_from_bytes(e, out)
+}
+
+pub fn lua_packsize(e: &mut ExecutorImpl) -> Value {
+    let fmt = check_bytes(e, 0, "packsize");
+    let mut h = Header::new("packsize", &fmt);
+    let mut total: usize = 0;
+
+    while !h.done() {
+        let (kind, size, n_align) = h.read_details(total);
+        if kind == Kind::String || kind == Kind::ZString {
+            arg_error(0, "packsize", "variable-length format");
+        }
+        let size = size + n_align;
+        if total > MAX_SIZE - size {
+            arg_error(0, "packsize", "format result too large");
+        }
+        total += size;
+    }
+    Value::Int(total as i64)
+}
+
+pub fn lua_unpack(e: &mut ExecutorImpl) -> Value {
+    let fmt = check_bytes(e, 0, "unpack");
+    let data = check_bytes(e, 1, "unpack");
+    let data = &data[..];
+    let mut pos = start_position(opt_integer(e, 2, "unpack", 1), data.len()) - 1;
+    if pos > data.len() {
+        arg_error(2, "unpack", "initial position out of string");
+    }
+
+    let mut h = Header::new("unpack", &fmt);
+    let mut values = Vec::new();
+    while !h.done() {
+        let (kind, size, n_align) = h.read_details(pos);
+        if n_align + size > data.len() - pos {
+            arg_error(1, "unpack", "data string too short");
+        }
+        pos += n_align;
+        let field = &data[pos..pos + size];
+        match kind {
+            Kind::Int | Kind::Uint => {
+                let v = unpack_int(field, h.little, size, kind == Kind::Int);
+                values.push(Value::Int(v));
+            },
+            Kind::Float => {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(&unpack_bytes(field, h.little));
+                values.push(Value::Float(f32::from_bits(u32::from_le_bytes(bytes)) as f64));
+            },
+            Kind::Double => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&unpack_bytes(field, h.little));
+                values.push(Value::Float(f64::from_bits(u64::from_le_bytes(bytes))));
+            },
+            Kind::Char => {
+                let v = new_string_from_bytes(e, field.to_vec());
+                values.push(v);
+            },
+            Kind::String => {
+                let len = unpack_int(field, h.little, size, false) as u64;
+                if len > (data.len() - pos - size) as u64 {
+                    arg_error(1, "unpack", "data string too short");
+                }
+                let start = pos + size;
+                let v = new_string_from_bytes(e, data[start..start + len as usize].to_vec());
+                values.push(v);
+                pos += len as usize;
+            },
+            Kind::ZString => {
+                let len = match data[pos..].iter().position(|&b| b == 0) {
+                    Some(len) => len,
+                    None => arg_error(1, "unpack", "unfinished string for format 'z'")
+                };
+                let v = new_string_from_bytes(e, data[pos..pos + len].to_vec());
+                values.push(v);
+                pos += len + 1;
+            },
+            Kind::Padding | Kind::PadAlign | Kind::Nop => {}
+        }
+        pos += size;
+    }
+    values.push(Value::Int(pos as i64 + 1));
+    MultiValue::from_values(e, values)
+}
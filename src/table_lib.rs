@@ -0,0 +1,329 @@
+//! The `table` library.
+//!
+//! Elements are read and written through `__get__` and `__set__`, so the
+//! `__index` and `__newindex` metamethods apply, and lengths honor `__len`.
+
+use std::panic::panic_any;
+use hexagon::executor::ExecutorImpl;
+use hexagon::value::Value;
+use hexagon::errors::VMError;
+use lua_types::{call_metamethod, index_value, is_function, is_truthy, MultiValue, Table};
+use runtime::{compare, concat_operand, lua_equal, new_library};
+use string_lib::{arg_error, check_integer, get_arg, native, new_string, opt_integer, type_error};
+
+/// Most values `table.unpack` may return.
+const MAX_RESULTS: u64 = 1000000;
+
+/// Intervals at least this long use a randomized pivot in `table.sort`
+/// once a partition has been found imbalanced.
+const RANDOM_PIVOT_LIMIT: i64 = 100;
+
+fn error(msg: String) -> ! {
+    panic_any(VMError::from(msg))
+}
+
+/// Returns the `n`-th argument, which must be a table.
+fn check_table(e: &ExecutorImpl, n: usize, fn_name: &str) -> Value {
+    let v = get_arg(e, n);
+    match v {
+        Value::Object(id) if e.get_object_pool().get_direct_typed::<Table>(id).is_some() => v,
+        _ => type_error(e, n, fn_name, "table")
+    }
+}
+
+/// Returns `#t`, which must be an integer.
+fn length(e: &mut ExecutorImpl, t: Value) -> i64 {
+    e.invoke(t, t, Some("__len__"), &[]);
+    match e.get_current_frame().pop_exec() {
+        Value::Int(v) => v,
+        Value::Float(v) if v.fract() == 0.0 => v as i64,
+        _ => error("object length is not an integer".to_string())
+    }
+}
+
+fn get_i(e: &mut ExecutorImpl, t: Value, i: i64) -> Value {
+    index_value(e, t, Value::Int(i))
+}
+
+fn set_i(e: &mut ExecutorImpl, t: Value, i: i64, v: Value) {
+    e.invoke(t, t, Some("__set__"), &[Value::Int(i), v]);
+    e.get_current_frame().pop_exec();
+}
+
+fn lua_insert(e: &mut ExecutorImpl) -> Value {
+    let t = check_table(e, 0, "insert");
+    let first_empty = length(e, t) + 1;
+    let (pos, v) = match e.get_current_frame().get_n_arguments() {
+        2 => (first_empty, get_arg(e, 1)),
+        3 => {
+            let pos = check_integer(e, 1, "insert");
+            // Only positions in [1, #t + 1] are valid.
+            if (pos as u64).wrapping_sub(1) >= first_empty as u64 {
+                arg_error(1, "insert", "position out of bounds");
+            }
+            for i in (pos + 1..first_empty + 1).rev() {
+                let prev = get_i(e, t, i - 1);
+                set_i(e, t, i, prev);
+            }
+            (pos, get_arg(e, 2))
+        },
+        _ => error("wrong number of arguments to 'insert'".to_string())
+    };
+    set_i(e, t, pos, v);
+    Value::Null
+}
+
+fn lua_remove(e: &mut ExecutorImpl) -> Value {
+    let t = check_table(e, 0, "remove");
+    let size = length(e, t);
+    let mut pos = opt_integer(e, 1, "remove", size);
+    // A given position may also be #t + 1.
+    if pos != size && (pos as u64).wrapping_sub(1) > size as u64 {
+        arg_error(1, "remove", "position out of bounds");
+    }
+    let removed = get_i(e, t, pos);
+    while pos < size {
+        let next = get_i(e, t, pos + 1);
+        set_i(e, t, pos, next);
+        pos += 1;
+    }
+    set_i(e, t, pos, Value::Null);
+    removed
+}
+
+fn lua_concat(e: &mut ExecutorImpl) -> Value {
+    let t = check_table(e, 0, "concat");
+    let len = length(e, t);
+    let sep = match get_arg(e, 1) {
+        Value::Null => String::new(),
+        v => match concat_operand(e, v) {
+            Some(s) => s,
+            None => type_error(e, 1, "concat", "string")
+        }
+    };
+    let first = opt_integer(e, 2, "concat", 1);
+    let last = opt_integer(e, 3, "concat", len);
+
+    let mut out = String::new();
+    let mut i = first;
+    while i <= last {
+        let v = get_i(e, t, i);
+        match concat_operand(e, v) {
+            Some(s) => out.push_str(&s),
+            None => error(format!("invalid value (at index {}) in table for 'concat'", i))
+        }
+        if i == last {
+            break;
+        }
+        out.push_str(&sep);
+        i += 1;
+    }
+    new_string(e, out)
+}
+
+fn lua_pack(e: &mut ExecutorImpl) -> Value {
+    let n = e.get_current_frame().get_n_arguments();
+    let t = Table::new();
+    for i in 0..n {
+        let v = get_arg(e, i);
+        t.set(e, Value::Int(i as i64 + 1), v);
+    }
+    let key = new_string(e, "n".to_string());
+    t.set(e, key, Value::Int(n as i64));
+    Value::Object(e.get_object_pool_mut().allocate(Box::new(t)))
+}
+
+fn lua_unpack(e: &mut ExecutorImpl) -> Value {
+    let t = get_arg(e, 0);
+    let first = opt_integer(e, 1, "unpack", 1);
+    let last = match get_arg(e, 2) {
+        Value::Null => {
+            check_table(e, 0, "unpack");
+            length(e, t)
+        },
+        _ => check_integer(e, 2, "unpack")
+    };
+    if first > last {
+        return MultiValue::from_values(e, Vec::new());
+    }
+    if (last as u64).wrapping_sub(first as u64) >= MAX_RESULTS {
+        error("too many results to unpack".to_string());
+    }
+    let values = (first..=last).map(|i| get_i(e, t, i)).collect();
+    MultiValue::from_values(e, values)
+}
+
+fn lua_move(e: &mut ExecutorImpl) -> Value {
+    let src = check_table(e, 0, "move");
+    let first = check_integer(e, 1, "move");
+    let last = check_integer(e, 2, "move");
+    let to = check_integer(e, 3, "move");
+    let dest = match get_arg(e, 4) {
+        Value::Null => src,
+        _ => check_table(e, 4, "move")
+    };
+
+    if last >= first {
+        if first <= 0 && last >= i64::MAX + first {
+            arg_error(2, "move", "too many elements to move");
+        }
+        let n = last - first + 1;
+        if to > i64::MAX - n + 1 {
+            arg_error(3, "move", "destination wrap around");
+        }
+        // Copies backwards when the ranges overlap with `to` inside.
+        if to > last || to <= first || !lua_equal(e, src, dest) {
+            for i in 0..n {
+                let v = get_i(e, src, first + i);
+                set_i(e, dest, to + i, v);
+            }
+        } else {
+            for i in (0..n).rev() {
+                let v = get_i(e, src, first + i);
+                set_i(e, dest, to + i, v);
+            }
+        }
+    }
+    dest
+}
+
+/// State of `table.sort`, a port of the reference quicksort.
+struct Sorter {
+    t: Value,
+    comp: Value
+}
+
+impl Sorter {
+    fn less(&self, e: &mut ExecutorImpl, a: Value, b: Value) -> bool {
+        if self.comp == Value::Null {
+            compare(e, "__lt", a, b)
+        } else {
+            is_truthy(call_metamethod(e, self.comp, &[a, b]))
+        }
+    }
+
+    fn invalid_order() -> ! {
+        error("invalid order function for sorting".to_string())
+    }
+
+    /// Partitions `lo..=up` around the pivot stored at `up - 1`, returning
+    /// its final position.
+    fn partition(&self, e: &mut ExecutorImpl, lo: i64, up: i64, pivot: Value) -> i64 {
+        let (mut i, mut j) = (lo, up - 1);
+        loop {
+            let a_i = loop {
+                i += 1;
+                let v = get_i(e, self.t, i);
+                if !self.less(e, v, pivot) {
+                    break v;
+                }
+                if i == up - 1 {
+                    Sorter::invalid_order();
+                }
+            };
+            let a_j = loop {
+                j -= 1;
+                let v = get_i(e, self.t, j);
+                if !self.less(e, pivot, v) {
+                    break v;
+                }
+                if j < i {
+                    Sorter::invalid_order();
+                }
+            };
+            if j < i {
+                set_i(e, self.t, up - 1, a_i);
+                set_i(e, self.t, i, pivot);
+                return i;
+            }
+            set_i(e, self.t, i, a_j);
+            set_i(e, self.t, j, a_i);
+        }
+    }
+
+    fn sort(&self, e: &mut ExecutorImpl, mut lo: i64, mut up: i64, mut rnd: u64) {
+        while lo < up {
+            let (a_lo, a_up) = (get_i(e, self.t, lo), get_i(e, self.t, up));
+            if self.less(e, a_up, a_lo) {
+                set_i(e, self.t, lo, a_up);
+                set_i(e, self.t, up, a_lo);
+            }
+            if up - lo == 1 {
+                break;
+            }
+
+            let p = if up - lo < RANDOM_PIVOT_LIMIT || rnd == 0 {
+                lo + (up - lo) / 2
+            } else {
+                let r4 = (up - lo) / 4;
+                (rnd % (r4 as u64 * 2)) as i64 + lo + r4
+            };
+            let (a_p, a_lo) = (get_i(e, self.t, p), get_i(e, self.t, lo));
+            if self.less(e, a_p, a_lo) {
+                set_i(e, self.t, p, a_lo);
+                set_i(e, self.t, lo, a_p);
+            } else {
+                let a_up = get_i(e, self.t, up);
+                if self.less(e, a_up, a_p) {
+                    set_i(e, self.t, p, a_up);
+                    set_i(e, self.t, up, a_p);
+                }
+            }
+            if up - lo == 2 {
+                break;
+            }
+
+            let pivot = get_i(e, self.t, p);
+            let before_up = get_i(e, self.t, up - 1);
+            set_i(e, self.t, p, before_up);
+            set_i(e, self.t, up - 1, pivot);
+            let p = self.partition(e, lo, up, pivot);
+
+            // Recurses into the smaller interval and loops on the larger.
+            let n;
+            if p - lo < up - p {
+                self.sort(e, lo, p - 1, rnd);
+                n = p - lo;
+                lo = p + 1;
+            } else {
+                self.sort(e, p + 1, up, rnd);
+                n = up - p;
+                up = p - 1;
+            }
+            if (up - lo) / 128 > n {
+                // The reference uses the clock here; a fixed mix keeps
+                // sorting deterministic.
+                rnd = (lo as u64).wrapping_mul(0x9e3779b97f4a7c15) ^ (up as u64) ^ (n as u64);
+            }
+        }
+    }
+}
+
+fn lua_sort(e: &mut ExecutorImpl) -> Value {
+    let t = check_table(e, 0, "sort");
+    let n = length(e, t);
+    if n > 1 {
+        if n >= i32::MAX as i64 {
+            arg_error(0, "sort", "array too big");
+        }
+        let comp = get_arg(e, 1);
+        if comp != Value::Null && !is_function(e, &comp) {
+            type_error(e, 1, "sort", "function");
+        }
+        Sorter { t, comp }.sort(e, 1, n, 0);
+    }
+    Value::Null
+}
+
+pub fn init(e: &mut ExecutorImpl) -> Value {
+    let fields = vec! [
+        ("insert", native(e, lua_insert)),
+        ("remove", native(e, lua_remove)),
+        ("concat", native(e, lua_concat)),
+        ("pack", native(e, lua_pack)),
+        ("unpack", native(e, lua_unpack)),
+        ("move", native(e, lua_move)),
+        ("sort", native(e, lua_sort))
+    ];
+    new_library(e, fields)
+}
@@ -537,3 +537,92 @@ pub fn get(name: &str) -> &'static str {
         _ => unimplemented!()
     }
 }
+
+pub fn get_source(name: &str) -> &'static str {
+    match name {
+        "arithmetic" => include_str!("../parser/tests/arithmetic.lua"),
+        "arrays" => include_str!("../parser/tests/arrays.lua"),
+        "break" => include_str!("../parser/tests/break.lua"),
+        "closures" => include_str!("../parser/tests/closures.lua"),
+        "coroutines" => include_str!("../parser/tests/coroutines.lua"),
+        "errors" => include_str!("../parser/tests/errors.lua"),
+        "fib" => include_str!("../parser/tests/fib.lua"),
+        "format" => include_str!("../parser/tests/format.lua"),
+        "function_def_call" => include_str!("../parser/tests/function_def_call.lua"),
+        "generic_for" => include_str!("../parser/tests/generic_for.lua"),
+        "goto" => include_str!("../parser/tests/goto.lua"),
+        "if_else" => include_str!("../parser/tests/if_else.lua"),
+        "length" => include_str!("../parser/tests/length.lua"),
+        "localrec" => include_str!("../parser/tests/localrec.lua"),
+        "locals" => include_str!("../parser/tests/locals.lua"),
+        "loops" => include_str!("../parser/tests/loops.lua"),
+        "math" => include_str!("../parser/tests/math.lua"),
+        "metamethods" => include_str!("../parser/tests/metamethods.lua"),
+        "metatables" => include_str!("../parser/tests/metatables.lua"),
+        "methods" => include_str!("../parser/tests/methods.lua"),
+        "multiple_assignment" => include_str!("../parser/tests/multiple_assignment.lua"),
+        "multiple_returns" => include_str!("../parser/tests/multiple_returns.lua"),
+        "multiple_value_returns" => include_str!("../parser/tests/multiple_value_returns.lua"),
+        "numeric_for" => include_str!("../parser/tests/numeric_for.lua"),
+        "operators" => include_str!("../parser/tests/operators.lua"),
+        "pack" => include_str!("../parser/tests/pack.lua"),
+        "patterns" => include_str!("../parser/tests/patterns.lua"),
+        "print" => include_str!("../parser/tests/print.lua"),
+        "repeat" => include_str!("../parser/tests/repeat.lua"),
+        "short_circuit" => include_str!("../parser/tests/short_circuit.lua"),
+        "simple_local" => include_str!("../parser/tests/simple_local.lua"),
+        "strings" => include_str!("../parser/tests/strings.lua"),
+        "table_array" => include_str!("../parser/tests/table_array.lua"),
+        "table_keys" => include_str!("../parser/tests/table_keys.lua"),
+        "table_lib" => include_str!("../parser/tests/table_lib.lua"),
+        "tables" => include_str!("../parser/tests/tables.lua"),
+        "typed_arrays" => include_str!("../parser/tests/typed_arrays.lua"),
+        "upvalues" => include_str!("../parser/tests/upvalues.lua"),
+        "varargs" => include_str!("../parser/tests/varargs.lua"),
+        "while" => include_str!("../parser/tests/while.lua"),
+        _ => unimplemented!()
+    }
+}
+
+pub const SOURCE_NAMES: &[&str] = &[
+    "arithmetic",
+    "arrays",
+    "break",
+    "closures",
+    "coroutines",
+    "errors",
+    "fib",
+    "format",
+    "function_def_call",
+    "generic_for",
+    "goto",
+    "if_else",
+    "length",
+    "localrec",
+    "locals",
+    "loops",
+    "math",
+    "metamethods",
+    "metatables",
+    "methods",
+    "multiple_assignment",
+    "multiple_returns",
+    "multiple_value_returns",
+    "numeric_for",
+    "operators",
+    "pack",
+    "patterns",
+    "print",
+    "repeat",
+    "short_circuit",
+    "simple_local",
+    "strings",
+    "table_array",
+    "table_keys",
+    "table_lib",
+    "tables",
+    "typed_arrays",
+    "upvalues",
+    "varargs",
+    "while"
+];